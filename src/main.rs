@@ -1,686 +1,10421 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
 use std::f32::consts::PI;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicU32, Ordering as AtomicOrdering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use bevy::core_pipeline::clear_color::ClearColorConfig;
+use bevy::input::mouse::{MouseScrollUnit, MouseWheel};
 use bevy::prelude::*;
+use bevy::render::camera::{RenderTarget, Viewport};
+use bevy::render::mesh::Indices;
+use bevy::render::render_resource::{Extent3d, PrimitiveTopology, TextureDimension, TextureFormat};
+use bevy::render::texture::ImageSampler;
+use bevy::render::view::RenderLayers;
+use bevy::window::PrimaryWindow;
 use bracket_pathfinding::prelude::{
     a_star_search, Algorithm2D, BaseMap, NavigationPath, Point as BracketPoint, SmallVec,
 };
 use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
 
 fn main() {
     App::new()
         .add_plugins(DefaultPlugins)
         .init_resource::<Options>()
-        .add_systems(Startup, setup)
+        .insert_resource(ClearColor(BackgroundPreset::default().color()))
+        .init_resource::<CameraFocus>()
+        .init_resource::<CameraFit>()
+        .init_resource::<SimRng>()
+        .init_resource::<RoadSelection>()
+        .init_resource::<BulldozeDrag>()
+        .init_resource::<ReplayBuffer>()
+        .init_resource::<WalkTiming>()
+        .init_resource::<TrafficMap>()
+        .init_resource::<UpkeepTimer>()
+        .init_resource::<Reservations>()
+        .init_resource::<LongestPath>()
+        .init_resource::<ArrivalHandlers>()
+        .init_resource::<VisitorScore>()
+        .init_resource::<BuildingSelection>()
+        .init_resource::<IdlePause>()
+        .init_resource::<FlashMessage>()
+        .init_resource::<TripStats>()
+        .init_resource::<BuildingIndex>()
+        .init_resource::<Paused>()
+        .init_resource::<TimeOfDay>()
+        .init_resource::<Population>()
+        .init_resource::<SimSpeed>()
+        .add_event::<RescaleWorld>()
+        .add_systems(Update, keyboard_population)
+        .add_systems(Update, keyboard_sim_speed)
+        .add_systems(
+            Update,
+            // anyone dropped or removed this frame has to be in the world before the count
+            (apply_deferred, match_population)
+                .chain()
+                .after(keyboard_population)
+                .after(drop_person)
+                .after(remove_selected_person),
+        )
+        .add_systems(Startup, (setup, register_visitor_score))
+        .add_systems(PostStartup, rebuild_building_index)
+        .add_systems(Startup, spawn_height_histogram)
+        // once setup has put the city in place, to size the grid from
+        .add_systems(PostStartup, create_ground_grid_texture)
         .add_systems(Update, keyboard_move_camera)
+        .add_systems(Update, mouse_wheel_zoom)
+        .add_systems(Update, fit_camera_to_city.before(look_at_camera_focus))
+        .add_systems(Update, look_at_camera_focus.after(keyboard_move_camera))
         .add_systems(Update, keyboard_set_options)
+        .add_systems(Update, keyboard_save_load)
+        .add_systems(Update, export_city_obj)
+        .add_systems(Update, export_stats)
+        .add_systems(Update, cycle_background)
+        .add_systems(Update, rescale_world.before(position_objects_on_grid))
         .add_systems(Update, position_objects_on_grid)
+        .add_systems(Update, apply_present_mode.after(keyboard_set_options))
+        .add_systems(Last, cap_frame_rate)
         .add_systems(Update, move_light)
+        .add_systems(Update, switch_light)
+        .add_systems(Update, configure_shadows)
+        .init_resource::<KeyboardCursor>()
         .add_systems(Update, move_cursor)
-        .add_systems(Update, add_buildings)
+        .add_systems(Update, keyboard_move_cursor.after(move_cursor))
+        .add_systems(Update, add_buildings.after(keyboard_move_cursor))
+        .add_systems(Update, remove_buildings.after(keyboard_move_cursor))
+        .add_systems(Update, drop_person.after(move_cursor))
+        .add_systems(Update, regenerate_city)
+        .add_systems(Update, clear_city)
+        .add_systems(Update, transform_city)
+        .add_systems(Update, (select_buildings, equalize_selected_buildings))
+        .add_systems(Update, (assign_district, tint_districts, draw_districts))
+        .add_systems(Update, connect_buildings_with_roads)
+        .add_systems(Update, paint_roads.after(move_cursor))
+        .add_systems(Update, spawn_road_tiles)
+        .add_systems(Update, spawn_terrain)
+        .add_systems(Update, edit_terrain.after(move_cursor))
+        .add_systems(Update, spawn_walkable_tint)
+        .add_systems(Update, update_building_meshes)
+        .add_systems(Update, draw_center_of_mass)
+        .add_systems(Update, draw_grid)
+        .add_systems(Update, (update_minimap, draw_minimap_focus))
         .add_systems(Update, reset_paths_after_city_changes)
-        .add_systems(Update, people_walk)
-        .add_systems(Update, apply_velocities)
+        .add_systems(
+            Update,
+            advance_time_of_day
+                .before(people_walk)
+                .run_if(not_replaying)
+                .run_if(not_idle),
+        )
+        .add_systems(Update, people_walk.run_if(not_replaying).run_if(not_idle))
+        .add_systems(
+            Update,
+            avoid_each_other
+                .after(people_walk)
+                .before(apply_velocities)
+                .run_if(not_replaying)
+                .run_if(not_idle),
+        )
+        .add_systems(Update, highlight_longest_path)
+        .add_systems(Update, (inspect_person, draw_selected_person))
+        .add_systems(Update, cycle_selected_person)
+        .add_systems(Update, validate_goals)
+        .add_systems(Update, highlight_isolated_buildings)
+        .add_systems(Update, draw_navigation_graph)
+        .add_systems(
+            Update,
+            advance_reservations.run_if(not_replaying).run_if(not_idle),
+        )
+        .add_systems(
+            Update,
+            apply_velocities.run_if(not_replaying).run_if(not_idle),
+        )
+        .init_resource::<Jams>()
+        .add_systems(
+            Update,
+            detect_jams
+                .after(apply_velocities)
+                .run_if(not_replaying)
+                .run_if(not_idle),
+        )
+        .add_systems(Update, draw_jams.after(detect_jams))
+        .add_systems(
+            Update,
+            warn_over_tick_budget
+                .after(people_walk)
+                .run_if(not_replaying)
+                .run_if(not_idle),
+        )
+        .add_systems(
+            Update,
+            abandon_derelict_buildings
+                .run_if(not_replaying)
+                .run_if(not_idle),
+        )
+        .add_systems(Update, demolish_buildings)
+        .add_systems(Update, (remove_selected_person, despawn_people))
+        .add_systems(Update, color_people.after(people_walk))
+        .add_systems(Update, update_height_histogram)
+        .add_systems(Update, draw_blueprint)
+        .add_systems(Update, apply_ground_grid_texture)
+        .add_systems(Update, update_height_labels)
+        .add_systems(Startup, spawn_tile_tooltip)
+        .add_systems(Startup, spawn_flash_message)
+        .add_systems(Startup, spawn_hud)
+        .add_systems(Update, update_hud_text)
+        .add_systems(Startup, spawn_inspector)
+        .add_systems(Update, update_inspector.after(move_cursor))
+        .add_systems(Update, show_flash_message)
+        .add_systems(Update, update_tile_tooltip)
+        .add_systems(Update, update_person_lod)
+        .add_systems(Update, keyboard_replay)
+        .add_systems(
+            Update,
+            record_replay.after(apply_velocities).run_if(not_idle),
+        )
+        .add_systems(PreUpdate, watch_for_idleness)
+        .add_systems(PreUpdate, track_buildings)
+        .add_systems(Update, play_replay)
         .run();
 }
 
 const CAMERA_MOVE_SPEED: f32 = 3.0;
 const CAMERA_ZOOM_SPEED: f32 = 0.2;
+/// how far in and out the camera can zoom, as orthographic projection scales
+const CAMERA_MIN_SCALE: f32 = 3.0;
+const CAMERA_MAX_SCALE: f32 = 100.0;
+/// how much one notch of the mouse wheel zooms by
+const CAMERA_WHEEL_ZOOM_STEP: f32 = 0.1;
+/// how many pixels of trackpad scrolling count as one notch of a wheel
+const PIXELS_PER_WHEEL_NOTCH: f32 = 20.0;
+/// radians per second the camera circles the city
+const CAMERA_ORBIT_SPEED: f32 = 1.0;
+/// speed multipliers while holding Shift or Ctrl
+const CAMERA_FINE_MULTIPLIER: f32 = 0.25;
+const CAMERA_COARSE_MULTIPLIER: f32 = 4.0;
+/// how much room to leave around the city when framing it
+const CAMERA_FIT_MARGIN: f32 = 1.1;
+/// fraction of the remaining way to the framed view the camera moves each second
+const CAMERA_FIT_SPEED: f32 = 4.0;
 
 const LIGHT_MOVE_SPEED: f32 = 0.1;
 
-const NUM_PEOPLE: usize = 10;
+const MAX_BUILDING_HEIGHT: Height = 10;
+
+const SIM_SEED: u64 = 0xc17ee;
+
+const SAVE_FILE: &str = "citybee.ron";
+const OBJ_EXPORT_FILE: &str = "citybee.obj";
+const STATS_EXPORT_FILE: &str = "citybee-stats.csv";
+
+/// where the middle of the city sits on the grid
+const CITY_ORIGIN_OFFSET: GridCoords = GridCoords::ORIGIN;
+
+/// how many times to try generating a city before settling for a fragmented one
+const CITY_GENERATION_ATTEMPTS: usize = 10;
+/// the fraction of empty cells that should be reachable from each other in a generated city
+const CITY_GENERATION_CONNECTEDNESS: f32 = 0.9;
+
+const GRID_TEXTURE_PIXELS_PER_CELL: usize = 32;
+
+const GROUND_COLOR: Color = Color::rgb(0.3, 0.5, 0.3);
+const BUILDING_COLOR: Color = Color::rgb(0.8, 0.7, 0.6);
+const PERSON_COLOR: Color = Color::rgb(0.1, 0.1, 0.1);
+const PERSON_WALKING_COLOR: Color = Color::rgb(0.1, 0.6, 0.2);
+const PERSON_STUCK_COLOR: Color = Color::rgb(0.8, 0.1, 0.1);
+/// building tints for each district, in order of district id
+const DISTRICT_COLORS: [Color; 6] = [
+    Color::rgb(0.85, 0.45, 0.4),
+    Color::rgb(0.45, 0.6, 0.85),
+    Color::rgb(0.9, 0.8, 0.4),
+    Color::rgb(0.6, 0.45, 0.8),
+    Color::rgb(0.45, 0.8, 0.7),
+    Color::rgb(0.9, 0.6, 0.3),
+];
+const BLUEPRINT_GROUND_COLOR: Color = Color::rgb(0.05, 0.1, 0.25);
+const BLUEPRINT_LINE_COLOR: Color = Color::rgb(0.7, 0.85, 1.0);
+
+/// pixels of bar per building in the height histogram
+const HISTOGRAM_BAR_SCALE: f32 = 8.0;
+
+/// the biggest step up or down people can walk between neighbouring cells
+const MAX_WALKABLE_SLOPE: Elevation = 1;
+/// how much cheaper walking along a road is than across open ground
+const ROAD_COST: f32 = 0.5;
+/// how high a single level of terrain is, in world units
+const ELEVATION_STEP: f32 = 0.25;
+/// how far terrain can be raised or lowered either way
+const MAX_ELEVATION: Elevation = 8;
+/// extra path cost per unit of elevation climbed
+const UPHILL_COST: f32 = 0.5;
+
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(10);
+const DEMOLITION_TIME: Duration = Duration::from_millis(1500);
+const PERSON_DESPAWN_TIME: Duration = Duration::from_millis(500);
+
+const REPLAY_CAPACITY: usize = 600;
+
+/// don't warn about blowing the tick budget more often than this
+const TICK_BUDGET_WARNING_INTERVAL: Duration = Duration::from_secs(1);
+
 const PERSON_HEIGHT: f32 = 0.1;
+/// how fast people walk until `SimSpeed` says otherwise, in cells/s
 const PERSON_SPEED: f32 = 1.0;
 
-#[derive(Default, Resource)]
-struct Options {
-    draw_paths: bool,
-    draw_selection: bool,
+/// Randomness for the simulation, seeded so runs are reproducible.
+#[derive(Resource)]
+struct SimRng(ChaCha8Rng);
+
+impl Default for SimRng {
+    fn default() -> Self {
+        Self(ChaCha8Rng::seed_from_u64(SIM_SEED))
+    }
 }
 
-const STARTING_CITY: [Height; 25] = [
-    0, 0, 0, 0, 0, //
-    0, 0, 0, 0, 0, //
-    0, 0, 0, 0, 0, //
-    0, 0, 0, 0, 0, //
-    0, 0, 0, 0, 0, //
-];
+/// Everything needed to pick up where we left off.
+#[derive(Serialize, Deserialize)]
+struct SaveGame {
+    rng: ChaCha8Rng,
+    /// which district each building that's in one is in
+    #[serde(default)]
+    districts: Vec<(GridCoords, District)>,
+    /// the buildings, if the save has them
+    #[serde(default)]
+    city: Option<SavedCity>,
+}
 
-#[derive(Resource)]
-struct City<const L: usize> {
-    heights: [Height; L],
+/// A city's layout as it goes in a save file.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct SavedCity {
     x_len: usize,
     y_len: usize,
+    heights: Vec<Height>,
+    /// the rest of the layout, each left out of saves from before it was kept
+    #[serde(default)]
+    elevations: Vec<Elevation>,
+    #[serde(default)]
+    roads: Vec<bool>,
+    #[serde(default)]
+    blocks: Vec<Option<usize>>,
 }
 
-impl<const L: usize> City<L> {
-    fn new(heights: [Height; L]) -> Self {
-        let size_f = (heights.len() as f32).sqrt();
-        let floor = size_f.floor();
-        assert_eq!(size_f, floor);
-        let size = floor as usize;
-        assert_eq!(size, 5); // TODO
-
-        Self {
-            heights,
-            x_len: size,
-            y_len: size,
-        }
+fn save_city(city: &City) -> SavedCity {
+    SavedCity {
+        x_len: city.x_len,
+        y_len: city.y_len,
+        heights: city.heights.to_vec(),
+        elevations: city.elevations.to_vec(),
+        roads: city.roads.to_vec(),
+        blocks: city.blocks.to_vec(),
     }
+}
 
-    fn buildings_iter<'a>(&'a self) -> impl Iterator<Item = (GridCoords, Height)> + 'a {
-        self.heights.iter().enumerate().flat_map(move |(i, &h)| {
-            if h > 0 {
-                Some((self.index_to_coords(i), h))
-            } else {
-                None
-            }
-        })
+/// One layer of a saved city, if there's a value for every cell.
+fn load_layer<T: Clone>(saved: &SavedCity, layer: &[T]) -> Result<Vec<T>, CityError> {
+    if layer.len() != saved.x_len * saved.y_len {
+        return Err(CityError::DimensionMismatch {
+            x_len: saved.x_len,
+            y_len: saved.y_len,
+            cells: layer.len(),
+        });
     }
+    Ok(layer.to_vec())
+}
 
-    fn height_at_coords(&self, coords: GridCoords) -> Option<Height> {
-        let idx = self.coords_to_index(coords)?;
-        let h = self.heights[idx];
-        if h > 0 {
-            Some(h)
-        } else {
-            None
-        }
+/// Rebuilds a saved city where `current` is, with the same origin and connectivity.  Older
+/// saves with only the heights come back on flat ground with no roads.
+fn load_city(saved: &SavedCity, current: &City) -> Result<City, SaveError> {
+    let mut city = City::try_new(load_layer(saved, &saved.heights)?, saved.x_len, saved.y_len)?
+        .with_origin_offset(current.origin_offset);
+    if !saved.elevations.is_empty() {
+        city.elevations = load_layer(saved, &saved.elevations)?;
     }
-
-    fn set_height_at_coords(&mut self, coords: GridCoords, height: Option<Height>) {
-        let Some(idx) = self.coords_to_index(coords) else {
-            return;
-        };
-        self.heights[idx] = height.unwrap_or(0);
+    if !saved.roads.is_empty() {
+        city.roads = load_layer(saved, &saved.roads)?;
+    }
+    if !saved.blocks.is_empty() {
+        city.blocks = load_layer(saved, &saved.blocks)?;
     }
+    Ok(city
+        .with_connectivity(current.connectivity)
+        .with_scale(current.scale))
+}
 
-    fn coords_to_index(&self, coords: GridCoords) -> Option<usize> {
-        let shifted_y = coords.y + (self.y_len as i8 / 2);
-        let shifted_x = coords.x + (self.x_len as i8 / 2);
-        if shifted_x < 0
-            || shifted_x as usize >= self.x_len
-            || shifted_y < 0
-            || shifted_y as usize >= self.y_len
-        {
-            None
-        } else {
-            Some(shifted_y as usize * self.y_len + shifted_x as usize)
+#[derive(Debug)]
+enum SaveError {
+    Io(io::Error),
+    Serialize(ron::Error),
+    Parse(ron::error::SpannedError),
+    City(CityError),
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "couldn't access save file: {}", e),
+            Self::Serialize(e) => write!(f, "couldn't serialize save: {}", e),
+            Self::Parse(e) => write!(f, "couldn't parse save file: {}", e),
+            Self::City(e) => write!(f, "couldn't load saved city: {}", e),
         }
     }
+}
 
-    fn index_to_coords(&self, idx: usize) -> GridCoords {
-        let half_xl = (self.x_len / 2) as i8;
-        let half_yl = (self.y_len / 2) as i8;
-
-        let x = idx % self.x_len;
-        let y = idx / self.y_len;
+impl std::error::Error for SaveError {}
 
-        GridCoords::new(x as i8 - half_xl, (y as i8) - half_yl)
+impl From<io::Error> for SaveError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
     }
+}
 
-    fn index_to_world(&self, idx: usize, elevation: f32) -> Vec3 {
-        self.index_to_coords(idx).to_world(elevation)
+impl From<ron::Error> for SaveError {
+    fn from(e: ron::Error) -> Self {
+        Self::Serialize(e)
     }
+}
 
-    fn valid_exit(&self, coords: GridCoords) -> Option<usize> {
-        if let None = self.height_at_coords(coords) {
-            self.coords_to_index(coords)
-        } else {
-            None
-        }
+impl From<ron::error::SpannedError> for SaveError {
+    fn from(e: ron::error::SpannedError) -> Self {
+        Self::Parse(e)
     }
 }
 
-impl<const L: usize> BaseMap for City<L> {
-    fn get_available_exits(&self, idx: usize) -> SmallVec<[(usize, f32); 10]> {
-        let mut exits = SmallVec::new();
-        let coords = self.index_to_coords(idx);
+impl From<CityError> for SaveError {
+    fn from(e: CityError) -> Self {
+        Self::City(e)
+    }
+}
 
-        if let Some(idx) = self.valid_exit(coords.up()) {
-            exits.push((idx, 1.0))
-        }
-        if let Some(idx) = self.valid_exit(coords.down()) {
-            exits.push((idx, 1.0))
-        }
-        if let Some(idx) = self.valid_exit(coords.left()) {
-            exits.push((idx, 1.0))
-        }
-        if let Some(idx) = self.valid_exit(coords.right()) {
-            exits.push((idx, 1.0))
-        }
+impl SaveGame {
+    fn to_ron(&self) -> Result<String, SaveError> {
+        Ok(ron::ser::to_string_pretty(self, default())?)
+    }
 
-        exits
+    fn from_ron(ron: &str) -> Result<Self, SaveError> {
+        Ok(ron::from_str(ron)?)
     }
 
-    fn get_pathing_distance(&self, idx1: usize, idx2: usize) -> f32 {
-        let coords1 = self.index_to_coords(idx1);
-        let coords2 = self.index_to_coords(idx2);
-        coords1.manhattan_dist(coords2) as f32
+    fn save(&self, path: &str) -> Result<(), SaveError> {
+        Ok(fs::write(path, self.to_ron()?)?)
     }
-}
 
-impl<const L: usize> Algorithm2D for City<L> {
-    fn dimensions(&self) -> BracketPoint {
-        BracketPoint::new(self.x_len, self.y_len)
+    fn load(path: &str) -> Result<Self, SaveError> {
+        Self::from_ron(&fs::read_to_string(path)?)
     }
 }
 
-fn setup(
-    mut commands: Commands,
+/// F5 to save, F9 to load.
+#[allow(clippy::too_many_arguments)]
+fn keyboard_save_load(
+    keys: Res<Input<KeyCode>>,
+    mut rng: ResMut<SimRng>,
+    mut city: ResMut<ActiveCity>,
+    building_query: Query<(Entity, &GridCoords, Option<&District>), With<Building>>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    mut window_query: Query<&mut Window>,
+    mut commands: Commands,
 ) {
-    let city = City::new(STARTING_CITY);
-    let building_coords = city.buildings_iter();
-
-    let mut window = window_query.single_mut();
-    window.cursor.visible = false;
-
-    // camera
-    commands.spawn(Camera3dBundle {
-        projection: OrthographicProjection {
-            scale: 3.0,
-            scaling_mode: bevy::render::camera::ScalingMode::FixedVertical(2.0),
-            ..default()
+    if keys.just_pressed(KeyCode::F5) {
+        let save = SaveGame {
+            rng: rng.0.clone(),
+            districts: building_query
+                .iter()
+                .filter_map(|(_, &coords, district)| Some((coords, *district?)))
+                .collect(),
+            city: Some(save_city(city.as_ref())),
+        };
+        match save.save(SAVE_FILE) {
+            Ok(()) => eprintln!("saved to {}", SAVE_FILE),
+            Err(e) => eprintln!("{}", e),
         }
-        .into(),
-        transform: Transform::from_xyz(4.0, 5.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
-        ..default()
-    });
-
-    // ground
-    commands
-        .spawn(PbrBundle {
-            mesh: meshes.add(shape::Plane::from_size(6.0).into()),
-            material: materials.add(Color::rgb(0.3, 0.5, 0.3).into()),
-            ..default()
-        })
-        .insert(Ground);
-
-    for (coords, height) in building_coords {
-        commands
-            .spawn(BuildingBundle::add(
-                &mut meshes,
-                &mut materials,
-                Building { height },
-            ))
-            .insert(coords);
     }
 
-    // light
-    commands.spawn(PointLightBundle {
-        transform: Transform::from_xyz(0.0, 8.0, 0.0),
-        point_light: PointLight {
-            shadows_enabled: true,
-            ..default()
-        },
-        ..default()
-    });
-
-    // cursor
-    commands
-        .spawn(PbrBundle {
-            // the cursor is a lump of coal until I can be bothered
-            mesh: meshes.add(Mesh::from(shape::UVSphere {
-                radius: 0.1,
-                sectors: 5,
-                stacks: 5,
-            })),
-            material: materials.add(Color::BLACK.into()),
-            ..default()
-        })
-        .insert(Cursor);
-
-    // person
-    // TODO bundle me
-    let mut rng = rand::thread_rng();
-    for _ in 0..NUM_PEOPLE {
-        let x = rng.gen_range(-2.0..2.0);
-        let z = rng.gen_range(-2.0..2.0);
-        commands
-            .spawn(PbrBundle {
-                mesh: meshes.add(Mesh::from(shape::Cylinder {
-                    radius: 0.025,
-                    height: PERSON_HEIGHT,
-                    ..default()
-                })),
-                material: materials.add(Color::rgb(0.1, 0.1, 0.1).into()),
-                transform: Transform::from_xyz(x, PERSON_HEIGHT * 0.5, z),
-                ..default()
-            })
-            .insert(Person::default())
-            .insert(Velocity::ZERO);
+    if keys.just_pressed(KeyCode::F9) {
+        let loaded = SaveGame::load(SAVE_FILE).and_then(|save| {
+            let city = save
+                .city
+                .as_ref()
+                .map(|saved| load_city(saved, city.as_ref()))
+                .transpose()?;
+            Ok((save, city))
+        });
+        match loaded {
+            Ok((save, Some(loaded_city))) => {
+                rng.0 = save.rng;
+                for (building, _, _) in &building_query {
+                    commands.entity(building).despawn();
+                }
+                *city = loaded_city;
+                let districts: HashMap<_, _> = save.districts.into_iter().collect();
+                for (coords, height) in city.distinct_buildings() {
+                    let mut building = commands.spawn(BuildingBundle::add(
+                        &mut meshes,
+                        &mut materials,
+                        Building {
+                            footprint: city.footprint_at(coords),
+                            ..Building::new(height)
+                        },
+                    ));
+                    building.insert(coords);
+                    if let Some(&district) = districts.get(&coords) {
+                        building.insert(district);
+                    }
+                }
+                eprintln!("loaded from {}", SAVE_FILE);
+            }
+            Ok((save, None)) => {
+                rng.0 = save.rng;
+                let districts: HashMap<_, _> = save.districts.into_iter().collect();
+                for (building, coords, district) in &building_query {
+                    match districts.get(coords) {
+                        Some(&saved) if district != Some(&saved) => {
+                            commands.entity(building).insert(saved);
+                        }
+                        None if district.is_some() => {
+                            commands.entity(building).remove::<District>();
+                        }
+                        _ => {}
+                    }
+                }
+                eprintln!("loaded from {}", SAVE_FILE);
+            }
+            Err(e) => eprintln!("{}, not loading", e),
+        }
     }
+}
 
-    commands.insert_resource(city);
+/// Backgrounds to suit different moods and screenshots.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum BackgroundPreset {
+    #[default]
+    DaySky,
+    Night,
+    NeutralGray,
 }
 
-fn position_objects_on_grid(mut q: Query<(&mut Transform, &GridCoords)>) {
-    for (mut tx, coords) in &mut q {
-        tx.translation = coords.to_world(0.5); // TODO
+impl BackgroundPreset {
+    fn next(self) -> Self {
+        match self {
+            Self::DaySky => Self::Night,
+            Self::Night => Self::NeutralGray,
+            Self::NeutralGray => Self::DaySky,
+        }
+    }
+
+    fn color(self) -> Color {
+        match self {
+            Self::DaySky => Color::rgb(0.53, 0.74, 0.92),
+            Self::Night => Color::rgb(0.02, 0.02, 0.08),
+            Self::NeutralGray => Color::rgb(0.4, 0.4, 0.4),
+        }
     }
 }
 
-fn keyboard_move_camera(
-    time: Res<Time>,
+/// Press X to cycle through the background presets.
+fn cycle_background(
     keys: Res<Input<KeyCode>>,
-    mut q: Query<(&mut Projection, &mut Transform)>,
+    mut preset: Local<BackgroundPreset>,
+    mut clear_color: ResMut<ClearColor>,
 ) {
-    let secs = time.delta_seconds();
-    let (mut proj, mut camera_tx) = q.single_mut();
+    if keys.just_pressed(KeyCode::X) {
+        *preset = preset.next();
+        clear_color.0 = preset.color();
+    }
+}
 
-    let velocity_right = if keys.pressed(KeyCode::A) {
-        1.0
-    } else if keys.pressed(KeyCode::D) {
-        -1.0
-    } else {
-        0.0
-    };
+/// The point the camera looks at.
+#[derive(Default, Resource)]
+struct CameraFocus(Vec3);
 
-    let velocity = velocity_right * camera_tx.right();
-    if velocity != Vec3::ZERO {
-        camera_tx.translation += velocity * CAMERA_MOVE_SPEED * secs;
-        camera_tx.look_at(Vec3::ZERO, Vec3::Y);
-    }
+#[derive(Resource)]
+struct Options {
+    draw_paths: bool,
+    draw_selection: bool,
+    /// pick buildings by their visible body rather than the ground cell under the cursor
+    pick_buildings: bool,
+    /// how much to grow (or shrink, if negative) building boxes when picking
+    pick_margin: f32,
+    show_histogram: bool,
+    /// draw buildings as outlines instead of solid
+    blueprint: bool,
+    /// plan around the cells other people have reserved along their paths
+    cooperative_pathing: bool,
+    animate_light: bool,
+    /// light the city with a directional sun rather than a point light
+    sunlight: bool,
+    /// light the point light at night when the sun's down
+    street_lamp: bool,
+    /// keep the light on the camera, wherever it goes
+    headlamp: bool,
+    show_height_labels: bool,
+    /// bake cell boundaries into the ground's texture
+    grid_texture: bool,
+    /// how many steps further along their path than the next one people steer towards, so they
+    /// start turning corners early (at the risk of clipping them)
+    steering_lookahead: usize,
+    /// rewrite planned paths to only ever move along the grid axes
+    manhattan_paths: bool,
+    /// keep walking through each step of a path rather than stopping at every one
+    pass_through_steps: bool,
+    /// how quickly people speed up and slow down, in units/s²
+    person_acceleration: f32,
+    /// people steer away from the cursor
+    flee_cursor: bool,
+    flee_radius: f32,
+    flee_strength: f32,
+    /// how close people get before they start stepping out of each other's way
+    avoid_radius: f32,
+    avoid_strength: f32,
+    /// warn if `people_walk` takes longer than this in a frame
+    walk_budget_ms: f32,
+    /// chance of a derelict building being abandoned at each upkeep
+    abandon_chance: f64,
+    /// buildings with fewer visits than this to their neighbouring cells between upkeeps are
+    /// derelict
+    abandon_traffic_threshold: u32,
+    /// refuse to put up any more buildings than this
+    max_buildings: Option<usize>,
+    /// fraction of cells to put buildings on when generating a city
+    generation_density: f64,
+    generation_max_height: Height,
+    /// describe the cell under the cursor once it's rested there a moment
+    show_tooltip: bool,
+    /// how long the cursor has to stay on a cell before its tooltip appears
+    tooltip_delay: Duration,
+    /// camera distances beyond which people are drawn with the medium and low detail meshes,
+    /// multiplied by the camera's zoom since the view is orthographic and only zooming out makes
+    /// them look smaller
+    person_lod_distances: [f32; 2],
+    /// draw the graph the pathfinder walks: a line from each walkable cell to each of its exits
+    show_navigation_graph: bool,
+    /// wait for the display before presenting each frame, rather than going as fast as possible
+    vsync: bool,
+    /// frames per second not to go over, whatever the vsync setting
+    frame_rate_cap: Option<f32>,
+    /// outline each district's edge on the ground
+    show_districts: bool,
+    /// size of the shadow maps, in texels; fitted to the city if not set
+    shadow_map_size: Option<usize>,
+    /// how far from the camera the sun casts shadows; fitted to the city if not set
+    shadow_distance: Option<f32>,
+    /// show counts and toggles in the corner of the screen
+    show_hud: bool,
+    /// mark where the city's buildings balance
+    show_center_of_mass: bool,
+    /// the shape of the tops of buildings
+    building_style: BuildingStyle,
+    /// what new buildings are placed as
+    building_kind: BuildingKind,
+    /// how many cells new buildings cover
+    footprint: Footprint,
+    /// put the cursor in the middle of the cell it's over rather than exactly under the mouse
+    snap_cursor: bool,
+    /// outline every cell on the ground
+    draw_grid: bool,
+    /// log every path as it's planned
+    verbose_pathing: bool,
+    /// show a bird's eye view of the whole city in the corner
+    show_minimap: bool,
+    /// how long people rest on reaching a goal before choosing another, picked at random from
+    /// this range
+    dwell_time: (Duration, Duration),
+    /// hand out new goals and paths; off lets everyone finish their current trip and stop
+    plan_paths: bool,
+    /// tint the ground under each cell by whether people can walk there
+    show_walkable_tint: bool,
+    /// outline cells where people are stuck in a jam
+    show_jams: bool,
+    /// people averaging less than this speed, in units/s...
+    jam_speed: f32,
+    /// ...over this many seconds are jammed
+    jam_window: f32,
+}
 
-    // trickery to deal with the Mut<> of an enum
-    let Projection::Orthographic(proj) = &mut *proj else {
-        unreachable!("projection is no longer orthographic");
-    };
-    let scale_amount = (CAMERA_ZOOM_SPEED * CAMERA_MOVE_SPEED * secs).clamp(0.0, 0.1);
-    if keys.pressed(KeyCode::W) {
-        let factor = 1.0 - scale_amount;
-        proj.scale = (proj.scale * factor).max(3.0);
-    } else if keys.pressed(KeyCode::S) {
-        let factor = 1.0 + scale_amount;
-        proj.scale = (proj.scale * factor).min(100.0);
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            draw_paths: false,
+            draw_selection: false,
+            pick_buildings: false,
+            pick_margin: 0.0,
+            show_histogram: false,
+            blueprint: false,
+            cooperative_pathing: false,
+            animate_light: true,
+            sunlight: false,
+            street_lamp: true,
+            headlamp: false,
+            show_height_labels: false,
+            grid_texture: false,
+            steering_lookahead: 0,
+            manhattan_paths: false,
+            pass_through_steps: false,
+            person_acceleration: 4.0,
+            flee_cursor: false,
+            flee_radius: 1.0,
+            flee_strength: 2.0,
+            avoid_radius: 0.4,
+            avoid_strength: 3.0,
+            max_buildings: None,
+            generation_density: 0.3,
+            generation_max_height: 5,
+            walk_budget_ms: 4.0,
+            abandon_chance: 0.1,
+            abandon_traffic_threshold: 1,
+            show_tooltip: false,
+            tooltip_delay: Duration::from_millis(400),
+            // the starting view is zoomed out to 3, so as near as 8 and 16 units away
+            person_lod_distances: [24.0, 48.0],
+            show_navigation_graph: false,
+            vsync: true,
+            frame_rate_cap: None,
+            show_districts: false,
+            shadow_map_size: None,
+            shadow_distance: None,
+            show_hud: true,
+            show_center_of_mass: false,
+            building_style: default(),
+            building_kind: default(),
+            footprint: default(),
+            snap_cursor: true,
+            draw_grid: false,
+            verbose_pathing: false,
+            show_minimap: false,
+            dwell_time: (Duration::from_secs(1), Duration::from_secs(4)),
+            plan_paths: true,
+            show_walkable_tint: false,
+            show_jams: false,
+            jam_speed: 0.05,
+            jam_window: 2.0,
+        }
     }
 }
 
-fn keyboard_set_options(keys: Res<Input<KeyCode>>, mut options: ResMut<Options>) {
-    if keys.just_pressed(KeyCode::P) {
-        options.draw_paths = !options.draw_paths;
-    }
-    if keys.just_pressed(KeyCode::E) {
-        options.draw_selection = !options.draw_selection;
-    }
+/// Grid coords are `i8`s centred on the origin, so neither side can be longer than this.
+const MAX_CITY_LEN: usize = i8::MAX as usize;
+
+#[derive(Debug, PartialEq, Eq)]
+enum CityError {
+    /// the dimensions don't account for exactly the cells given
+    DimensionMismatch {
+        x_len: usize,
+        y_len: usize,
+        cells: usize,
+    },
+    /// a city has to be at least one cell in each direction
+    Degenerate { x_len: usize, y_len: usize },
+    /// too big to address with grid coords
+    TooLarge { x_len: usize, y_len: usize },
 }
 
-fn move_light(time: Res<Time>, mut light_tx: Query<&mut Transform, With<PointLight>>) {
-    let mut light_tx = light_tx.get_single_mut().unwrap();
-    let mut light_pos = &mut light_tx.translation;
-    let elapsed = time.elapsed_seconds() * LIGHT_MOVE_SPEED;
-    light_pos.x = 3.0 * elapsed.sin();
-    light_pos.z = 5.0 * elapsed.cos();
+impl fmt::Display for CityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DimensionMismatch {
+                x_len,
+                y_len,
+                cells,
+            } => write!(f, "a {}×{} city can't have {} cells", x_len, y_len, cells),
+            Self::Degenerate { x_len, y_len } => write!(f, "a {}×{} city is empty", x_len, y_len),
+            Self::TooLarge { x_len, y_len } => write!(
+                f,
+                "a {}×{} city is too big, max is {}×{}",
+                x_len, y_len, MAX_CITY_LEN, MAX_CITY_LEN
+            ),
+        }
+    }
 }
 
-type Height = u8;
+impl std::error::Error for CityError {}
 
-#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
-struct GridCoords {
-    x: i8,
-    y: i8,
+/// How many cells across and deep the app's city is.  Change them here to try out other sizes.
+const ACTIVE_CITY_SIZE: (usize, usize) = (5, 5);
+
+/// The city the app runs with.
+type ActiveCity = City;
+
+/// The cells of a city a row at a time, `x_len` to a row.
+#[derive(Resource, Clone)]
+struct City {
+    heights: Vec<Height>,
+    elevations: Vec<Elevation>,
+    roads: Vec<bool>,
+    /// for each cell under a building bigger than one cell, the index of that building's anchor
+    /// cell
+    blocks: Vec<Option<usize>>,
+    x_len: usize,
+    y_len: usize,
+    /// the grid coords of the middle cell, so the city can sit anywhere in the world
+    origin_offset: GridCoords,
+    /// which neighbouring cells people can walk to
+    connectivity: Connectivity,
+    /// how big the cells are in the world
+    scale: WorldScale,
 }
 
-impl GridCoords {
-    #[allow(dead_code)]
-    const ORIGIN: GridCoords = GridCoords { x: 0, y: 0 };
+/// Whether people can only walk to orthogonal neighbours, or diagonally too.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Connectivity {
+    #[default]
+    Four,
+    Eight,
+}
 
-    fn new(x: i8, y: i8) -> Self {
-        Self { x, y }
+impl Connectivity {
+    fn toggled(self) -> Self {
+        match self {
+            Connectivity::Four => Connectivity::Eight,
+            Connectivity::Eight => Connectivity::Four,
+        }
     }
+}
 
-    fn from_world(world: Vec3) -> Self {
-        GridCoords::new(world.x.round() as i8, world.z.round() as i8)
+impl City {
+    /// A city `x_len` cells across and `y_len` deep with nothing built on it yet.
+    fn empty((x_len, y_len): (usize, usize)) -> Self {
+        Self::with_dimensions(vec![0; x_len * y_len], x_len, y_len)
     }
 
-    fn to_world(&self, elevation: f32) -> Vec3 {
-        // grid xy is world xz (world y is elevation)
-        Vec3::new(self.x as f32, elevation, self.y as f32)
+    /// A square city, with the heights given a row at a time.  Panics if they don't fill a
+    /// square.
+    #[cfg_attr(not(test), allow(dead_code))] // the app starts from `empty` or `random`
+    fn new(heights: impl Into<Vec<Height>>) -> Self {
+        let heights = heights.into();
+        let side = (heights.len() as f64).sqrt() as usize;
+        Self::with_dimensions(heights, side, side)
     }
 
-    fn manhattan_dist(&self, dest: Self) -> i8 {
-        (dest.x - self.x).abs() + (dest.y - self.y).abs()
+    /// A city `x_len` cells across and `y_len` deep, with the heights given a row at a time.
+    /// Panics if there aren't exactly that many heights, or `try_new` won't take the size.
+    fn with_dimensions(heights: Vec<Height>, x_len: usize, y_len: usize) -> Self {
+        Self::try_new(heights, x_len, y_len).unwrap_or_else(|err| panic!("{}", err))
     }
 
-    fn up(&self) -> Self {
-        Self {
-            x: self.x,
-            y: self.y + 1,
+    fn try_new(heights: Vec<Height>, x_len: usize, y_len: usize) -> Result<Self, CityError> {
+        if x_len == 0 || y_len == 0 {
+            return Err(CityError::Degenerate { x_len, y_len });
         }
+        if x_len > MAX_CITY_LEN || y_len > MAX_CITY_LEN {
+            return Err(CityError::TooLarge { x_len, y_len });
+        }
+        if x_len * y_len != heights.len() {
+            return Err(CityError::DimensionMismatch {
+                x_len,
+                y_len,
+                cells: heights.len(),
+            });
+        }
+
+        let cells = heights.len();
+        Ok(Self {
+            heights,
+            elevations: vec![0; cells],
+            roads: vec![false; cells],
+            blocks: vec![None; cells],
+            x_len,
+            y_len,
+            origin_offset: GridCoords::ORIGIN,
+            connectivity: Connectivity::Four,
+            scale: WorldScale::default(),
+        })
     }
 
-    fn down(&self) -> Self {
-        Self {
-            x: self.x,
-            y: self.y - 1,
-        }
+    fn with_origin_offset(mut self, origin_offset: GridCoords) -> Self {
+        self.origin_offset = origin_offset;
+        self
     }
 
-    fn left(&self) -> Self {
-        Self {
-            x: self.x - 1,
-            y: self.y,
-        }
+    fn with_scale(mut self, scale: WorldScale) -> Self {
+        self.scale = scale;
+        self
     }
 
-    fn right(&self) -> Self {
-        Self {
-            x: self.x + 1,
-            y: self.y,
-        }
+    fn with_connectivity(mut self, connectivity: Connectivity) -> Self {
+        self.connectivity = connectivity;
+        self
     }
-}
 
-#[derive(Component)]
-struct Cursor;
+    /// A cell picked uniformly at random from the whole city.
+    fn random_coords(&self, rng: &mut impl Rng) -> GridCoords {
+        self.index_to_coords(rng.gen_range(0..self.cells()))
+    }
 
-#[derive(Component)]
-struct Ground;
+    /// A cell with no building on it, picked at random, or none if a few tries all hit
+    /// buildings.
+    fn random_empty_coords(&self, rng: &mut impl Rng) -> Option<GridCoords> {
+        (0..MAX_GOAL_ATTEMPTS)
+            .map(|_| self.random_coords(rng))
+            .find(|&coords| self.is_walkable(coords))
+    }
 
-#[derive(Component)]
-struct Building {
-    height: Height,
+    /// How many cells there are in the whole city.
+    fn cells(&self) -> usize {
+        self.heights.len()
+    }
+
+    /// A randomly generated city `x_len` by `y_len` that's always the same for the same seed.
+    /// `density` is roughly the fraction of cells built on.
+    fn random((x_len, y_len): (usize, usize), seed: u64, density: f32) -> Self {
+        Self::generate(
+            (x_len, y_len),
+            &mut ChaCha8Rng::seed_from_u64(seed),
+            density.clamp(0.0, 1.0) as f64,
+            RANDOM_CITY_MAX_HEIGHT,
+        )
+    }
+
+    /// Scatters buildings randomly, mostly short ones, retrying if they cut the city up too much.
+    fn generate(
+        (x_len, y_len): (usize, usize),
+        rng: &mut impl Rng,
+        density: f64,
+        max_height: Height,
+    ) -> Self {
+        let max_height = max_height.clamp(1, MAX_BUILDING_HEIGHT);
+
+        let mut best: Option<(Self, usize)> = None;
+        for _ in 0..CITY_GENERATION_ATTEMPTS {
+            let mut heights = vec![0; x_len * y_len];
+            for height in &mut heights {
+                if rng.gen_bool(density) {
+                    // squaring skews the distribution towards short buildings
+                    let tallness = rng.gen::<f32>().powi(2);
+                    *height = 1 + (tallness * (max_height - 1) as f32).round() as Height;
+                }
+            }
+            let city = Self::with_dimensions(heights, x_len, y_len);
+
+            let empty = city.heights.iter().filter(|&&h| h == 0).count();
+            let connected = city.largest_walkable_region();
+            if connected as f32 >= CITY_GENERATION_CONNECTEDNESS * empty as f32 {
+                return city;
+            }
+            if best.as_ref().is_none_or(|&(_, best)| connected > best) {
+                best = Some((city, connected));
+            }
+        }
+
+        best.unwrap().0
+    }
+
+    /// The number of cells in the biggest group of empty cells people can walk between.
+    fn largest_walkable_region(&self) -> usize {
+        let mut seen = vec![false; self.cells()];
+        let mut largest = 0;
+        for start in 0..self.cells() {
+            if seen[start] || self.heights[start] > 0 {
+                continue;
+            }
+            seen[start] = true;
+            let mut size = 0;
+            let mut frontier = vec![start];
+            while let Some(idx) = frontier.pop() {
+                size += 1;
+                for (next, _) in self.get_available_exits(idx) {
+                    if !seen[next] {
+                        seen[next] = true;
+                        frontier.push(next);
+                    }
+                }
+            }
+            largest = largest.max(size);
+        }
+        largest
+    }
+
+    fn buildings_iter<'a>(&'a self) -> impl Iterator<Item = (GridCoords, Height)> + 'a {
+        self.heights.iter().enumerate().flat_map(move |(i, &h)| {
+            if h > 0 {
+                Some((self.index_to_coords(i), h))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Each building once, at its anchor cell, however many cells it covers.
+    fn distinct_buildings(&self) -> impl Iterator<Item = (GridCoords, Height)> + '_ {
+        self.buildings_iter().filter(|&(coords, _)| {
+            self.coords_to_index(coords)
+                .is_some_and(|idx| self.blocks[idx].is_none_or(|anchor| anchor == idx))
+        })
+    }
+
+    fn building_count(&self) -> usize {
+        self.distinct_buildings().count()
+    }
+
+    /// Buildings with no other building directly next to them.
+    fn isolated_buildings(&self) -> Vec<(GridCoords, Height)> {
+        self.distinct_buildings()
+            .filter(|&(coords, _)| {
+                self.building_neighbors(coords)
+                    .into_iter()
+                    .all(|neighbor| self.height_at_coords(neighbor).is_none())
+            })
+            .collect()
+    }
+
+    /// The cells directly next to any part of the building on the given cell, but not under it.
+    fn building_neighbors(&self, coords: GridCoords) -> Vec<GridCoords> {
+        let cells = self.building_cells(coords);
+        let mut neighbors = vec![];
+        for neighbor in cells.iter().flat_map(|cell| cell.neighbors()) {
+            if !cells.contains(&neighbor) && !neighbors.contains(&neighbor) {
+                neighbors.push(neighbor);
+            }
+        }
+        neighbors
+    }
+
+    /// Sets every building in `cells` to the same height: `height` if given, otherwise the
+    /// (rounded) average of their heights.  Returns the height they ended up at, if there were
+    /// any buildings there at all.
+    fn equalize_heights(
+        &mut self,
+        cells: impl IntoIterator<Item = GridCoords>,
+        height: Option<Height>,
+    ) -> Option<Height> {
+        // each building once, however many of its cells are in there
+        let mut buildings: Vec<(Vec<GridCoords>, Height)> = vec![];
+        for coords in cells {
+            let Some(height) = self.height_at_coords(coords) else {
+                continue;
+            };
+            if !buildings
+                .iter()
+                .any(|(covered, _)| covered.contains(&coords))
+            {
+                buildings.push((self.building_cells(coords), height));
+            }
+        }
+        if buildings.is_empty() {
+            return None;
+        }
+        let height = height.unwrap_or_else(|| {
+            let total: usize = buildings.iter().map(|&(_, h)| h as usize).sum();
+            ((total as f32 / buildings.len() as f32).round() as Height).max(1)
+        });
+        for cell in buildings.into_iter().flat_map(|(covered, _)| covered) {
+            self.set_height_at_coords(cell, Some(height));
+        }
+        Some(height)
+    }
+
+    /// Puts a storey on the building at `coords`, or a new building there if there isn't one and
+    /// the city has fewer than `max_buildings`.  Returns the building's new height, if it got
+    /// built.
+    fn add_storey(&mut self, coords: GridCoords, max_buildings: Option<usize>) -> Option<Height> {
+        self.coords_to_index(coords)?;
+        let height = match self.height_at_coords(coords) {
+            Some(height) if height >= MAX_BUILDING_HEIGHT => return None,
+            Some(height) => height + 1,
+            None if max_buildings.is_some_and(|max| self.building_count() >= max) => {
+                return None;
+            }
+            None => 1,
+        };
+        for cell in self.building_cells(coords) {
+            self.set_height_at_coords(cell, Some(height));
+        }
+        Some(height)
+    }
+
+    /// Every cell of the building on the given cell: just that one, unless the building covers
+    /// more.
+    fn building_cells(&self, coords: GridCoords) -> Vec<GridCoords> {
+        match self
+            .coords_to_index(coords)
+            .and_then(|idx| self.blocks[idx])
+        {
+            Some(anchor) => (0..self.cells())
+                .filter(|&idx| self.blocks[idx] == Some(anchor))
+                .map(|idx| self.index_to_coords(idx))
+                .collect(),
+            None => vec![coords],
+        }
+    }
+
+    /// The shape of the building on the given cell.
+    fn footprint_at(&self, coords: GridCoords) -> Footprint {
+        if self.building_cells(coords).len() > 1 {
+            Footprint::TwoByTwo
+        } else {
+            Footprint::OneByOne
+        }
+    }
+
+    /// The way into the building on the given cell: the first empty cell next to any part of it.
+    /// None if it's walled in.
+    fn entrance(&self, coords: GridCoords) -> Option<GridCoords> {
+        open_neighbor(self, &self.building_cells(coords))
+    }
+
+    /// Somewhere for someone to work: the entrance of a building picked at random, or any empty
+    /// cell if no building has a way in.
+    fn random_workplace(&self, rng: &mut impl Rng) -> Option<GridCoords> {
+        let entrances: Vec<_> = self
+            .occupied_coords()
+            .filter_map(|coords| self.entrance(coords))
+            .collect();
+        entrances
+            .choose(rng)
+            .copied()
+            .or_else(|| self.random_empty_coords(rng))
+    }
+
+    /// Puts up a one-storey building over the footprint anchored at the given cell, as long as
+    /// every cell it covers is on the grid and empty.  Returns whether it did.
+    fn place_building(
+        &mut self,
+        anchor: GridCoords,
+        footprint: Footprint,
+        max_buildings: Option<usize>,
+    ) -> bool {
+        let cells: Vec<_> = footprint.cells(anchor).collect();
+        if cells.iter().any(|&cell| !self.is_walkable(cell))
+            || max_buildings.is_some_and(|max| self.building_count() >= max)
+        {
+            return false;
+        }
+        let anchor_idx = self.coords_to_index(anchor);
+        for &cell in &cells {
+            self.set_height_at_coords(cell, Some(1));
+            if cells.len() > 1 {
+                if let Some(idx) = self.coords_to_index(cell) {
+                    self.blocks[idx] = anchor_idx;
+                }
+            }
+        }
+        true
+    }
+
+    /// Knocks down the building on the given cell, all of it if it covers more than one.
+    fn remove_building(&mut self, coords: GridCoords) {
+        for cell in self.building_cells(coords) {
+            self.set_height_at_coords(cell, None);
+            if let Some(idx) = self.coords_to_index(cell) {
+                self.blocks[idx] = None;
+            }
+        }
+    }
+
+    /// Where the weight of all the buildings balances, treating each storey as equally heavy.
+    /// None for a city with no buildings.
+    fn center_of_mass(&self) -> Option<Vec3> {
+        let (moment, mass) =
+            self.buildings_iter()
+                .fold((Vec3::ZERO, 0.0), |(moment, mass), (coords, height)| {
+                    let height = height as f32;
+                    (
+                        moment + self.coords_to_world(coords, height * 0.5) * height,
+                        mass + height,
+                    )
+                });
+        (mass > 0.0).then(|| moment / mass)
+    }
+
+    /// How many buildings there are of each height from 1 to `MAX_BUILDING_HEIGHT`, with any
+    /// taller ones counted in the top bucket.
+    fn height_histogram(&self) -> [usize; MAX_BUILDING_HEIGHT as usize] {
+        let mut histogram = [0; MAX_BUILDING_HEIGHT as usize];
+        for (_, height) in self.distinct_buildings() {
+            histogram[(height.min(MAX_BUILDING_HEIGHT) - 1) as usize] += 1;
+        }
+        histogram
+    }
+
+    fn height_at_coords(&self, coords: GridCoords) -> Option<Height> {
+        let idx = self.coords_to_index(coords)?;
+        let h = self.heights[idx];
+        if h > 0 {
+            Some(h)
+        } else {
+            None
+        }
+    }
+
+    /// Leaves the cell as it was if the height is over `MAX_BUILDING_HEIGHT`.
+    fn set_height_at_coords(&mut self, coords: GridCoords, height: Option<Height>) {
+        let Some(idx) = self.coords_to_index(coords) else {
+            return;
+        };
+        if height.is_some_and(|height| height > MAX_BUILDING_HEIGHT) {
+            return;
+        }
+        self.heights[idx] = height.unwrap_or(0);
+    }
+
+    /// Knocks down every building, leaving the terrain as it is.
+    fn clear(&mut self) {
+        let built: Vec<_> = self.occupied_coords().collect();
+        for coords in built {
+            self.set_height_at_coords(coords, None);
+        }
+        self.blocks.fill(None);
+    }
+
+    fn elevation_at_coords(&self, coords: GridCoords) -> Option<Elevation> {
+        let idx = self.coords_to_index(coords)?;
+        Some(self.elevations[idx])
+    }
+
+    fn set_elevation_at_coords(&mut self, coords: GridCoords, elevation: Elevation) {
+        let Some(idx) = self.coords_to_index(coords) else {
+            return;
+        };
+        self.elevations[idx] = elevation;
+    }
+
+    fn tile_kind(&self, coords: GridCoords) -> Option<TileKind> {
+        let idx = self.coords_to_index(coords)?;
+        Some(if self.heights[idx] > 0 {
+            TileKind::Building
+        } else if self.roads[idx] {
+            TileKind::Road
+        } else {
+            TileKind::Empty
+        })
+    }
+
+    /// Road cells that haven't since been built over.
+    fn roads_iter<'a>(&'a self) -> impl Iterator<Item = GridCoords> + 'a {
+        self.roads.iter().enumerate().flat_map(move |(i, &road)| {
+            if road && self.heights[i] == 0 {
+                Some(self.index_to_coords(i))
+            } else {
+                None
+            }
+        })
+    }
+
+    fn set_road_at_coords(&mut self, coords: GridCoords, road: bool) {
+        let Some(idx) = self.coords_to_index(coords) else {
+            return;
+        };
+        self.roads[idx] = road;
+    }
+
+    fn coords_to_index(&self, coords: GridCoords) -> Option<usize> {
+        // widened first, so coords far off the grid can't overflow on the way
+        let shifted_y =
+            i32::from(coords.y) - i32::from(self.origin_offset.y) + (self.y_len as i32 / 2);
+        let shifted_x =
+            i32::from(coords.x) - i32::from(self.origin_offset.x) + (self.x_len as i32 / 2);
+        if shifted_x < 0
+            || shifted_x as usize >= self.x_len
+            || shifted_y < 0
+            || shifted_y as usize >= self.y_len
+        {
+            None
+        } else {
+            Some(shifted_y as usize * self.x_len + shifted_x as usize)
+        }
+    }
+
+    fn index_to_coords(&self, idx: usize) -> GridCoords {
+        let half_xl = (self.x_len / 2) as i8;
+        let half_yl = (self.y_len / 2) as i8;
+
+        let x = idx % self.x_len;
+        let y = idx / self.x_len;
+
+        GridCoords::new(
+            x as i8 - half_xl + self.origin_offset.x,
+            (y as i8) - half_yl + self.origin_offset.y,
+        )
+    }
+
+    /// The corners of the ground the grid covers, lowest first.
+    fn world_bounds(&self) -> (Vec3, Vec3) {
+        // cells are a square centred on their coords
+        let half_cell = Vec3::new(0.5, 0.0, 0.5) * self.scale.0;
+        let min = self.index_to_coords(0).to_world(0.0, self.scale) - half_cell;
+        let max = self
+            .index_to_coords(self.x_len * self.y_len - 1)
+            .to_world(0.0, self.scale)
+            + half_cell;
+        (min, max)
+    }
+
+    /// The corners of the ground the city stands on: the grid with half a cell spare on each
+    /// side.
+    fn ground_bounds(&self) -> (Vec3, Vec3) {
+        let (min, max) = self.world_bounds();
+        let spare = Vec3::new(0.5, 0.0, 0.5) * self.scale.0;
+        (min - spare, max + spare)
+    }
+
+    /// How many cells the ground spans across and deep, counting the spare halves.
+    fn ground_cells(&self) -> (usize, usize) {
+        (self.x_len + 1, self.y_len + 1)
+    }
+
+    /// Finds the first building the ray hits, and where it hits it.
+    fn pick_building(&self, ray: Ray, margin: f32) -> Option<(GridCoords, Vec3)> {
+        self.buildings_iter()
+            .filter_map(|(coords, height)| {
+                let half = Vec3::new(0.5 + margin, 0.0, 0.5 + margin) * self.scale.0;
+                let min = self.coords_to_world(coords, 0.0) - half;
+                let max = self.coords_to_world(coords, height as f32 + margin) + half;
+                ray_box_distance(ray, min, max).map(|distance| (coords, distance))
+            })
+            .min_by(|(_, d1), (_, d2)| d1.total_cmp(d2))
+            .map(|(coords, distance)| (coords, ray.get_point(distance)))
+    }
+
+    /// Where in the world a point `above` the terrain of a cell is.  Off the grid there's no
+    /// terrain, so it's measured from flat ground.
+    fn coords_to_world(&self, coords: GridCoords, above: f32) -> Vec3 {
+        let terrain = self.elevation_at_coords(coords).unwrap_or(0);
+        coords.to_world(terrain as f32 * ELEVATION_STEP + above, self.scale)
+    }
+
+    /// Which cell a point in the world is over, whether or not it's in the city.
+    fn world_to_coords(&self, world: Vec3) -> GridCoords {
+        GridCoords::from_world(world, self.scale)
+    }
+
+    fn index_to_world(&self, idx: usize, elevation: f32) -> Vec3 {
+        self.coords_to_world(self.index_to_coords(idx), elevation)
+    }
+
+    /// The shortest walk between two empty cells, or none if there isn't one.
+    fn find_path(&self, from: GridCoords, to: GridCoords) -> Option<NavigationPath> {
+        let start = self.valid_exit(from)?;
+        let end = self.valid_exit(to)?;
+        let path = a_star_search(start, end, self);
+        (path.success && !path.steps.is_empty()).then_some(path)
+    }
+
+    fn valid_exit(&self, coords: GridCoords) -> Option<usize> {
+        self.coords_to_index(coords)
+            .filter(|_| self.is_walkable(coords))
+    }
+
+    /// Whether someone could stand on the cell: it's on the grid and nothing's built there.
+    fn is_walkable(&self, coords: GridCoords) -> bool {
+        self.coords_to_index(coords)
+            .is_some_and(|idx| self.heights[idx] == 0)
+    }
+
+    /// Every cell with a building on it, counting each cell a bigger building covers.
+    fn occupied_coords(&self) -> impl Iterator<Item = GridCoords> + '_ {
+        self.buildings_iter().map(|(coords, _)| coords)
+    }
+
+    /// The index of and cost of moving to a neighbouring cell, if it's possible to walk there.
+    fn exit_to(&self, from: GridCoords, to: GridCoords) -> Option<(usize, f32)> {
+        let idx = self.valid_exit(to)?;
+        let climb = self.elevation_at_coords(to)? as i16 - self.elevation_at_coords(from)? as i16;
+        if climb.abs() > MAX_WALKABLE_SLOPE as i16 {
+            return None;
+        }
+        let distance = if from.x != to.x && from.y != to.y {
+            // no squeezing diagonally between two buildings, or round the corner of one
+            let corners = [GridCoords::new(from.x, to.y), GridCoords::new(to.x, from.y)];
+            if corners.iter().any(|&corner| !self.is_walkable(corner)) {
+                return None;
+            }
+            std::f32::consts::SQRT_2
+        } else {
+            1.0
+        };
+        let distance = match self.tile_kind(to) {
+            Some(TileKind::Road) => distance * ROAD_COST,
+            _ => distance,
+        };
+        // going downhill is no cheaper, so every exit costs at least its distance along a road
+        Some((idx, distance + UPHILL_COST * climb.max(0) as f32))
+    }
+
+    /// The cost of walking the path, as the pathfinder sees it.
+    fn path_cost(&self, steps: &[usize]) -> f32 {
+        steps
+            .windows(2)
+            .map(|pair| {
+                let from = self.index_to_coords(pair[0]);
+                let to = self.index_to_coords(pair[1]);
+                // the city might have changed under the path
+                self.exit_to(from, to).map_or(1.0, |(_, cost)| cost)
+            })
+            .sum()
+    }
+
+    fn walkable_neighbors(&self, coords: GridCoords) -> impl Iterator<Item = GridCoords> + '_ {
+        coords
+            .neighbors()
+            .filter(|&neighbor| self.is_walkable(neighbor))
+    }
+
+    /// Finds a walkable route between the two buildings, running from a cell next to one to a
+    /// cell next to the other.  Tries the closest pairs of neighbors first.
+    fn road_route(&self, from: GridCoords, to: GridCoords) -> Option<Vec<GridCoords>> {
+        let mut ends: Vec<_> = self
+            .walkable_neighbors(from)
+            .flat_map(|start| self.walkable_neighbors(to).map(move |end| (start, end)))
+            .collect();
+        ends.sort_by_key(|(start, end)| start.manhattan_dist(*end));
+
+        ends.into_iter().find_map(|(start, end)| {
+            let path = a_star_search(
+                self.coords_to_index(start)?,
+                self.coords_to_index(end)?,
+                self,
+            );
+            if path.success {
+                Some(
+                    path.steps
+                        .into_iter()
+                        .map(|step| self.index_to_coords(step))
+                        .collect(),
+                )
+            } else {
+                None
+            }
+        })
+    }
 }
 
-#[derive(Bundle)]
-struct BuildingBundle {
-    building: Building,
-    pbr: PbrBundle,
+impl BaseMap for City {
+    fn get_available_exits(&self, idx: usize) -> SmallVec<[(usize, f32); 10]> {
+        let mut exits = SmallVec::new();
+        let coords = self.index_to_coords(idx);
+
+        // the orthogonal neighbours come first
+        let count = match self.connectivity {
+            Connectivity::Four => 4,
+            Connectivity::Eight => 8,
+        };
+        for neighbor in coords.neighbors8().take(count) {
+            if let Some(exit) = self.exit_to(coords, neighbor) {
+                exits.push(exit)
+            }
+        }
+
+        exits
+    }
+
+    fn get_pathing_distance(&self, idx1: usize, idx2: usize) -> f32 {
+        // admissible as long as no exit costs less than the distance it covers along a road
+        let coords1 = self.index_to_coords(idx1);
+        let coords2 = self.index_to_coords(idx2);
+        ROAD_COST
+            * match self.connectivity {
+                Connectivity::Four => coords1.manhattan_dist(coords2) as f32,
+                Connectivity::Eight => {
+                    // octile distance: diagonally as far as possible, then straight
+                    let dx = (coords2.x - coords1.x).abs() as f32;
+                    let dy = (coords2.y - coords1.y).abs() as f32;
+                    dx.max(dy) + (std::f32::consts::SQRT_2 - 1.0) * dx.min(dy)
+                }
+            }
+    }
+}
+
+/// What's on a cell, as far as walking across it goes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TileKind {
+    Empty,
+    /// cheaper to walk along than empty ground
+    Road,
+    /// can't be walked through at all
+    Building,
+}
+
+impl Algorithm2D for City {
+    fn dimensions(&self) -> BracketPoint {
+        BracketPoint::new(self.x_len, self.y_len)
+    }
 }
 
-impl BuildingBundle {
-    fn add(
-        meshes: &mut Assets<Mesh>,
-        materials: &mut Assets<StandardMaterial>,
-        building: Building,
-    ) -> Self {
-        let pbr = PbrBundle {
-            mesh: meshes.add(Mesh::from(shape::Box {
-                min_x: -0.5,
-                max_x: 0.5,
-                min_y: -0.5,
-                max_y: -0.5 + (building.height as f32),
-                min_z: -0.5,
-                max_z: 0.5,
-            })),
-            material: materials.add(Color::rgb(0.8, 0.7, 0.6).into()),
+/// set this to a number to start with a city generated from that seed instead of the usual one
+const CITY_SEED_VAR: &str = "CITYBEE_SEED";
+/// set this to a number of world units to make the cells bigger or smaller than one
+const WORLD_SCALE_VAR: &str = "CITYBEE_SCALE";
+/// tallest building `City::random` puts up
+const RANDOM_CITY_MAX_HEIGHT: Height = 5;
+
+#[allow(clippy::too_many_arguments)]
+fn setup(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut window_query: Query<&mut Window>,
+    population: Res<Population>,
+    options: Res<Options>,
+) {
+    let scale = std::env::var(WORLD_SCALE_VAR)
+        .ok()
+        .and_then(|scale| scale.parse::<f32>().ok())
+        .filter(|&scale| scale > 0.0)
+        .map_or_else(WorldScale::default, WorldScale);
+    let seed = std::env::var(CITY_SEED_VAR)
+        .ok()
+        .and_then(|seed| seed.parse().ok());
+    let city: ActiveCity = match seed {
+        Some(seed) => City::random(ACTIVE_CITY_SIZE, seed, options.generation_density as f32),
+        None => City::empty(ACTIVE_CITY_SIZE),
+    }
+    .with_origin_offset(CITY_ORIGIN_OFFSET)
+    .with_scale(scale);
+    let building_coords = city.buildings_iter();
+    let center = city.origin_offset.to_world(0.0, city.scale);
+
+    let mut window = window_query.single_mut();
+    window.cursor.visible = false;
+
+    // camera
+    commands.spawn((
+        Camera3dBundle {
+            projection: OrthographicProjection {
+                scale: 3.0 * scale.0,
+                scaling_mode: bevy::render::camera::ScalingMode::FixedVertical(2.0),
+                ..default()
+            }
+            .into(),
+            transform: Transform::from_translation(center + Vec3::new(4.0, 5.0, 5.0) * scale.0)
+                .looking_at(center, Vec3::Y),
+            ..default()
+        },
+        RenderLayers::default().with(MAIN_CAMERA_ONLY_LAYER),
+    ));
+    commands.insert_resource(CameraFocus(center));
+    spawn_minimap(&mut commands, &city);
+
+    // ground, a unit square stretched to fit under the city
+    let (ground_min, ground_max) = city.ground_bounds();
+    let ground_size = ground_max - ground_min;
+    commands
+        .spawn(PbrBundle {
+            mesh: meshes.add(shape::Plane::from_size(1.0).into()),
+            material: materials.add(GROUND_COLOR.into()),
+            transform: Transform::from_translation((ground_min + ground_max) * 0.5)
+                .with_scale(Vec3::new(ground_size.x, scale.0, ground_size.z)),
+            ..default()
+        })
+        .insert(Ground);
+
+    for (coords, height) in building_coords {
+        commands
+            .spawn(BuildingBundle::add(
+                &mut meshes,
+                &mut materials,
+                Building::new(height),
+            ))
+            .insert(coords);
+    }
+
+    // light
+    commands.spawn(PointLightBundle {
+        transform: Transform::from_xyz(0.0, 8.0 * scale.0, 0.0),
+        point_light: PointLight {
+            shadows_enabled: true,
+            ..default()
+        },
+        ..default()
+    });
+    commands.spawn(DirectionalLightBundle {
+        directional_light: DirectionalLight {
+            shadows_enabled: true,
+            ..default()
+        },
+        transform: Transform::from_rotation(sun_rotation(0.0, SUN_MAX_ELEVATION)),
+        // shadow range is fitted to the city by `configure_shadows`
+        visibility: Visibility::Hidden,
+        ..default()
+    });
+
+    // cursor
+    commands
+        .spawn(PbrBundle {
+            // the cursor is a lump of coal until I can be bothered
+            mesh: meshes.add(Mesh::from(shape::UVSphere {
+                radius: 0.1,
+                sectors: 5,
+                stacks: 5,
+            })),
+            material: materials.add(Color::BLACK.into()),
+            transform: Transform::from_scale(Vec3::splat(scale.0)),
+            ..default()
+        })
+        .insert((Cursor, RenderLayers::layer(MAIN_CAMERA_ONLY_LAYER)));
+
+    // person
+    let mut rng = rand::thread_rng();
+    let person_meshes = PersonMeshes::new(&mut meshes);
+    for _ in 0..population.target {
+        commands.spawn(PersonBundle::new(
+            &person_meshes,
+            &mut materials,
+            spawn_position(&city, &mut rng),
+            city.scale,
+            city.random_workplace(&mut rng),
+        ));
+    }
+
+    commands.insert_resource(person_meshes);
+    commands.insert_resource(city);
+}
+
+/// how many random spots to try for a new person before settling for the first empty cell
+const MAX_SPAWN_ATTEMPTS: usize = 20;
+/// how many random cells to try for somewhere to go before giving up and staying put
+const MAX_GOAL_ATTEMPTS: usize = 20;
+
+/// Somewhere random for a new person to appear, out on the street rather than inside a
+/// building.
+fn spawn_position(city: &City, rng: &mut impl Rng) -> Vec3 {
+    let (min, max) = city.world_bounds();
+    let mut sample = || {
+        // keep clear of the very edge, where they'd round off the grid
+        let half_cell = 0.5 * city.scale.0;
+        let x = rng.gen_range(min.x + half_cell..max.x - half_cell);
+        let z = rng.gen_range(min.z + half_cell..max.z - half_cell);
+        // standing on whatever terrain is under them
+        let ground = city.world_to_coords(Vec3::new(x, 0.0, z));
+        let y = city.coords_to_world(ground, PERSON_HEIGHT * 0.5).y;
+        Vec3::new(x, y, z)
+    };
+    let mut position = sample();
+    for _ in 0..MAX_SPAWN_ATTEMPTS {
+        if city.is_walkable(city.world_to_coords(position)) {
+            return position;
+        }
+        position = sample();
+    }
+    // the city's packed, so go looking for a gap
+    (0..city.cells())
+        .map(|idx| city.index_to_coords(idx))
+        .find(|&coords| city.is_walkable(coords))
+        .map_or(position, |coords| {
+            city.coords_to_world(coords, PERSON_HEIGHT * 0.5)
+        })
+}
+
+#[derive(Bundle)]
+struct PersonBundle {
+    person: Person,
+    lod: PersonLod,
+    speed_history: SpeedHistory,
+    velocity: Velocity,
+    pbr: PbrBundle,
+    layers: RenderLayers,
+}
+
+impl PersonBundle {
+    fn new(
+        person_meshes: &PersonMeshes,
+        materials: &mut Assets<StandardMaterial>,
+        translation: Vec3,
+        scale: WorldScale,
+        work: Option<GridCoords>,
+    ) -> Self {
+        let home = GridCoords::from_world(translation, scale);
+        Self {
+            // with nowhere to work they just stay home
+            person: Person::commuting(home, work.unwrap_or(home)),
+            lod: PersonLod::High,
+            speed_history: SpeedHistory::default(),
+            velocity: Velocity::ZERO,
+            pbr: PbrBundle {
+                mesh: person_meshes.get(PersonLod::High),
+                material: materials.add(PERSON_COLOR.into()),
+                transform: Transform::from_translation(translation)
+                    .with_scale(Vec3::splat(scale.0)),
+                ..default()
+            },
+            // kept off the minimap, which is for seeing the layout
+            layers: RenderLayers::layer(MAIN_CAMERA_ONLY_LAYER),
+        }
+    }
+}
+
+/// Shift+click on an empty cell to drop a new person there.
+#[allow(clippy::too_many_arguments)]
+fn drop_person(
+    buttons: Res<Input<MouseButton>>,
+    keys: Res<Input<KeyCode>>,
+    city: Res<ActiveCity>,
+    cursor_query: Query<&GridCoords, With<Cursor>>,
+    person_meshes: Res<PersonMeshes>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut population: ResMut<Population>,
+    mut commands: Commands,
+) {
+    if !buttons.just_pressed(MouseButton::Left)
+        || !keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight])
+    {
+        return;
+    }
+
+    // nowhere to drop them with the mouse off the ground
+    let Ok(&grid) = cursor_query.get_single() else {
+        return;
+    };
+    if !city.is_walkable(grid) {
+        eprintln!("can't drop anyone at {:?}", grid);
+        return;
+    }
+    commands.spawn((
+        PersonBundle::new(
+            &person_meshes,
+            &mut materials,
+            city.coords_to_world(grid, PERSON_HEIGHT * 0.5),
+            city.scale,
+            city.random_workplace(&mut rand::thread_rng()),
+        ),
+        VISITOR_TAG,
+    ));
+    population.target += 1;
+}
+
+/// How much detail to draw a person with, depending on how far they are from the camera.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+enum PersonLod {
+    High,
+    Medium,
+    Low,
+}
+
+impl PersonLod {
+    fn for_distance(distance: f32, thresholds: [f32; 2]) -> Self {
+        if distance > thresholds[1] {
+            PersonLod::Low
+        } else if distance > thresholds[0] {
+            PersonLod::Medium
+        } else {
+            PersonLod::High
+        }
+    }
+}
+
+/// One shared person mesh for each level of detail.
+#[derive(Resource)]
+struct PersonMeshes {
+    high: Handle<Mesh>,
+    medium: Handle<Mesh>,
+    low: Handle<Mesh>,
+}
+
+impl PersonMeshes {
+    fn new(meshes: &mut Assets<Mesh>) -> Self {
+        let mut cylinder = |resolution| {
+            meshes.add(Mesh::from(shape::Cylinder {
+                radius: 0.025,
+                height: PERSON_HEIGHT,
+                resolution,
+                segments: 1,
+            }))
+        };
+        Self {
+            high: cylinder(16),
+            medium: cylinder(8),
+            low: cylinder(3),
+        }
+    }
+
+    fn get(&self, lod: PersonLod) -> Handle<Mesh> {
+        match lod {
+            PersonLod::High => self.high.clone(),
+            PersonLod::Medium => self.medium.clone(),
+            PersonLod::Low => self.low.clone(),
+        }
+    }
+}
+
+fn update_person_lod(
+    options: Res<Options>,
+    person_meshes: Res<PersonMeshes>,
+    camera_query: Query<(&GlobalTransform, &Projection), Without<Minimap>>,
+    mut people: Query<(&Transform, &mut PersonLod, &mut Handle<Mesh>), With<Person>>,
+) {
+    let Ok((camera_gtx, projection)) = camera_query.get_single() else {
+        return;
+    };
+    let camera = camera_gtx.translation();
+    let zoom = match projection {
+        Projection::Orthographic(ortho) => ortho.scale,
+        Projection::Perspective(_) => 1.0,
+    };
+    for (tx, mut lod, mut mesh) in &mut people {
+        let wanted = PersonLod::for_distance(
+            tx.translation.distance(camera) * zoom,
+            options.person_lod_distances,
+        );
+        if *lod != wanted {
+            *lod = wanted;
+            *mesh = person_meshes.get(wanted);
+        }
+    }
+}
+
+fn position_objects_on_grid(
+    city: Res<ActiveCity>,
+    mut q: Query<(&mut Transform, &GridCoords), Without<Cursor>>,
+) {
+    for (mut tx, &coords) in &mut q {
+        tx.translation = city.coords_to_world(coords, 0.5); // TODO
+        tx.scale = Vec3::splat(city.scale.0);
+    }
+}
+
+/// Ask for the cells to be made this wide in the world.
+#[derive(Event)]
+struct RescaleWorld(WorldScale);
+
+/// Stretches the world about its origin when asked to, so the city, the people in it and the
+/// view of it all keep their shape at the new size.  Anything placed by its GridCoords follows
+/// along in position_objects_on_grid.
+#[allow(clippy::type_complexity)]
+fn rescale_world(
+    mut requests: EventReader<RescaleWorld>,
+    mut city: ResMut<ActiveCity>,
+    mut focus: ResMut<CameraFocus>,
+    mut objects: ParamSet<(
+        Query<&mut Transform, Or<(With<Person>, With<Ground>, With<Cursor>)>>,
+        Query<(&mut Transform, &mut Projection)>,
+    )>,
+) {
+    let Some(&RescaleWorld(scale)) = requests.iter().last() else {
+        return;
+    };
+    if city.scale == scale {
+        return;
+    }
+    let ratio = scale.0 / city.scale.0;
+    city.scale = scale;
+    focus.0 *= ratio;
+
+    for mut tx in &mut objects.p0() {
+        tx.translation *= ratio;
+        tx.scale *= ratio;
+    }
+    for (mut tx, mut projection) in &mut objects.p1() {
+        tx.translation *= ratio;
+        if let Projection::Orthographic(ortho) = projection.as_mut() {
+            ortho.scale *= ratio;
+        }
+    }
+}
+
+fn keyboard_move_camera(
+    time: Res<Time>,
+    keys: Res<Input<KeyCode>>,
+    city: Res<ActiveCity>,
+    focus: Res<CameraFocus>,
+    mut q: Query<(&mut Projection, &mut Transform), Without<Minimap>>,
+) {
+    let secs = time.delta_seconds();
+    let speed = CAMERA_MOVE_SPEED * city.scale.0 * camera_speed_multiplier(&keys);
+    let (mut proj, mut camera_tx) = q.single_mut();
+
+    let velocity_right = if keys.pressed(KeyCode::A) {
+        1.0
+    } else if keys.pressed(KeyCode::D) {
+        -1.0
+    } else {
+        0.0
+    };
+
+    let velocity = velocity_right * camera_tx.right();
+    if velocity != Vec3::ZERO {
+        camera_tx.translation += velocity * speed * secs;
+        camera_tx.look_at(focus.0, Vec3::Y);
+    }
+
+    // alt+arrows move the cursor instead
+    let alt = keys.any_pressed([KeyCode::AltLeft, KeyCode::AltRight]);
+    let orbit_direction = if alt {
+        0.0
+    } else if keys.pressed(KeyCode::Left) {
+        1.0
+    } else if keys.pressed(KeyCode::Right) {
+        -1.0
+    } else {
+        0.0
+    };
+    if orbit_direction != 0.0 {
+        let angle = orbit_direction * CAMERA_ORBIT_SPEED * camera_speed_multiplier(&keys) * secs;
+        orbit_camera(&mut camera_tx, focus.0, angle);
+    }
+
+    // trickery to deal with the Mut<> of an enum
+    let Projection::Orthographic(proj) = &mut *proj else {
+        unreachable!("projection is no longer orthographic");
+    };
+    let scale_amount = (CAMERA_ZOOM_SPEED * speed * secs).clamp(0.0, 0.1);
+    if keys.pressed(KeyCode::W) {
+        let factor = 1.0 - scale_amount;
+        proj.scale = clamp_camera_scale(proj.scale * factor, city.scale);
+    } else if keys.pressed(KeyCode::S) {
+        let factor = 1.0 + scale_amount;
+        proj.scale = clamp_camera_scale(proj.scale * factor, city.scale);
+    }
+}
+
+/// Keeps the zoom within limits that grow with the cells, so the same amount of city fits on
+/// screen at either end whatever the world scale.
+fn clamp_camera_scale(scale: f32, world_scale: WorldScale) -> f32 {
+    scale.clamp(
+        CAMERA_MIN_SCALE * world_scale.0,
+        CAMERA_MAX_SCALE * world_scale.0,
+    )
+}
+
+/// How much to multiply the projection scale by for some scrolling, less than 1 to zoom in.
+fn wheel_zoom_factor(unit: MouseScrollUnit, scrolled: f32) -> f32 {
+    let notches = match unit {
+        MouseScrollUnit::Line => scrolled,
+        MouseScrollUnit::Pixel => scrolled / PIXELS_PER_WHEEL_NOTCH,
+    };
+    (1.0 - CAMERA_WHEEL_ZOOM_STEP).powf(notches)
+}
+
+fn mouse_wheel_zoom(
+    mut wheel: EventReader<MouseWheel>,
+    city: Res<ActiveCity>,
+    mut q: Query<&mut Projection, (With<Camera>, Without<Minimap>)>,
+) {
+    let factor: f32 = wheel
+        .iter()
+        .map(|event| wheel_zoom_factor(event.unit, event.y))
+        .product();
+    if factor == 1.0 {
+        return;
+    }
+    for mut proj in &mut q {
+        if let Projection::Orthographic(proj) = &mut *proj {
+            proj.scale = clamp_camera_scale(proj.scale * factor, city.scale);
+        }
+    }
+}
+
+/// Swings the camera round the focus, keeping the same height and distance from it.
+fn orbit_camera(camera_tx: &mut Transform, focus: Vec3, angle: f32) {
+    camera_tx.rotate_around(focus, Quat::from_rotation_y(angle));
+    camera_tx.look_at(focus, Vec3::Y);
+}
+
+/// for things drawn by the main camera but left off the minimap
+const MAIN_CAMERA_ONLY_LAYER: u8 = 1;
+/// how much of the window's shorter side the minimap takes up
+const MINIMAP_FRACTION: f32 = 0.25;
+/// gap between the minimap and the edges of the window, in physical pixels
+const MINIMAP_MARGIN: u32 = 10;
+
+/// A camera looking straight down on the whole city, into a corner of the window.
+#[derive(Component)]
+struct Minimap;
+
+fn spawn_minimap(commands: &mut Commands, city: &ActiveCity) {
+    let center = city.origin_offset.to_world(0.0, city.scale);
+    commands.spawn((
+        Camera3dBundle {
+            camera: Camera {
+                // drawn over the main camera
+                order: 1,
+                is_active: false,
+                ..default()
+            },
+            camera_3d: Camera3d {
+                clear_color: ClearColorConfig::Custom(Color::DARK_GRAY),
+                ..default()
+            },
+            projection: OrthographicProjection {
+                scaling_mode: bevy::render::camera::ScalingMode::FixedVertical(
+                    (city.x_len.max(city.y_len) as f32 + 1.0) * city.scale.0,
+                ),
+                ..default()
+            }
+            .into(),
+            // north (-z) at the top, as in the main view
+            transform: Transform::from_translation(center + Vec3::Y * 20.0 * city.scale.0)
+                .looking_at(center, Vec3::NEG_Z),
+            ..default()
+        },
+        UiCameraConfig { show_ui: false },
+        Minimap,
+    ));
+}
+
+/// Where the minimap goes in a window of the given physical size: a square in the top right
+/// corner.
+fn minimap_viewport(window_width: u32, window_height: u32) -> Viewport {
+    let size = (window_width.min(window_height) as f32 * MINIMAP_FRACTION) as u32;
+    Viewport {
+        physical_position: UVec2::new(
+            window_width.saturating_sub(size + MINIMAP_MARGIN),
+            MINIMAP_MARGIN,
+        ),
+        physical_size: UVec2::splat(size.max(1)),
+        ..default()
+    }
+}
+
+fn update_minimap(
+    options: Res<Options>,
+    window_query: Query<&Window>,
+    mut minimap_query: Query<&mut Camera, With<Minimap>>,
+) {
+    let Ok(mut camera) = minimap_query.get_single_mut() else {
+        return;
+    };
+    let Ok(window) = window_query.get_single() else {
+        return;
+    };
+    if camera.is_active != options.show_minimap {
+        camera.is_active = options.show_minimap;
+    }
+    let viewport = minimap_viewport(window.physical_width(), window.physical_height());
+    let placement = |v: &Viewport| (v.physical_position, v.physical_size);
+    if camera.viewport.as_ref().map(placement) != Some(placement(&viewport)) {
+        camera.viewport = Some(viewport);
+    }
+}
+
+/// The patch of ground the main camera can see, as the center, turn about the vertical, and
+/// size of a rectangle lying on the ground.
+fn focus_region(area: Rect, camera_tx: &Transform, focus: Vec3) -> (Vec3, Quat, Vec2) {
+    let forward = camera_tx.forward();
+    // a tilted camera sees a longer stretch of ground than the height of its view
+    let depth = area.height() / (-forward.y).max(0.1);
+    let yaw = f32::atan2(-forward.x, -forward.z);
+    let rotation = Quat::from_rotation_y(yaw) * Quat::from_rotation_x(PI * 0.5);
+    (
+        Vec3::new(focus.x, 0.0, focus.z),
+        rotation,
+        Vec2::new(area.width(), depth),
+    )
+}
+
+/// Outlines on the ground what the main camera is looking at, for the minimap to show.
+fn draw_minimap_focus(
+    options: Res<Options>,
+    focus: Res<CameraFocus>,
+    camera_query: Query<(&Projection, &Transform), Without<Minimap>>,
+    mut gizmos: Gizmos,
+) {
+    if !options.show_minimap {
+        return;
+    }
+    let Ok((Projection::Orthographic(projection), camera_tx)) = camera_query.get_single() else {
+        return;
+    };
+    let (center, rotation, size) = focus_region(projection.area, camera_tx, focus.0);
+    gizmos.rect(center, rotation, size, Color::YELLOW);
+}
+
+fn look_at_camera_focus(
+    focus: Res<CameraFocus>,
+    mut q: Query<&mut Transform, (With<Camera>, Without<Minimap>)>,
+) {
+    if !focus.is_changed() {
+        return;
+    }
+    for mut camera_tx in &mut q {
+        camera_tx.look_at(focus.0, Vec3::Y);
+    }
+}
+
+/// Where the camera's heading to frame the whole city.
+#[derive(Default, Resource)]
+struct CameraFit {
+    target: Option<(Vec3, f32)>,
+}
+
+/// The orthographic scale that fits the box from `min` to `max` on screen, for a camera with the
+/// given rotation and aspect ratio.  Our projection shows `scale` units either side of the centre
+/// vertically.
+fn fit_scale(min: Vec3, max: Vec3, rotation: Quat, aspect: f32) -> f32 {
+    let center = (min + max) * 0.5;
+    let to_view = rotation.inverse();
+    let mut half_width: f32 = 0.0;
+    let mut half_height: f32 = 0.0;
+    for corner in 0..8 {
+        let point = Vec3::new(
+            if corner & 1 == 0 { min.x } else { max.x },
+            if corner & 2 == 0 { min.y } else { max.y },
+            if corner & 4 == 0 { min.z } else { max.z },
+        );
+        let view = to_view * (point - center);
+        half_width = half_width.max(view.x.abs());
+        half_height = half_height.max(view.y.abs());
+    }
+    half_height.max(half_width / aspect) * CAMERA_FIT_MARGIN
+}
+
+/// Press Home to swing the camera round to take in the whole city.
+fn fit_camera_to_city(
+    time: Res<Time>,
+    keys: Res<Input<KeyCode>>,
+    city: Res<ActiveCity>,
+    window_query: Query<&Window>,
+    mut fit: ResMut<CameraFit>,
+    mut focus: ResMut<CameraFocus>,
+    mut camera_query: Query<(&mut Projection, &mut Transform), Without<Minimap>>,
+) {
+    let Ok((mut proj, mut camera_tx)) = camera_query.get_single_mut() else {
+        return;
+    };
+    let Projection::Orthographic(proj) = &mut *proj else {
+        unreachable!("projection is no longer orthographic");
+    };
+
+    if keys.just_pressed(KeyCode::Home) {
+        let (min, mut max) = city.world_bounds();
+        max.y = city.buildings_iter().map(|(_, h)| h).max().unwrap_or(0) as f32 * city.scale.0;
+        let aspect = window_query
+            .get_single()
+            .map_or(1.0, |window| window.width() / window.height());
+        let center = (min + max) * 0.5;
+        let scale = fit_scale(min, max, camera_tx.rotation, aspect);
+        fit.target = Some((Vec3::new(center.x, 0.0, center.z), scale));
+    }
+
+    let Some((target_focus, target_scale)) = fit.target else {
+        return;
+    };
+    let t = (CAMERA_FIT_SPEED * time.delta_seconds()).min(1.0);
+    let mut next_focus = focus.0.lerp(target_focus, t);
+    let mut next_scale = proj.scale + (target_scale - proj.scale) * t;
+    if next_focus.distance(target_focus) < 0.01 && (next_scale - target_scale).abs() < 0.01 {
+        (next_focus, next_scale) = (target_focus, target_scale);
+        fit.target = None;
+    }
+    // keep looking from the same direction
+    camera_tx.translation += next_focus - focus.0;
+    focus.0 = next_focus;
+    proj.scale = next_scale;
+}
+
+/// Shift for fine adjustments, Ctrl to get around quickly.
+fn camera_speed_multiplier(keys: &Input<KeyCode>) -> f32 {
+    if keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]) {
+        CAMERA_FINE_MULTIPLIER
+    } else if keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]) {
+        CAMERA_COARSE_MULTIPLIER
+    } else {
+        1.0
+    }
+}
+
+fn keyboard_set_options(
+    keys: Res<Input<KeyCode>>,
+    mut options: ResMut<Options>,
+    mut paused: ResMut<Paused>,
+    mut city: ResMut<ActiveCity>,
+) {
+    if keys.just_pressed(KeyCode::Space) {
+        paused.0 = !paused.0;
+    }
+    if keys.just_pressed(KeyCode::P) {
+        options.draw_paths = !options.draw_paths;
+    }
+    if keys.just_pressed(KeyCode::E) {
+        options.draw_selection = !options.draw_selection;
+    }
+    if keys.just_pressed(KeyCode::B) {
+        options.pick_buildings = !options.pick_buildings;
+    }
+    if keys.just_pressed(KeyCode::H) {
+        options.show_histogram = !options.show_histogram;
+    }
+    if keys.just_pressed(KeyCode::O) {
+        options.blueprint = !options.blueprint;
+    }
+    if keys.just_pressed(KeyCode::C) {
+        options.cooperative_pathing = !options.cooperative_pathing;
+    }
+    if keys.just_pressed(KeyCode::K) {
+        options.animate_light = !options.animate_light;
+    }
+    if keys.just_pressed(KeyCode::T) {
+        options.show_height_labels = !options.show_height_labels;
+    }
+    if keys.just_pressed(KeyCode::F) {
+        options.flee_cursor = !options.flee_cursor;
+    }
+    if keys.just_pressed(KeyCode::G) {
+        options.grid_texture = !options.grid_texture;
+    }
+    if keys.just_pressed(KeyCode::U) {
+        options.show_tooltip = !options.show_tooltip;
+    }
+    if keys.just_pressed(KeyCode::J) {
+        options.sunlight = !options.sunlight;
+    }
+    if keys.just_pressed(KeyCode::Q) {
+        options.show_jams = !options.show_jams;
+    }
+    if keys.just_pressed(KeyCode::Z) {
+        options.headlamp = !options.headlamp;
+    }
+    if keys.just_pressed(KeyCode::Key1) {
+        options.pass_through_steps = !options.pass_through_steps;
+    }
+    if keys.just_pressed(KeyCode::Key2) {
+        options.show_navigation_graph = !options.show_navigation_graph;
+    }
+    if keys.just_pressed(KeyCode::Key5) {
+        options.vsync = !options.vsync;
+    }
+    if keys.just_pressed(KeyCode::Key8) {
+        options.show_districts = !options.show_districts;
+    }
+    if keys.just_pressed(KeyCode::Key9) {
+        options.manhattan_paths = !options.manhattan_paths;
+    }
+    if keys.just_pressed(KeyCode::F1) {
+        options.show_hud = !options.show_hud;
+    }
+    if keys.just_pressed(KeyCode::F2) {
+        // the city owns it, so it comes along when the city's regenerated or loaded
+        city.connectivity = city.connectivity.toggled();
+    }
+    if keys.just_pressed(KeyCode::F11) {
+        options.show_minimap = !options.show_minimap;
+    }
+    if keys.just_pressed(KeyCode::F12) {
+        options.draw_grid = !options.draw_grid;
+    }
+    if keys.just_pressed(KeyCode::F10) {
+        options.show_center_of_mass = !options.show_center_of_mass;
+    }
+    if keys.just_pressed(KeyCode::F6) {
+        options.building_style = options.building_style.next();
+    }
+    for (key, kind) in [
+        (KeyCode::Numpad1, BuildingKind::Residential),
+        (KeyCode::Numpad2, BuildingKind::Commercial),
+        (KeyCode::Numpad3, BuildingKind::Industrial),
+    ] {
+        if keys.just_pressed(key) {
+            options.building_kind = kind;
+        }
+    }
+    if keys.just_pressed(KeyCode::Numpad0) {
+        options.footprint = options.footprint.next();
+    }
+    if keys.just_pressed(KeyCode::F4) {
+        options.plan_paths = !options.plan_paths;
+    }
+    if keys.just_pressed(KeyCode::F3) {
+        options.show_walkable_tint = !options.show_walkable_tint;
+    }
+}
+
+fn present_mode(options: &Options) -> bevy::window::PresentMode {
+    if options.vsync {
+        bevy::window::PresentMode::AutoVsync
+    } else {
+        bevy::window::PresentMode::AutoNoVsync
+    }
+}
+
+fn apply_present_mode(options: Res<Options>, mut window_query: Query<&mut Window>) {
+    if !options.is_changed() {
+        return;
+    }
+    let wanted = present_mode(&options);
+    for mut window in &mut window_query {
+        if window.present_mode != wanted {
+            window.present_mode = wanted;
+        }
+    }
+}
+
+/// Sleeps off whatever's left of the frame if we're running faster than the cap.
+fn cap_frame_rate(options: Res<Options>, mut last_frame: Local<Option<Instant>>) {
+    if let (Some(cap), Some(last)) = (options.frame_rate_cap, *last_frame) {
+        let frame = Duration::from_secs_f32(1.0 / cap);
+        if let Some(left) = frame.checked_sub(last.elapsed()) {
+            std::thread::sleep(left);
+        }
+    }
+    *last_frame = Some(Instant::now());
+}
+
+fn move_light(
+    time: Res<Time>,
+    options: Res<Options>,
+    city: Res<ActiveCity>,
+    time_of_day: Res<TimeOfDay>,
+    mut point_query: Query<&mut Transform, (With<PointLight>, Without<DirectionalLight>)>,
+    mut sun_query: Query<(&mut Transform, &mut DirectionalLight)>,
+    camera_query: Query<&GlobalTransform, (With<Camera>, Without<Minimap>)>,
+) {
+    if options.headlamp {
+        let Ok(camera_gtx) = camera_query.get_single() else {
+            return;
+        };
+        let camera_tx = camera_gtx.compute_transform();
+        for mut light_tx in &mut point_query {
+            light_tx.translation = camera_tx.translation;
+        }
+        for (mut sun_tx, _) in &mut sun_query {
+            sun_tx.rotation = camera_tx.rotation;
+        }
+        return;
+    }
+
+    if !options.animate_light {
+        return;
+    }
+
+    let elapsed = time.elapsed_seconds() * LIGHT_MOVE_SPEED;
+    for mut light_tx in &mut point_query {
+        let light_pos = &mut light_tx.translation;
+        light_pos.x = 3.0 * city.scale.0 * elapsed.sin();
+        light_pos.z = 5.0 * city.scale.0 * elapsed.cos();
+    }
+    // the sun keeps to the clock instead
+    let (elevation, azimuth) = sun_angles(time_of_day.fraction());
+    for (mut sun_tx, mut sun) in &mut sun_query {
+        sun_tx.rotation = sun_rotation(azimuth, elevation);
+        sun.illuminance = sun_illuminance(elevation);
+    }
+}
+
+/// how far above the horizon the sun gets at noon, in radians
+const SUN_MAX_ELEVATION: f32 = PI * 0.3;
+/// how bright the sun is once it's well up, in lux
+const SUN_ILLUMINANCE: f32 = 100_000.0;
+/// how high the sun has to climb before it's at full brightness, in radians
+const SUN_TWILIGHT_ELEVATION: f32 = PI * 0.1;
+
+/// Which way the sun points once it's swung `azimuth` radians around the city and risen
+/// `elevation` radians above the horizon.
+fn sun_rotation(azimuth: f32, elevation: f32) -> Quat {
+    Quat::from_rotation_y(azimuth) * Quat::from_rotation_x(-elevation)
+}
+
+/// The sun's elevation and azimuth a `fraction` of the way through the day.  It rises in the east
+/// (+x) at the start, is highest in the south (+z) at noon a quarter of the way through, sets in
+/// the west at halfway, and spends the night below the horizon.
+fn sun_angles(fraction: f32) -> (f32, f32) {
+    let angle = fraction * 2.0 * PI;
+    (angle.sin() * SUN_MAX_ELEVATION, PI * 0.5 - angle)
+}
+
+/// Bright by day, fading through dawn and dusk, and nothing at all at night.
+fn sun_illuminance(elevation: f32) -> f32 {
+    SUN_ILLUMINANCE * (elevation / SUN_TWILIGHT_ELEVATION).clamp(0.0, 1.0)
+}
+
+/// shadow map texels per cell along the city's longer side
+const SHADOW_TEXELS_PER_CELL: f32 = 128.0;
+
+/// A shadow map big enough to give each cell of the city a decent number of texels, within what
+/// graphics cards will cope with.
+fn shadow_map_size(min: Vec3, max: Vec3, scale: WorldScale) -> usize {
+    let side = (max.x - min.x).max(max.z - min.z) / scale.0;
+    ((side * SHADOW_TEXELS_PER_CELL) as usize)
+        .next_power_of_two()
+        .clamp(1024, 4096)
+}
+
+/// How far from a camera `camera_distance` away from the city's centre shadows need to reach to
+/// cover all of the city, buildings included.
+fn shadow_distance(min: Vec3, max: Vec3, camera_distance: f32) -> f32 {
+    camera_distance + min.distance(max) * 0.5 * CAMERA_FIT_MARGIN
+}
+
+fn configure_shadows(
+    options: Res<Options>,
+    city: Res<ActiveCity>,
+    focus: Res<CameraFocus>,
+    camera_query: Query<&Transform, (With<Camera>, Without<Minimap>)>,
+    mut directional_map: ResMut<bevy::pbr::DirectionalLightShadowMap>,
+    mut point_map: ResMut<bevy::pbr::PointLightShadowMap>,
+    mut sun_query: Query<&mut bevy::pbr::CascadeShadowConfig>,
+) {
+    if !options.is_changed() && !city.is_changed() {
+        return;
+    }
+
+    let (min, mut max) = city.world_bounds();
+    max.y = MAX_BUILDING_HEIGHT as f32 * city.scale.0;
+    let size = options
+        .shadow_map_size
+        .unwrap_or_else(|| shadow_map_size(min, max, city.scale));
+    if directional_map.size != size {
+        directional_map.size = size;
+    }
+    if point_map.size != size {
+        point_map.size = size;
+    }
+
+    let camera_distance = camera_query
+        .get_single()
+        .map_or(0.0, |camera_tx| camera_tx.translation.distance(focus.0));
+    let maximum_distance = options
+        .shadow_distance
+        .unwrap_or_else(|| shadow_distance(min, max, camera_distance));
+    for mut cascades in &mut sun_query {
+        *cascades = bevy::pbr::CascadeShadowConfigBuilder {
+            maximum_distance,
+            ..default()
+        }
+        .build();
+    }
+}
+
+/// Turn on whichever of the point light and the sun the options call for, with the point light
+/// standing in as a street lamp at night.
+fn switch_light(
+    options: Res<Options>,
+    time_of_day: Res<TimeOfDay>,
+    mut point_query: Query<&mut Visibility, (With<PointLight>, Without<DirectionalLight>)>,
+    mut sun_query: Query<&mut Visibility, With<DirectionalLight>>,
+) {
+    let shown = |on: bool| {
+        if on {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        }
+    };
+    let point = shown(!options.sunlight || (options.street_lamp && !time_of_day.is_daytime()));
+    let sun = shown(options.sunlight);
+    for mut visibility in &mut point_query {
+        if *visibility != point {
+            *visibility = point;
+        }
+    }
+    for mut visibility in &mut sun_query {
+        if *visibility != sun {
+            *visibility = sun;
+        }
+    }
+}
+
+type Height = u8;
+type Elevation = i8;
+
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct GridCoords {
+    x: i8,
+    y: i8,
+}
+
+impl GridCoords {
+    const ORIGIN: GridCoords = GridCoords { x: 0, y: 0 };
+
+    fn new(x: i8, y: i8) -> Self {
+        Self { x, y }
+    }
+
+    fn from_world(world: Vec3, scale: WorldScale) -> Self {
+        let cells = world / scale.0;
+        GridCoords::new(cells.x.round() as i8, cells.z.round() as i8)
+    }
+
+    /// The middle of the cell in the world, `elevation` cells up.
+    fn to_world(self, elevation: f32, scale: WorldScale) -> Vec3 {
+        // grid xy is world xz (world y is elevation)
+        Vec3::new(self.x as f32, elevation, self.y as f32) * scale.0
+    }
+
+    fn manhattan_dist(&self, dest: Self) -> i32 {
+        // widened first, since coords at opposite ends of the range are further apart than an i8
+        // can count
+        let dx = i32::from(dest.x) - i32::from(self.x);
+        let dy = i32::from(dest.y) - i32::from(self.y);
+        dx.abs() + dy.abs()
+    }
+
+    fn up(&self) -> Self {
+        Self {
+            x: self.x,
+            y: self.y + 1,
+        }
+    }
+
+    fn down(&self) -> Self {
+        Self {
+            x: self.x,
+            y: self.y - 1,
+        }
+    }
+
+    fn left(&self) -> Self {
+        Self {
+            x: self.x - 1,
+            y: self.y,
+        }
+    }
+
+    fn right(&self) -> Self {
+        Self {
+            x: self.x + 1,
+            y: self.y,
+        }
+    }
+
+    /// The four orthogonal neighbours: up, down, left, right.
+    fn neighbors(&self) -> impl Iterator<Item = GridCoords> {
+        [self.up(), self.down(), self.left(), self.right()].into_iter()
+    }
+
+    /// All eight neighbours: the orthogonal ones, then up-left, up-right, down-left, down-right.
+    fn neighbors8(&self) -> impl Iterator<Item = GridCoords> {
+        let diagonals = [
+            self.up().left(),
+            self.up().right(),
+            self.down().left(),
+            self.down().right(),
+        ];
+        self.neighbors().chain(diagonals)
+    }
+}
+
+/// Follows the mouse around the city.  Carries the `GridCoords` of the cell it's over, or none
+/// while the mouse is off the ground.
+#[derive(Component)]
+struct Cursor;
+
+/// How wide a grid cell is in the world.  Anything measured in cells (storeys, people, how far
+/// a mesh sits above its coords, how fast people walk) is multiplied by this on the way into the
+/// world.  The city's own `scale` is the one in use; send a RescaleWorld to change it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct WorldScale(f32);
+
+impl Default for WorldScale {
+    fn default() -> Self {
+        Self(1.0)
+    }
+}
+
+#[derive(Component)]
+struct Ground;
+
+#[derive(Component, Clone)]
+struct Building {
+    height: Height,
+    kind: BuildingKind,
+    footprint: Footprint,
+}
+
+impl Building {
+    fn new(height: Height) -> Self {
+        Self {
+            height,
+            kind: default(),
+            footprint: default(),
+        }
+    }
+
+    fn mesh(&self, style: BuildingStyle) -> Mesh {
+        building_mesh(self.height, style, self.footprint)
+    }
+
+    /// An empty cell next to the building, standing at `coords`, that people can go in by.
+    fn entrance(&self, coords: GridCoords, city: &City) -> Option<GridCoords> {
+        let cells: Vec<_> = self.footprint.cells(coords).collect();
+        open_neighbor(city, &cells)
+    }
+}
+
+/// The first walkable cell next to any of the given cells, and not one of them.
+fn open_neighbor(city: &City, cells: &[GridCoords]) -> Option<GridCoords> {
+    cells
+        .iter()
+        .flat_map(|cell| cell.neighbors())
+        .find(|neighbor| !cells.contains(neighbor) && city.is_walkable(*neighbor))
+}
+
+/// How many cells a building covers: a square stretching from its anchor cell towards +x and +y.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Footprint {
+    #[default]
+    OneByOne,
+    TwoByTwo,
+}
+
+impl Footprint {
+    /// cells along each side
+    fn size(self) -> i8 {
+        match self {
+            Self::OneByOne => 1,
+            Self::TwoByTwo => 2,
+        }
+    }
+
+    fn next(self) -> Self {
+        match self {
+            Self::OneByOne => Self::TwoByTwo,
+            Self::TwoByTwo => Self::OneByOne,
+        }
+    }
+
+    fn cells(self, anchor: GridCoords) -> impl Iterator<Item = GridCoords> {
+        let size = self.size();
+        (0..size).flat_map(move |dy| {
+            (0..size).map(move |dx| GridCoords::new(anchor.x + dx, anchor.y + dy))
+        })
+    }
+}
+
+/// What a building is for, which decides its color.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum BuildingKind {
+    #[default]
+    Residential,
+    Commercial,
+    Industrial,
+}
+
+impl BuildingKind {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Residential => "residential",
+            Self::Commercial => "commercial",
+            Self::Industrial => "industrial",
+        }
+    }
+
+    fn color(self) -> Color {
+        match self {
+            Self::Residential => BUILDING_COLOR,
+            Self::Commercial => Color::rgb(0.45, 0.6, 0.85),
+            Self::Industrial => Color::rgb(0.55, 0.55, 0.5),
+        }
+    }
+}
+
+/// The shape of the tops of buildings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum BuildingStyle {
+    #[default]
+    Flat,
+    Beveled,
+}
+
+impl BuildingStyle {
+    fn next(self) -> Self {
+        match self {
+            Self::Flat => Self::Beveled,
+            Self::Beveled => Self::Flat,
+        }
+    }
+}
+
+/// how far in from the edges a beveled roof starts sloping
+const BUILDING_BEVEL: f32 = 0.15;
+
+/// A building's mesh, filling its footprint from the ground up to its height whatever the style.
+/// Centred on its anchor cell, so bigger footprints stick out towards +x and +z.
+fn building_mesh(height: Height, style: BuildingStyle, footprint: Footprint) -> Mesh {
+    let top = -0.5 + height as f32;
+    let half_size = footprint.size() as f32 * 0.5;
+    // from the anchor cell to the middle of the footprint
+    let middle = half_size - 0.5;
+    let bevel = match style {
+        BuildingStyle::Flat => {
+            return Mesh::from(shape::Box {
+                min_x: -0.5,
+                max_x: middle + half_size,
+                min_y: -0.5,
+                max_y: top,
+                min_z: -0.5,
+                max_z: middle + half_size,
+            })
+        }
+        BuildingStyle::Beveled => BUILDING_BEVEL.min(height as f32 * 0.5),
+    };
+
+    let ring = |half_width: f32, y: f32| {
+        [
+            Vec3::new(middle - half_width, y, middle - half_width),
+            Vec3::new(middle + half_width, y, middle - half_width),
+            Vec3::new(middle + half_width, y, middle + half_width),
+            Vec3::new(middle - half_width, y, middle + half_width),
+        ]
+    };
+    let base = ring(half_size, -0.5);
+    let shoulder = ring(half_size, top - bevel);
+    let roof = ring(half_size - bevel, top);
+
+    let mut quads = vec![base, roof];
+    for i in 0..4 {
+        let j = (i + 1) % 4;
+        quads.push([base[i], base[j], shoulder[j], shoulder[i]]);
+        quads.push([shoulder[i], shoulder[j], roof[j], roof[i]]);
+    }
+
+    let center = Vec3::new(middle, (top - 0.5) * 0.5, middle);
+    let (mut positions, mut normals, mut uvs, mut indices) = (vec![], vec![], vec![], vec![]);
+    for mut quad in quads {
+        let mut normal = (quad[1] - quad[0]).cross(quad[2] - quad[0]).normalize();
+        // faces all point outwards, since the shape is convex
+        if normal.dot(quad[0] + quad[2] - center * 2.0) < 0.0 {
+            quad.reverse();
+            normal = -normal;
+        }
+        let first = positions.len() as u32;
+        positions.extend(quad.map(|corner| corner.to_array()));
+        normals.extend([normal.to_array(); 4]);
+        uvs.extend([[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]]);
+        indices.extend([first, first + 1, first + 2, first, first + 2, first + 3]);
+    }
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh
+}
+
+/// Reshapes buildings whose height has changed, or all of them when the style does.  New ones
+/// count as changed, since they're always made flat.
+fn update_building_meshes(
+    options: Res<Options>,
+    building_query: Query<(Ref<Building>, &Handle<Mesh>)>,
+    mut meshes: ResMut<Assets<Mesh>>,
+) {
+    for (building, mesh) in &building_query {
+        if !options.is_changed() && !building.is_changed() {
+            continue;
+        }
+        if let Some(mesh) = meshes.get_mut(mesh) {
+            *mesh = building.mesh(options.building_style);
+        }
+    }
+}
+
+#[derive(Bundle)]
+struct BuildingBundle {
+    building: Building,
+    pbr: PbrBundle,
+}
+
+impl BuildingBundle {
+    fn add(
+        meshes: &mut Assets<Mesh>,
+        materials: &mut Assets<StandardMaterial>,
+        building: Building,
+    ) -> Self {
+        let pbr = PbrBundle {
+            mesh: meshes.add(building.mesh(default())),
+            material: materials.add(building.kind.color().into()),
+            ..default()
+        };
+        Self { building, pbr }
+    }
+}
+
+/// Which building entity stands on each cell, so systems don't have to search for it.
+#[derive(Resource, Default)]
+struct BuildingIndex(HashMap<GridCoords, Entity>);
+
+impl BuildingIndex {
+    fn get(&self, coords: GridCoords) -> Option<Entity> {
+        self.0.get(&coords).copied()
+    }
+}
+
+fn rebuild_building_index(
+    building_query: Query<(Entity, &GridCoords, &Building)>,
+    mut index: ResMut<BuildingIndex>,
+) {
+    index.0 = building_query
+        .iter()
+        .flat_map(|(entity, &coords, building)| {
+            building
+                .footprint
+                .cells(coords)
+                .map(move |cell| (cell, entity))
+        })
+        .collect();
+}
+
+/// Keeps the index up to date with buildings spawned or despawned since the last frame.
+fn track_buildings(
+    added: Query<(Entity, &GridCoords, &Building), Added<Building>>,
+    mut removed: RemovedComponents<Building>,
+    mut index: ResMut<BuildingIndex>,
+) {
+    // removals first, so a cell that was cleared and rebuilt ends up with the new building
+    for entity in removed.iter() {
+        index.0.retain(|_, &mut indexed| indexed != entity);
+    }
+    for (entity, &coords, building) in &added {
+        for cell in building.footprint.cells(coords) {
+            index.0.insert(cell, entity);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn move_cursor(
+    mut cursor_query: Query<(Entity, &mut Transform, Option<&mut GridCoords>), With<Cursor>>,
+    camera_query: Query<(&Camera, &GlobalTransform), Without<Minimap>>,
+    ground_query: Query<&GlobalTransform, With<Ground>>,
+    window_query: Query<&Window>,
+    primary_query: Query<Entity, With<PrimaryWindow>>,
+    building_query: Query<(&Building, &GridCoords), Without<Cursor>>,
+    index: Res<BuildingIndex>,
+    city: Res<ActiveCity>,
+    options: Res<Options>,
+    mut keyboard_cursor: ResMut<KeyboardCursor>,
+    mut cursor_moved: EventReader<CursorMoved>,
+    mut commands: Commands,
+    mut gizmos: Gizmos,
+) {
+    let (cursor, mut cursor_tx, cursor_coords) = cursor_query.single_mut();
+    let (Ok((camera, camera_gtx)), Ok(ground_gtx)) =
+        (camera_query.get_single(), ground_query.get_single())
+    else {
+        return;
+    };
+    let window = camera_window(camera, &window_query, primary_query.get_single().ok());
+
+    // the keyboard has the cursor until the mouse moves again
+    if cursor_moved.iter().last().is_some() {
+        keyboard_cursor.0 = false;
+    }
+    let grid = match cursor_coords {
+        Some(ref coords) if keyboard_cursor.0 => {
+            // stay on the roof as the building underneath grows or shrinks
+            cursor_tx.translation = snapped_cursor_position(&city, **coords);
+            **coords
+        }
+        cursor_coords => {
+            let Some((grid, point)) =
+                cursor_to_grid(window, camera, camera_gtx, ground_gtx, &city, &options)
+            else {
+                if cursor_coords.is_some() {
+                    commands.entity(cursor).remove::<GridCoords>();
+                }
+                return;
+            };
+
+            cursor_tx.translation = if options.snap_cursor {
+                snapped_cursor_position(&city, grid)
+            } else {
+                point
+            };
+            match cursor_coords {
+                Some(mut coords) => {
+                    if *coords != grid {
+                        *coords = grid;
+                    }
+                }
+                None => {
+                    commands.entity(cursor).insert(grid);
+                }
+            }
+            grid
+        }
+    };
+
+    if options.draw_selection {
+        let building = index
+            .get(grid)
+            .and_then(|entity| building_query.get(entity).ok());
+        let height = building.map_or(0, |(b, _)| b.height) as f32;
+        let selection_center = city.coords_to_world(grid, height);
+
+        let rotation = Quat::from_rotation_x(PI * 0.5);
+        let cell = Vec2::splat(city.scale.0);
+        gizmos.rect(selection_center, rotation, cell, Color::ANTIQUE_WHITE);
+        // and the way in
+        if let Some(entrance) =
+            building.and_then(|(building, &anchor)| building.entrance(anchor, city.as_ref()))
+        {
+            gizmos.circle(
+                city.coords_to_world(entrance, 0.01),
+                Vec3::Y,
+                0.2 * city.scale.0,
+                Color::ANTIQUE_WHITE,
+            );
+        }
+    }
+}
+
+/// Whether the arrow keys are steering the cursor rather than the mouse.
+#[derive(Resource, Default)]
+struct KeyboardCursor(bool);
+
+/// The step across the grid that goes most nearly the given way across the screen (+x right,
+/// +y up) for a camera facing `forward` with `up` as its up.
+fn screen_step(forward: Vec3, up: Vec3, screen: Vec2) -> (i8, i8) {
+    // up the screen is the way the camera faces, flattened onto the ground, unless it's looking
+    // straight down, when it's the way the top of the camera points
+    let flat = |v: Vec3| Vec2::new(v.x, v.z);
+    let facing = if flat(forward).length() > 1e-3 {
+        flat(forward)
+    } else {
+        flat(up)
+    };
+    let ahead = facing.normalize_or_zero();
+    let right = Vec2::new(-ahead.y, ahead.x);
+    let way = right * screen.x + ahead * screen.y;
+    if way.x.abs() >= way.y.abs() {
+        (way.x.signum() as i8, 0)
+    } else {
+        (0, way.y.signum() as i8)
+    }
+}
+
+/// One cell over from `from`, or `from` itself if that would be off the edge of the city.
+fn step_cursor(city: &City, from: GridCoords, dx: i8, dy: i8) -> GridCoords {
+    let to = GridCoords::new(from.x + dx, from.y + dy);
+    if city.coords_to_index(to).is_some() {
+        to
+    } else {
+        from
+    }
+}
+
+/// Hold Alt and press the arrow keys to move the cursor a cell at a time, so the city can be
+/// edited without a mouse: Enter builds and Backspace knocks down, like clicking.
+fn keyboard_move_cursor(
+    keys: Res<Input<KeyCode>>,
+    city: Res<ActiveCity>,
+    mut keyboard_cursor: ResMut<KeyboardCursor>,
+    mut cursor_query: Query<(Entity, &mut Transform, Option<&mut GridCoords>), With<Cursor>>,
+    camera_query: Query<&GlobalTransform, (With<Camera>, Without<Minimap>)>,
+    mut commands: Commands,
+) {
+    if !keys.any_pressed([KeyCode::AltLeft, KeyCode::AltRight]) {
+        return;
+    }
+    let screen = if keys.just_pressed(KeyCode::Up) {
+        Vec2::Y
+    } else if keys.just_pressed(KeyCode::Down) {
+        Vec2::NEG_Y
+    } else if keys.just_pressed(KeyCode::Left) {
+        Vec2::NEG_X
+    } else if keys.just_pressed(KeyCode::Right) {
+        Vec2::X
+    } else {
+        return;
+    };
+    // the arrows follow the view, however far it's been orbited
+    let camera_gtx = camera_query.get_single().copied().unwrap_or_default();
+    let (dx, dy) = screen_step(camera_gtx.forward(), camera_gtx.up(), screen);
+    let Ok((cursor, mut cursor_tx, cursor_coords)) = cursor_query.get_single_mut() else {
+        return;
+    };
+
+    keyboard_cursor.0 = true;
+    let grid = match cursor_coords {
+        Some(mut coords) => {
+            *coords = step_cursor(city.as_ref(), *coords, dx, dy);
+            *coords
+        }
+        // start from the middle if the mouse wasn't over the city
+        None => {
+            commands.entity(cursor).insert(city.origin_offset);
+            city.origin_offset
+        }
+    };
+    cursor_tx.translation = snapped_cursor_position(&city, grid);
+}
+
+/// The middle of the cell, on the roof of the building there if there is one.
+fn snapped_cursor_position(city: &ActiveCity, grid: GridCoords) -> Vec3 {
+    let height = city.height_at_coords(grid).unwrap_or(0);
+    city.coords_to_world(grid, height as f32)
+}
+
+/// The window the camera draws into, if it's still open.  None for a camera drawing to an image,
+/// or to the primary window once that's closed.
+fn camera_window<'a>(
+    camera: &Camera,
+    windows: &'a Query<&Window>,
+    primary: Option<Entity>,
+) -> Option<&'a Window> {
+    let RenderTarget::Window(window_ref) = &camera.target else {
+        return None;
+    };
+    windows.get(window_ref.normalize(primary)?.entity()).ok()
+}
+
+/// The cell under the mouse, and the point on the ground or the building it's over.  Only the
+/// camera's own window counts: with the cursor off in another one, or no window at all, there's
+/// nothing under it.
+fn cursor_to_grid(
+    window: Option<&Window>,
+    camera: &Camera,
+    camera_gtx: &GlobalTransform,
+    ground_gtx: &GlobalTransform,
+    city: &ActiveCity,
+    options: &Options,
+) -> Option<(GridCoords, Vec3)> {
+    let cursor_pos = window?.cursor_position()?;
+
+    let ray = camera.viewport_to_world(camera_gtx, cursor_pos)?;
+    ray_to_grid(ray, ground_gtx, city, options)
+}
+
+/// Where a ray from the camera first meets the city: the building it hits, or else the ground.
+fn ray_to_grid(
+    ray: Ray,
+    ground_gtx: &GlobalTransform,
+    city: &ActiveCity,
+    options: &Options,
+) -> Option<(GridCoords, Vec3)> {
+    if options.pick_buildings {
+        if let Some(hit) = city.pick_building(ray, options.pick_margin) {
+            return Some(hit);
+        }
+    }
+
+    let distance = ray.intersect_plane(ground_gtx.translation(), ground_gtx.up())?;
+    let point = ray.get_point(distance);
+    let grid = city.world_to_coords(point);
+    Some((grid, point))
+}
+
+/// Distance along the ray to where it enters the axis-aligned box, if it hits it at all.
+fn ray_box_distance(ray: Ray, min: Vec3, max: Vec3) -> Option<f32> {
+    // slab method: intersect the ray with the pair of planes bounding each axis
+    let inv_direction = ray.direction.recip();
+    let t1 = (min - ray.origin) * inv_direction;
+    let t2 = (max - ray.origin) * inv_direction;
+    let near = t1.min(t2).max_element();
+    let far = t1.max(t2).min_element();
+    if near <= far && far >= 0.0 {
+        Some(near.max(0.0))
+    } else {
+        None
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn add_buildings(
+    buttons: Res<Input<MouseButton>>,
+    keys: Res<Input<KeyCode>>,
+    cursor_query: Query<&GridCoords, With<Cursor>>,
+    mut building_query: Query<&mut Building>,
+    index: Res<BuildingIndex>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut commands: Commands,
+    mut city: ResMut<ActiveCity>,
+    options: Res<Options>,
+    mut flash: ResMut<FlashMessage>,
+) {
+    // shift-clicking drops people and ctrl-clicking paints roads instead
+    if !(buttons.just_pressed(MouseButton::Left) || keys.just_pressed(KeyCode::Return))
+        || keys.any_pressed([
+            KeyCode::ShiftLeft,
+            KeyCode::ShiftRight,
+            KeyCode::ControlLeft,
+            KeyCode::ControlRight,
+        ])
+    {
+        return;
+    }
+
+    let Ok(&grid) = cursor_query.get_single() else {
+        return;
+    };
+
+    let building = index
+        .get(grid)
+        .and_then(|entity| building_query.get_mut(entity).ok());
+
+    // bigger buildings go up all at once, and only grow after that
+    let footprint = options.footprint;
+    let placing_block = building.is_none() && footprint != Footprint::OneByOne;
+    let height = if placing_block {
+        city.place_building(grid, footprint, options.max_buildings)
+            .then_some(1)
+    } else {
+        city.add_storey(grid, options.max_buildings)
+    };
+    let Some(height) = height else {
+        if placing_block {
+            let size = footprint.size();
+            flash.show(format!("no room for a {}x{} building there", size, size));
+        } else if building.is_none() && options.max_buildings.is_some() {
+            flash.show(format!(
+                "no room for more than {} buildings",
+                city.building_count()
+            ));
+        }
+        return;
+    };
+
+    if let Some(mut building) = building {
+        building.height = height;
+    } else {
+        commands
+            .spawn(BuildingBundle::add(
+                &mut meshes,
+                &mut materials,
+                Building {
+                    height: 1,
+                    kind: options.building_kind,
+                    footprint,
+                },
+            ))
+            .insert(grid);
+    }
+}
+
+/// The cells the cursor has passed over since the right button went down, so dragging back
+/// over a cell doesn't knock it down twice.
+#[derive(Default, Resource)]
+struct BulldozeDrag {
+    visited: HashSet<GridCoords>,
+}
+
+impl BulldozeDrag {
+    /// Notes the cell, saying whether it's the first time this drag got there.
+    fn visit(&mut self, coords: GridCoords) -> bool {
+        self.visited.insert(coords)
+    }
+
+    fn end(&mut self) {
+        self.visited.clear();
+    }
+}
+
+/// Right-click or press Backspace to knock down the building under the cursor.  Hold the right
+/// button and drag to knock down everything the cursor passes over.
+#[allow(clippy::too_many_arguments)]
+fn remove_buildings(
+    buttons: Res<Input<MouseButton>>,
+    keys: Res<Input<KeyCode>>,
+    cursor_query: Query<&GridCoords, With<Cursor>>,
+    index: Res<BuildingIndex>,
+    mut drag: ResMut<BulldozeDrag>,
+    mut commands: Commands,
+    mut city: ResMut<ActiveCity>,
+) {
+    if buttons.just_pressed(MouseButton::Right) || buttons.just_released(MouseButton::Right) {
+        drag.end();
+    }
+    let shift = keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    // shift+backspace clears the whole city instead
+    let backspace = keys.just_pressed(KeyCode::Back) && !shift;
+    let dragging = buttons.pressed(MouseButton::Right);
+    if !dragging && !backspace {
+        return;
+    }
+
+    let Ok(&grid) = cursor_query.get_single() else {
+        return;
+    };
+    if dragging && !drag.visit(grid) && !backspace {
+        return;
+    }
+    // only touch the city if there's something to remove, so nobody replans for nothing
+    if city.height_at_coords(grid).is_none() {
+        return;
+    }
+
+    city.remove_building(grid);
+    if let Some(building) = index.get(grid) {
+        commands.entity(building).despawn();
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn regenerate_city(
+    keys: Res<Input<KeyCode>>,
+    options: Res<Options>,
+    mut rng: ResMut<SimRng>,
+    mut city: ResMut<ActiveCity>,
+    building_query: Query<Entity, With<Building>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut commands: Commands,
+) {
+    if !keys.just_pressed(KeyCode::N) {
+        return;
+    }
+
+    for building in &building_query {
+        commands.entity(building).despawn();
+    }
+
+    *city = City::generate(
+        (city.x_len, city.y_len),
+        &mut rng.0,
+        options.generation_density,
+        options.generation_max_height,
+    )
+    .with_origin_offset(city.origin_offset)
+    .with_scale(city.scale)
+    .with_connectivity(city.connectivity);
+    spawn_buildings(&city, &mut meshes, &mut materials, &mut commands);
+}
+
+/// Press Shift+Backspace to knock down the whole city and start afresh.
+fn clear_city(
+    keys: Res<Input<KeyCode>>,
+    mut city: ResMut<ActiveCity>,
+    building_query: Query<Entity, With<Building>>,
+    mut index: ResMut<BuildingIndex>,
+    mut commands: Commands,
+) {
+    if !keys.just_pressed(KeyCode::Back)
+        || !keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight])
+    {
+        return;
+    }
+
+    for building in &building_query {
+        commands.entity(building).despawn();
+    }
+    index.0.clear();
+    // people replan when they see the city change
+    city.clear();
+}
+
+fn spawn_buildings(
+    city: &City,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    commands: &mut Commands,
+) {
+    for (coords, height) in city.distinct_buildings() {
+        commands
+            .spawn(BuildingBundle::add(
+                meshes,
+                materials,
+                Building {
+                    footprint: city.footprint_at(coords),
+                    ..Building::new(height)
+                },
+            ))
+            .insert(coords);
+    }
+}
+
+/// A layer of the city turned a quarter turn clockwise, seen from above.  Only makes sense for
+/// square cities.
+fn rotate_layer_cw<T: Copy>(city: &City, layer: &[T]) -> Vec<T> {
+    let mut rotated = layer.to_vec();
+    for (idx, &value) in layer.iter().enumerate() {
+        let (x, y) = (idx % city.x_len, idx / city.x_len);
+        // world z points toward the viewer, so clockwise takes +x to +z
+        let (new_x, new_y) = (city.y_len - 1 - y, x);
+        rotated[new_y * city.x_len + new_x] = value;
+    }
+    rotated
+}
+
+/// A layer of the city flipped end to end along the x axis, or the y axis if not `along_x`.
+fn mirror_layer<T: Copy>(city: &City, layer: &[T], along_x: bool) -> Vec<T> {
+    let mut mirrored = layer.to_vec();
+    for (idx, &value) in layer.iter().enumerate() {
+        let (mut x, mut y) = (idx % city.x_len, idx / city.x_len);
+        if along_x {
+            x = city.x_len - 1 - x;
+        } else {
+            y = city.y_len - 1 - y;
+        }
+        mirrored[y * city.x_len + x] = value;
+    }
+    mirrored
+}
+
+/// The blocks layer moved along with the rest of the city, `moved_from` giving the cell each
+/// cell's contents came from.  Every block is anchored at its new top-left corner, since that's
+/// the cell its building grows from.
+fn move_blocks(city: &City, moved_from: &[usize]) -> Vec<Option<usize>> {
+    let mut anchors: HashMap<usize, usize> = HashMap::new();
+    for (idx, &from) in moved_from.iter().enumerate() {
+        if let Some(anchor) = city.blocks[from] {
+            // indices run along each row in turn, so the top-left cell has the lowest
+            let corner = anchors.entry(anchor).or_insert(idx);
+            *corner = (*corner).min(idx);
+        }
+    }
+    moved_from
+        .iter()
+        .map(|&from| city.blocks[from].map(|anchor| anchors[&anchor]))
+        .collect()
+}
+
+fn rotate_cw(city: &City) -> Vec<Height> {
+    rotate_layer_cw(city, &city.heights)
+}
+
+fn mirror_x(city: &City) -> Vec<Height> {
+    mirror_layer(city, &city.heights, true)
+}
+
+/// Press 6 to turn the whole city a quarter turn clockwise, 0 to mirror it left to right, or
+/// Shift+0 to mirror it front to back.
+#[allow(clippy::too_many_arguments)]
+fn transform_city(
+    keys: Res<Input<KeyCode>>,
+    mut city: ResMut<ActiveCity>,
+    building_query: Query<(Entity, &GridCoords, &Building, Option<&District>)>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut commands: Commands,
+) {
+    let shift = keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    let cells: Vec<usize> = (0..city.cells()).collect();
+    let moved_from = if keys.just_pressed(KeyCode::Key6) {
+        if city.x_len != city.y_len {
+            eprintln!("can only rotate square cities");
+            return;
+        }
+        city.heights = rotate_cw(&city);
+        city.elevations = rotate_layer_cw(&city, &city.elevations);
+        city.roads = rotate_layer_cw(&city, &city.roads);
+        rotate_layer_cw(&city, &cells)
+    } else if keys.just_pressed(KeyCode::Key0) {
+        city.heights = if shift {
+            mirror_layer(&city, &city.heights, false)
+        } else {
+            mirror_x(&city)
+        };
+        city.elevations = mirror_layer(&city, &city.elevations, !shift);
+        city.roads = mirror_layer(&city, &city.roads, !shift);
+        mirror_layer(&city, &cells, !shift)
+    } else {
+        return;
+    };
+    city.blocks = move_blocks(&city, &moved_from);
+
+    // everything comes back where its anchor cell went, as it was
+    let mut moved_to = cells;
+    for (idx, &from) in moved_from.iter().enumerate() {
+        moved_to[from] = idx;
+    }
+    for (entity, &coords, building, district) in &building_query {
+        commands.entity(entity).despawn();
+        let Some(from) = city.coords_to_index(coords) else {
+            continue;
+        };
+        let to = moved_to[from];
+        let anchor = city.index_to_coords(city.blocks[to].unwrap_or(to));
+        let mut moved = commands.spawn(BuildingBundle::add(
+            &mut meshes,
+            &mut materials,
+            building.clone(),
+        ));
+        moved.insert(anchor);
+        if let Some(&district) = district {
+            moved.insert(district);
+        }
+    }
+}
+
+/// Buildings picked out for editing together.
+#[derive(Default, Resource)]
+struct BuildingSelection {
+    cells: HashSet<GridCoords>,
+}
+
+/// Press 3 to add the building under the cursor to the selection, or take it back out.
+fn select_buildings(
+    keys: Res<Input<KeyCode>>,
+    city: Res<ActiveCity>,
+    cursor_query: Query<&GridCoords, With<Cursor>>,
+    mut selection: ResMut<BuildingSelection>,
+    mut gizmos: Gizmos,
+) {
+    if keys.just_pressed(KeyCode::Key3) {
+        if let Ok(&coords) = cursor_query.get_single() {
+            if city.height_at_coords(coords).is_some() && !selection.cells.remove(&coords) {
+                selection.cells.insert(coords);
+            }
+        }
+    }
+    if city.is_changed() {
+        // forget anything that's been knocked down
+        selection
+            .cells
+            .retain(|&coords| city.height_at_coords(coords).is_some());
+    }
+
+    let rotation = Quat::from_rotation_x(PI * 0.5);
+    for &coords in &selection.cells {
+        let height = city.height_at_coords(coords).unwrap_or(0);
+        gizmos.rect(
+            city.coords_to_world(coords, height as f32),
+            rotation,
+            Vec2::splat(0.9 * city.scale.0),
+            Color::GOLD,
+        );
+    }
+}
+
+/// Press 4 to level the selected buildings off at their average height.
+fn equalize_selected_buildings(
+    keys: Res<Input<KeyCode>>,
+    selection: Res<BuildingSelection>,
+    mut city: ResMut<ActiveCity>,
+    mut building_query: Query<(&GridCoords, &mut Building)>,
+) {
+    if !keys.just_pressed(KeyCode::Key4) {
+        return;
+    }
+    if city
+        .equalize_heights(selection.cells.iter().copied(), None)
+        .is_none()
+    {
+        return;
+    }
+
+    // a block's anchor needn't be one of the selected cells, so go by what the city says
+    for (&coords, mut building) in &mut building_query {
+        if let Some(height) = city.height_at_coords(coords) {
+            if building.height != height {
+                building.height = height;
+            }
+        }
+    }
+}
+
+/// A named group of buildings, all tinted the same.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+struct District(u8);
+
+impl District {
+    fn color(self) -> Color {
+        DISTRICT_COLORS[self.0 as usize % DISTRICT_COLORS.len()]
+    }
+}
+
+/// Press 7 to put the selected buildings into a district of their own.
+fn assign_district(
+    keys: Res<Input<KeyCode>>,
+    selection: Res<BuildingSelection>,
+    building_query: Query<(Entity, &GridCoords, Option<&District>), With<Building>>,
+    mut commands: Commands,
+) {
+    if !keys.just_pressed(KeyCode::Key7) || selection.cells.is_empty() {
+        return;
+    }
+
+    let district = building_query
+        .iter()
+        .filter_map(|(.., district)| district.map(|d| d.0))
+        .max()
+        .map_or(District(0), |id| {
+            District((id + 1) % DISTRICT_COLORS.len() as u8)
+        });
+    for (building, coords, _) in &building_query {
+        if selection.cells.contains(coords) {
+            commands.entity(building).insert(district);
+        }
+    }
+    eprintln!(
+        "put {} buildings in district {}",
+        selection.cells.len(),
+        district.0
+    );
+}
+
+fn tint_districts(
+    mut districts_removed: RemovedComponents<District>,
+    districted: Query<(&District, &Handle<StandardMaterial>), Changed<District>>,
+    undistricted: Query<(&Building, &Handle<StandardMaterial>), Without<District>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for (district, material) in &districted {
+        if let Some(material) = materials.get_mut(material) {
+            material.base_color = district.color();
+        }
+    }
+    for building in districts_removed.iter() {
+        let Ok((building, material)) = undistricted.get(building) else {
+            continue;
+        };
+        if let Some(material) = materials.get_mut(material) {
+            material.base_color = building.kind.color();
+        }
+    }
+}
+
+fn draw_districts(
+    options: Res<Options>,
+    city: Res<ActiveCity>,
+    building_query: Query<(&GridCoords, &District)>,
+    mut gizmos: Gizmos,
+) {
+    if !options.show_districts {
+        return;
+    }
+
+    let districts: HashMap<GridCoords, District> = building_query
+        .iter()
+        .map(|(&coords, &district)| (coords, district))
+        .collect();
+    for (&coords, &district) in &districts {
+        let center = city.coords_to_world(coords, 0.02);
+        for neighbor in coords.neighbors() {
+            if districts.get(&neighbor) == Some(&district) {
+                continue;
+            }
+            // the edge of the cell facing that neighbour
+            let toward = (city.coords_to_world(neighbor, 0.02) - center) * 0.5;
+            let along = Vec3::new(toward.z, 0.0, -toward.x);
+            gizmos.line(
+                center + toward - along,
+                center + toward + along,
+                district.color(),
+            );
+        }
+    }
+}
+
+#[derive(Default, Resource)]
+struct RoadSelection {
+    first: Option<GridCoords>,
+}
+
+#[derive(Component)]
+struct Road;
+
+#[derive(Bundle)]
+struct RoadBundle {
+    road: Road,
+    pbr: PbrBundle,
+}
+
+impl RoadBundle {
+    fn add(meshes: &mut Assets<Mesh>, materials: &mut Assets<StandardMaterial>) -> Self {
+        let pbr = PbrBundle {
+            // a thin slab sitting just above the ground
+            mesh: meshes.add(Mesh::from(shape::Box {
+                min_x: -0.5,
+                max_x: 0.5,
+                min_y: -0.5,
+                max_y: -0.49,
+                min_z: -0.5,
+                max_z: 0.5,
+            })),
+            material: materials.add(Color::rgb(0.35, 0.35, 0.35).into()),
+            ..default()
+        };
+        Self { road: Road, pbr }
+    }
+}
+
+/// Press R over one building then another to lay a road between them.
+fn connect_buildings_with_roads(
+    keys: Res<Input<KeyCode>>,
+    cursor_query: Query<&GridCoords, With<Cursor>>,
+    mut city: ResMut<ActiveCity>,
+    mut selection: ResMut<RoadSelection>,
+    mut gizmos: Gizmos,
+) {
+    if let Some(first) = selection.first {
+        let height = city.height_at_coords(first).unwrap_or(0) as f32;
+        let rotation = Quat::from_rotation_x(PI * 0.5);
+        gizmos.rect(
+            city.coords_to_world(first, height),
+            rotation,
+            Vec2::splat(city.scale.0),
+            Color::ORANGE,
+        );
+    }
+
+    if !keys.just_pressed(KeyCode::R) {
+        return;
+    }
+
+    let Ok(&grid) = cursor_query.get_single() else {
+        return;
+    };
+    if city.height_at_coords(grid).is_none() {
+        eprintln!("no building at {:?} to connect", grid);
+        return;
+    }
+
+    let Some(first) = selection.first.take() else {
+        selection.first = Some(grid);
+        return;
+    };
+    if first == grid {
+        return;
+    }
+
+    let Some(route) = city.road_route(first, grid) else {
+        eprintln!("no route from {:?} to {:?}, not laying road", first, grid);
+        return;
+    };
+    for coords in route {
+        city.set_road_at_coords(coords, true);
+    }
+}
+
+/// Ctrl+click an empty cell to lay a road on it, or take one up.
+fn paint_roads(
+    buttons: Res<Input<MouseButton>>,
+    keys: Res<Input<KeyCode>>,
+    cursor_query: Query<&GridCoords, With<Cursor>>,
+    mut city: ResMut<ActiveCity>,
+) {
+    if !buttons.just_pressed(MouseButton::Left)
+        || !keys.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight])
+    {
+        return;
+    }
+    let Ok(&coords) = cursor_query.get_single() else {
+        return;
+    };
+    match city.tile_kind(coords) {
+        Some(TileKind::Empty) => city.set_road_at_coords(coords, true),
+        Some(TileKind::Road) => city.set_road_at_coords(coords, false),
+        Some(TileKind::Building) | None => {}
+    }
+}
+
+fn spawn_road_tiles(
+    city: Res<ActiveCity>,
+    roads: Query<Entity, With<Road>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut commands: Commands,
+) {
+    if !city.is_changed() {
+        return;
+    }
+
+    for road in &roads {
+        commands.entity(road).despawn();
+    }
+    for coords in city.roads_iter() {
+        commands
+            .spawn(RoadBundle::add(&mut meshes, &mut materials))
+            .insert(coords);
+    }
+}
+
+/// A column of raised ground under a cell.
+#[derive(Component)]
+struct Terrain;
+
+fn spawn_terrain(
+    city: Res<ActiveCity>,
+    terrain: Query<Entity, With<Terrain>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut commands: Commands,
+) {
+    if !city.is_changed() {
+        return;
+    }
+
+    for column in &terrain {
+        commands.entity(column).despawn();
+    }
+    let material = materials.add(GROUND_COLOR.into());
+    for idx in 0..city.x_len * city.y_len {
+        let coords = city.index_to_coords(idx);
+        // lowered cells just sink out of sight under the ground
+        let elevation = city.elevations[idx];
+        if elevation <= 0 {
+            continue;
+        }
+        // hangs down from the top of the terrain, where things on the cell are put
+        let mesh = meshes.add(Mesh::from(shape::Box {
+            min_x: -0.5,
+            max_x: 0.5,
+            min_y: -0.5 - elevation as f32 * ELEVATION_STEP,
+            max_y: -0.5,
+            min_z: -0.5,
+            max_z: 0.5,
+        }));
+        commands
+            .spawn(PbrBundle {
+                mesh,
+                material: material.clone(),
+                ..default()
+            })
+            .insert((Terrain, coords));
+    }
+}
+
+/// Press Page Up or Page Down to raise or lower the ground under the cursor.
+fn edit_terrain(
+    keys: Res<Input<KeyCode>>,
+    cursor_query: Query<&GridCoords, With<Cursor>>,
+    mut city: ResMut<ActiveCity>,
+) {
+    let change = if keys.just_pressed(KeyCode::PageUp) {
+        1
+    } else if keys.just_pressed(KeyCode::PageDown) {
+        -1
+    } else {
+        return;
+    };
+    let Ok(&coords) = cursor_query.get_single() else {
+        return;
+    };
+    let Some(elevation) = city.elevation_at_coords(coords) else {
+        return;
+    };
+    let raised = (elevation + change).clamp(-MAX_ELEVATION, MAX_ELEVATION);
+    if raised != elevation {
+        city.set_elevation_at_coords(coords, raised);
+    }
+}
+
+/// Whether a cell is open for walking or built on.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+enum CellTint {
+    Walkable,
+    Blocked,
+}
+
+impl CellTint {
+    fn of(city: &City, coords: GridCoords) -> Self {
+        if city.is_walkable(coords) {
+            Self::Walkable
+        } else {
+            Self::Blocked
+        }
+    }
+
+    fn color(self) -> Color {
+        match self {
+            Self::Walkable => Color::rgba(0.4, 0.9, 1.0, 0.25),
+            Self::Blocked => Color::rgba(1.0, 0.3, 0.2, 0.25),
+        }
+    }
+}
+
+fn spawn_walkable_tint(
+    options: Res<Options>,
+    city: Res<ActiveCity>,
+    tints: Query<Entity, With<CellTint>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut commands: Commands,
+) {
+    if !options.is_changed() && !city.is_changed() {
+        return;
+    }
+
+    for tint in &tints {
+        commands.entity(tint).despawn();
+    }
+    if !options.show_walkable_tint {
+        return;
+    }
+
+    // a film just above the ground, under any roads
+    let mesh = meshes.add(Mesh::from(shape::Box {
+        min_x: -0.5,
+        max_x: 0.5,
+        min_y: -0.5,
+        max_y: -0.495,
+        min_z: -0.5,
+        max_z: 0.5,
+    }));
+    let mut material = |tint: CellTint| {
+        materials.add(StandardMaterial {
+            base_color: tint.color(),
+            alpha_mode: AlphaMode::Blend,
+            unlit: true,
+            ..default()
+        })
+    };
+    let walkable = material(CellTint::Walkable);
+    let blocked = material(CellTint::Blocked);
+    for idx in 0..city.x_len * city.y_len {
+        let coords = city.index_to_coords(idx);
+        let tint = CellTint::of(city.as_ref(), coords);
+        let material = match tint {
+            CellTint::Walkable => walkable.clone(),
+            CellTint::Blocked => blocked.clone(),
+        };
+        commands
+            .spawn(PbrBundle {
+                mesh: mesh.clone(),
+                material,
+                ..default()
+            })
+            .insert((tint, coords));
+    }
+}
+
+/// How many times people have stepped on each cell since the last upkeep.
+#[derive(Default, Resource)]
+struct TrafficMap {
+    visits: HashMap<GridCoords, u32>,
+}
+
+impl TrafficMap {
+    fn record_visit(&mut self, coords: GridCoords) {
+        *self.visits.entry(coords).or_default() += 1;
+    }
+
+    fn visits(&self, coords: GridCoords) -> u32 {
+        self.visits.get(&coords).copied().unwrap_or(0)
+    }
+
+    /// Nobody walks *into* a building, so judge it by the traffic going past: through the cells
+    /// `around` it.
+    fn is_derelict(&self, around: impl IntoIterator<Item = GridCoords>, threshold: u32) -> bool {
+        let passing: u32 = around
+            .into_iter()
+            .map(|neighbor| self.visits(neighbor))
+            .sum();
+        passing < threshold
+    }
+
+    fn clear(&mut self) {
+        self.visits.clear();
+    }
+}
+
+#[derive(Resource)]
+struct UpkeepTimer(Timer);
+
+impl Default for UpkeepTimer {
+    fn default() -> Self {
+        Self(Timer::new(UPKEEP_INTERVAL, TimerMode::Repeating))
+    }
+}
+
+/// A building that's been abandoned, shrinking into the ground before it's despawned.
+#[derive(Component)]
+struct Demolishing(Timer);
+
+#[allow(clippy::too_many_arguments)]
+fn abandon_derelict_buildings(
+    time: Res<Time>,
+    options: Res<Options>,
+    mut timer: ResMut<UpkeepTimer>,
+    mut traffic: ResMut<TrafficMap>,
+    mut city: ResMut<ActiveCity>,
+    mut rng: ResMut<SimRng>,
+    buildings: Query<(Entity, &GridCoords), With<Building>>,
+    mut commands: Commands,
+) {
+    if !timer.0.tick(time.delta()).just_finished() {
+        return;
+    }
+
+    for (entity, &coords) in &buildings {
+        if traffic.is_derelict(
+            city.building_neighbors(coords),
+            options.abandon_traffic_threshold,
+        ) && rng.0.gen_bool(options.abandon_chance)
+        {
+            info!("abandoning derelict building at {:?}", coords);
+            // clearing the cell straight away lets people replan through it
+            city.remove_building(coords);
+            commands
+                .entity(entity)
+                .remove::<(Building, GridCoords)>()
+                .insert(Demolishing(Timer::new(DEMOLITION_TIME, TimerMode::Once)));
+        }
+    }
+
+    traffic.clear();
+}
+
+fn demolish_buildings(
+    time: Res<Time>,
+    city: Res<ActiveCity>,
+    mut query: Query<(Entity, &mut Transform, &mut Demolishing)>,
+    mut commands: Commands,
+) {
+    for (entity, mut tx, mut demolishing) in &mut query {
+        demolishing.0.tick(time.delta());
+        if demolishing.0.finished() {
+            commands.entity(entity).despawn();
+            continue;
+        }
+
+        // squash towards the ground, keeping the base where it was
+        let base = tx.translation.y - 0.5 * tx.scale.y;
+        let height = city.scale.0 * demolishing.0.percent_left();
+        tx.scale.y = height;
+        tx.translation.y = base + 0.5 * height;
+    }
+}
+
+/// A person on their way out, shrinking away before they're despawned.
+#[derive(Component)]
+struct Despawning(Timer);
+
+/// Press Delete to send the selected person away.
+fn remove_selected_person(
+    keys: Res<Input<KeyCode>>,
+    selected: Query<Entity, (With<Selected>, Without<Despawning>)>,
+    mut reservations: ResMut<Reservations>,
+    mut population: ResMut<Population>,
+    mut commands: Commands,
+) {
+    if !keys.just_pressed(KeyCode::Delete) {
+        return;
+    }
+    for person in &selected {
+        population.target = population.target.saturating_sub(1);
+        reservations.release(person);
+        commands
+            .entity(person)
+            .remove::<Selected>()
+            .insert(Despawning(Timer::new(PERSON_DESPAWN_TIME, TimerMode::Once)));
+    }
+}
+
+/// How many people there ought to be in the city.
+#[derive(Resource)]
+struct Population {
+    target: usize,
+}
+
+impl Default for Population {
+    fn default() -> Self {
+        Self { target: 10 }
+    }
+}
+
+/// Press + or - for more or fewer people.
+fn keyboard_population(keys: Res<Input<KeyCode>>, mut population: ResMut<Population>) {
+    if keys.any_just_pressed([KeyCode::Equals, KeyCode::NumpadAdd]) {
+        population.target += 1;
+    }
+    if keys.any_just_pressed([KeyCode::Minus, KeyCode::NumpadSubtract]) {
+        population.target = population.target.saturating_sub(1);
+    }
+}
+
+/// Brings in new people or sends some away until there are as many as the population calls for.
+fn match_population(
+    population: Res<Population>,
+    city: Res<ActiveCity>,
+    people: Query<Entity, (With<Person>, Without<Despawning>)>,
+    person_meshes: Res<PersonMeshes>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut reservations: ResMut<Reservations>,
+    mut commands: Commands,
+) {
+    let mut people: Vec<_> = people.iter().collect();
+    let count = people.len();
+    if population.target > count {
+        let mut rng = rand::thread_rng();
+        for _ in count..population.target {
+            commands.spawn(PersonBundle::new(
+                &person_meshes,
+                &mut materials,
+                spawn_position(city.as_ref(), &mut rng),
+                city.scale,
+                city.random_workplace(&mut rng),
+            ));
+        }
+    } else if population.target < count {
+        // newest first
+        people.sort_by_key(|person| Reverse(person.index()));
+        for &person in &people[..count - population.target] {
+            reservations.release(person);
+            commands
+                .entity(person)
+                .remove::<Selected>()
+                .insert(Despawning(Timer::new(PERSON_DESPAWN_TIME, TimerMode::Once)));
+        }
+    }
+}
+
+/// What someone's up to, at a glance: green while they're heading somewhere, red if they
+/// couldn't find a way to where they wanted to go, and the usual dark grey otherwise.
+fn state_color(person: &Person) -> Color {
+    if person.goal.is_some() {
+        PERSON_WALKING_COLOR
+    } else if person.stuck {
+        PERSON_STUCK_COLOR
+    } else {
+        PERSON_COLOR
+    }
+}
+
+fn color_people(
+    people: Query<(&Person, &Handle<StandardMaterial>), Changed<Person>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for (person, material) in &people {
+        let color = state_color(person);
+        // only touch the ones that need it, since every change means re-uploading the material
+        if materials
+            .get(material)
+            .is_some_and(|material| material.base_color != color)
+        {
+            if let Some(material) = materials.get_mut(material) {
+                material.base_color = color;
+            }
+        }
+    }
+}
+
+fn despawn_people(
+    time: Res<Time>,
+    city: Res<ActiveCity>,
+    mut query: Query<(
+        Entity,
+        &mut Transform,
+        &mut Despawning,
+        Option<&mut Velocity>,
+    )>,
+    mut commands: Commands,
+) {
+    for (entity, mut tx, mut despawning, velocity) in &mut query {
+        if let Some(mut velocity) = velocity {
+            *velocity = Velocity::ZERO;
+        }
+        // hang on for a frame once they've shrunk away to nothing, so they're never seen to pop
+        if despawning.0.finished() && tx.scale == Vec3::ZERO {
+            commands.entity(entity).despawn();
+            continue;
+        }
+
+        // shrink towards their feet, wherever those are
+        let feet = tx.translation.y - PERSON_HEIGHT * 0.5 * tx.scale.y;
+        despawning.0.tick(time.delta());
+        let size = city.scale.0 * despawning.0.percent_left();
+        tx.scale = Vec3::splat(size);
+        tx.translation.y = feet + PERSON_HEIGHT * 0.5 * size;
+    }
+}
+
+#[derive(Component)]
+struct Velocity(Vec3);
+
+impl Velocity {
+    const ZERO: Self = Self(Vec3::ZERO);
+}
+
+#[derive(Component)]
+struct Person {
+    goal: Option<GridCoords>,
+    path: NavigationPath,
+    /// how far along the path they've got, so taking a step doesn't mean copying the rest
+    next_step: usize,
+    /// how fast they're currently walking, short of `PERSON_SPEED` while speeding up or slowing
+    /// down
+    speed: f32,
+    /// when they set off for their current goal, in seconds since startup
+    trip_started: f32,
+    /// how long they've left to rest before choosing their next goal
+    dwell: Timer,
+    /// where they head at night
+    home: GridCoords,
+    /// where they head during the day
+    work: GridCoords,
+    /// whether there was no way to the last goal they tried for
+    stuck: bool,
+}
+
+impl Person {
+    fn commuting(home: GridCoords, work: GridCoords) -> Self {
+        Self {
+            home,
+            work,
+            ..default()
+        }
+    }
+
+    fn reset_path(&mut self) {
+        self.set_path(default());
+    }
+
+    fn set_path(&mut self, path: NavigationPath) {
+        self.path = path;
+        self.next_step = 0;
+    }
+
+    /// The steps of their path they've still to take.
+    fn steps(&self) -> &[usize] {
+        self.path.steps.get(self.next_step..).unwrap_or_default()
+    }
+
+    /// Ticks off the step of their path they're standing on, and any before it they cut past
+    /// while steering further ahead, saying whether they were on one.
+    fn reach_step(&mut self, city: &City, coords: GridCoords) -> bool {
+        let reached = self
+            .steps()
+            .iter()
+            .position(|&step| city.index_to_coords(step) == coords);
+        if let Some(skipped) = reached {
+            self.next_step += skipped + 1;
+        }
+        reached.is_some()
+    }
+
+    /// Whether they've finished their trip: standing where their path ends, with at most that
+    /// last step still to tick off.  Crossing the goal cell with more path left doesn't count.
+    fn at_goal(&self, city: &City, coords: GridCoords) -> bool {
+        let Some(goal) = self.goal else {
+            return false;
+        };
+        let end = self
+            .steps()
+            .last()
+            .map_or(goal, |&step| city.index_to_coords(step));
+        end == coords && self.steps().len() <= 1
+    }
+
+    /// Where they ought to be at this time of day.
+    fn destination(&self, time_of_day: &TimeOfDay) -> GridCoords {
+        if time_of_day.is_daytime() {
+            self.work
+        } else {
+            self.home
+        }
+    }
+
+    fn is_dwelling(&self) -> bool {
+        self.goal.is_none() && self.dwell.remaining() > Duration::ZERO
+    }
+}
+
+impl Default for Person {
+    fn default() -> Self {
+        Person {
+            goal: None,
+            path: default(),
+            next_step: 0,
+            speed: 0.0,
+            trip_started: 0.0,
+            dwell: default(),
+            home: GridCoords::ORIGIN,
+            work: GridCoords::ORIGIN,
+            stuck: false,
+        }
+    }
+}
+
+/// How far through the simulated day it is.  The first half of each day is daytime, the second
+/// half night.
+#[derive(Resource)]
+struct TimeOfDay {
+    /// seconds since the start of the current day
+    elapsed: f32,
+    /// how many seconds a whole day and night lasts
+    day_length: f32,
+}
+
+impl Default for TimeOfDay {
+    fn default() -> Self {
+        Self {
+            elapsed: 0.0,
+            day_length: 120.0,
+        }
+    }
+}
+
+impl TimeOfDay {
+    fn tick(&mut self, secs: f32) {
+        self.elapsed = (self.elapsed + secs).rem_euclid(self.day_length);
+    }
+
+    fn is_daytime(&self) -> bool {
+        self.fraction() < 0.5
+    }
+
+    /// how far through the day it is, from 0 at sunrise to 1 at the next
+    fn fraction(&self) -> f32 {
+        self.elapsed / self.day_length
+    }
+}
+
+fn advance_time_of_day(
+    time: Res<Time>,
+    sim_speed: Res<SimSpeed>,
+    paused: Res<Paused>,
+    mut time_of_day: ResMut<TimeOfDay>,
+) {
+    if !paused.0 {
+        time_of_day.tick(sim_speed.delta_seconds(&time));
+    }
+}
+
+/// Marks a person for the arrival handlers registered under the same tag.
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct ArrivalTag(&'static str);
+
+/// A person getting where they were going.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Arrival {
+    person: Entity,
+    goal: GridCoords,
+    /// when they got there, in seconds since startup
+    at: f32,
+}
+
+type ArrivalHandler = Box<dyn FnMut(&Arrival) + Send + Sync>;
+
+/// Code to run whenever someone with a particular `ArrivalTag` reaches their goal.
+#[derive(Default, Resource)]
+struct ArrivalHandlers {
+    handlers: HashMap<&'static str, Vec<ArrivalHandler>>,
+}
+
+impl ArrivalHandlers {
+    fn register(
+        &mut self,
+        tag: &'static str,
+        handler: impl FnMut(&Arrival) + Send + Sync + 'static,
+    ) {
+        self.handlers
+            .entry(tag)
+            .or_default()
+            .push(Box::new(handler));
+    }
+
+    fn arrived(&mut self, tag: ArrivalTag, arrival: &Arrival) {
+        for handler in self.handlers.get_mut(tag.0).into_iter().flatten() {
+            handler(arrival);
+        }
+    }
+}
+
+/// People dropped into the city by hand, who count towards the visitor score when they arrive.
+const VISITOR_TAG: ArrivalTag = ArrivalTag("visitor");
+
+/// How many trips the people dropped in by hand have finished.  Shared with the arrival
+/// handler, which can't reach the world to update a plain resource.
+#[derive(Clone, Default, Resource)]
+struct VisitorScore(Arc<AtomicU32>);
+
+impl VisitorScore {
+    fn get(&self) -> u32 {
+        self.0.load(AtomicOrdering::Relaxed)
+    }
+}
+
+fn register_visitor_score(score: Res<VisitorScore>, mut handlers: ResMut<ArrivalHandlers>) {
+    let score = score.clone();
+    handlers.register(VISITOR_TAG.0, move |_| {
+        score.0.fetch_add(1, AtomicOrdering::Relaxed);
+    });
+}
+
+/// Which cells people plan to be in at each time step, so others can plan around them.  A time
+/// step is roughly how long it takes to walk one cell.
+#[derive(Default, Resource)]
+struct Reservations {
+    cells: HashMap<(usize, u32), Entity>,
+    now: u32,
+}
+
+impl Reservations {
+    fn is_free(&self, idx: usize, time: u32, entity: Entity) -> bool {
+        self.cells
+            .get(&(idx, time))
+            .is_none_or(|&holder| holder == entity)
+    }
+
+    /// Whether walking from one cell to another, starting at `time`, runs into anyone else:
+    /// either someone will be in the destination, or they're coming the other way.
+    fn can_move(&self, from: usize, to: usize, time: u32, entity: Entity) -> bool {
+        if !self.is_free(to, time + 1, entity) {
+            return false;
+        }
+        match (
+            self.cells.get(&(to, time)),
+            self.cells.get(&(from, time + 1)),
+        ) {
+            (Some(&a), Some(&b)) => a != b || a == entity,
+            _ => true,
+        }
+    }
+
+    /// Reserves each step of a path for successive time steps, replacing any previous booking.
+    fn reserve(&mut self, entity: Entity, steps: &[usize]) {
+        self.release(entity);
+        for (i, &idx) in steps.iter().enumerate() {
+            self.cells.insert((idx, self.now + i as u32), entity);
+        }
+    }
+
+    fn release(&mut self, entity: Entity) {
+        self.cells.retain(|_, &mut holder| holder != entity);
+    }
+
+    fn advance(&mut self) {
+        self.now += 1;
+        let now = self.now;
+        self.cells.retain(|&(_, time), _| time >= now);
+    }
+}
+
+fn advance_reservations(
+    time: Res<Time>,
+    sim_speed: Res<SimSpeed>,
+    mut timer: Local<Option<Timer>>,
+    mut reservations: ResMut<Reservations>,
+) {
+    let step = Duration::from_secs_f32(1.0 / sim_speed.person_speed);
+    let timer = timer.get_or_insert_with(|| Timer::new(step, TimerMode::Repeating));
+    timer.set_duration(step);
+    let delta = time.delta().mul_f32(sim_speed.time_scale);
+    for _ in 0..timer.tick(delta).times_finished_this_tick() {
+        reservations.advance();
+    }
+}
+
+/// A* node not yet expanded, ordered by estimated total cost.
+#[derive(PartialEq)]
+struct CooperativeNode {
+    f: f32,
+    g: f32,
+    idx: usize,
+    time: u32,
+}
+
+impl Eq for CooperativeNode {}
+
+impl Ord for CooperativeNode {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.f.total_cmp(&other.f)
+    }
+}
+
+impl PartialOrd for CooperativeNode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A* through space and time, treating cells other people have reserved as blocked at the
+/// times they're reserved.  Returns the same shape of path as `a_star_search`.
+fn cooperative_a_star(
+    city: &City,
+    reservations: &Reservations,
+    entity: Entity,
+    start: usize,
+    end: usize,
+) -> NavigationPath {
+    // give up on routes that wander much further than going straight there
+    let max_time = reservations.now + 2 * (city.x_len + city.y_len) as u32;
+
+    let mut open = BinaryHeap::new();
+    let mut closed = HashSet::new();
+    // best known parent and cost so far of each (cell, time) reached
+    let mut parents: HashMap<(usize, u32), ((usize, u32), f32)> = HashMap::new();
+    open.push(Reverse(CooperativeNode {
+        f: city.get_pathing_distance(start, end),
+        g: 0.0,
+        idx: start,
+        time: reservations.now,
+    }));
+
+    while let Some(Reverse(node)) = open.pop() {
+        if node.idx == end {
+            let mut steps = vec![node.idx];
+            let mut current = (node.idx, node.time);
+            while let Some(&(parent, _)) = parents.get(&current) {
+                steps.push(parent.0);
+                current = parent;
+            }
+            steps.reverse();
+
+            let mut path = NavigationPath::new();
+            path.destination = end;
+            path.success = true;
+            path.steps = steps;
+            return path;
+        }
+
+        if !closed.insert((node.idx, node.time)) || node.time >= max_time {
+            continue;
+        }
+
+        for (next, cost) in city.get_available_exits(node.idx) {
+            let state = (next, node.time + 1);
+            if closed.contains(&state) || !reservations.can_move(node.idx, next, node.time, entity)
+            {
+                continue;
+            }
+            let g = node.g + cost;
+            if parents.get(&state).is_some_and(|&(_, best)| best <= g) {
+                continue;
+            }
+            parents.insert(state, ((node.idx, node.time), g));
+            let f = g + city.get_pathing_distance(next, end);
+            open.push(Reverse(CooperativeNode {
+                f,
+                g,
+                idx: next,
+                time: state.1,
+            }));
+        }
+    }
+
+    NavigationPath::new()
+}
+
+/// The person whose remaining path was most expensive last time we looked.
+#[derive(Default, Resource)]
+struct LongestPath(Option<Entity>);
+
+fn longest_path<'a>(
+    city: &City,
+    people: impl Iterator<Item = (Entity, &'a Person)>,
+) -> Option<Entity> {
+    people
+        .filter(|(_, person)| !person.steps().is_empty())
+        .map(|(entity, person)| (entity, city.path_cost(person.steps())))
+        .max_by(|(_, cost1), (_, cost2)| cost1.total_cmp(cost2))
+        .map(|(entity, _)| entity)
+}
+
+/// Press M to find whoever has furthest to go, and keep their path highlighted.
+fn highlight_longest_path(
+    keys: Res<Input<KeyCode>>,
+    city: Res<ActiveCity>,
+    people: Query<(Entity, &Person, &Transform)>,
+    mut longest: ResMut<LongestPath>,
+    mut gizmos: Gizmos,
+) {
+    if keys.just_pressed(KeyCode::M) {
+        longest.0 = longest_path(
+            &city,
+            people.iter().map(|(entity, person, _)| (entity, person)),
+        );
+        if let Some((_, person, _)) = longest.0.and_then(|entity| people.get(entity).ok()) {
+            eprintln!(
+                "longest path: {} steps, cost {}",
+                person.steps().len(),
+                city.path_cost(person.steps())
+            );
+        }
+    }
+
+    let Some((_, person, tx)) = longest.0.and_then(|entity| people.get(entity).ok()) else {
+        return;
+    };
+    let mut from = tx.translation;
+    for &step in person.steps() {
+        let to = city.index_to_world(step, PERSON_HEIGHT * 0.5);
+        gizmos.line(from, to, Color::FUCHSIA);
+        from = to;
+    }
+}
+
+/// The person picked out for closer inspection.
+#[derive(Component)]
+struct Selected;
+
+/// how close to the cursor a person has to be to select them, in cells
+const SELECT_RADIUS: f32 = 0.5;
+
+fn path_coords(city: &City, steps: &[usize]) -> Vec<GridCoords> {
+    steps
+        .iter()
+        .map(|&step| city.index_to_coords(step))
+        .collect()
+}
+
+fn draw_selected_person(
+    city: Res<ActiveCity>,
+    selected: Query<&Transform, (With<Person>, With<Selected>)>,
+    mut gizmos: Gizmos,
+) {
+    for tx in &selected {
+        gizmos.circle(tx.translation, Vec3::Y, 0.1 * city.scale.0, Color::YELLOW);
+    }
+}
+
+/// Press I to select the person nearest the cursor and dump their route to the log.
+fn inspect_person(
+    keys: Res<Input<KeyCode>>,
+    city: Res<ActiveCity>,
+    cursor_query: Query<&GridCoords, With<Cursor>>,
+    people: Query<(Entity, &Person, &Transform, Option<&Selected>)>,
+    mut commands: Commands,
+) {
+    if !keys.just_pressed(KeyCode::I) {
+        return;
+    }
+
+    let Ok(&grid) = cursor_query.get_single() else {
+        eprintln!("the cursor's off the city, nobody to inspect");
+        return;
+    };
+    // level with the people standing in the cell
+    let cursor = city.coords_to_world(grid, PERSON_HEIGHT * 0.5);
+    let nearest = people
+        .iter()
+        .map(|(entity, person, tx, _)| (entity, person, tx, tx.translation.distance(cursor)))
+        .filter(|&(.., distance)| distance < SELECT_RADIUS * city.scale.0)
+        .min_by(|(.., d1), (.., d2)| d1.total_cmp(d2));
+
+    for (entity, .., selected) in &people {
+        if selected.is_some() {
+            commands.entity(entity).remove::<Selected>();
+        }
+    }
+    let Some((entity, person, tx, _)) = nearest else {
+        eprintln!("nobody near the cursor to inspect");
+        return;
+    };
+    commands.entity(entity).insert(Selected);
+
+    eprintln!(
+        "person {:?} at {:?}, heading for {:?}, route: {:?}",
+        entity,
+        city.world_to_coords(tx.translation),
+        person.goal,
+        path_coords(&city, person.steps())
+    );
+}
+
+/// Who to select after `current`, going through everyone in order of entity index and wrapping
+/// round at the end.  Starts from the first (or last, going backwards) if nobody is selected.
+fn next_selection(people: &[Entity], current: Option<Entity>, backwards: bool) -> Option<Entity> {
+    let mut people = people.to_vec();
+    people.sort_by_key(|person| person.index());
+    let len = people.len();
+    let next = match current.and_then(|current| people.iter().position(|&p| p == current)) {
+        Some(at) if backwards => (at + len - 1) % len,
+        Some(at) => (at + 1) % len,
+        None if backwards => len.checked_sub(1)?,
+        None => 0,
+    };
+    people.get(next).copied()
+}
+
+/// Press Tab to select the next person, or Shift+Tab for the previous one.
+fn cycle_selected_person(
+    keys: Res<Input<KeyCode>>,
+    people: Query<Entity, (With<Person>, Without<Despawning>)>,
+    selected: Query<Entity, With<Selected>>,
+    mut commands: Commands,
+) {
+    if !keys.just_pressed(KeyCode::Tab) {
+        return;
+    }
+    let backwards = keys.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+
+    let people: Vec<_> = people.iter().collect();
+    let current = selected.iter().next();
+    let Some(next) = next_selection(&people, current, backwards) else {
+        return;
+    };
+    for entity in &selected {
+        commands.entity(entity).remove::<Selected>();
+    }
+    commands.entity(next).insert(Selected);
+}
+
+/// The (current cell, goal) of each person whose goal can't be reached from where they are.
+fn unreachable_goals(
+    city: &City,
+    people: impl IntoIterator<Item = (GridCoords, GridCoords)>,
+) -> Vec<(GridCoords, GridCoords)> {
+    people
+        .into_iter()
+        .filter(|&(from, goal)| {
+            let (Some(start), Some(end)) = (city.coords_to_index(from), city.coords_to_index(goal))
+            else {
+                return true;
+            };
+            !a_star_search(start, end, city).success
+        })
+        .collect()
+}
+
+/// Press V to check that everyone can actually get to where they're going.
+fn validate_goals(
+    keys: Res<Input<KeyCode>>,
+    city: Res<ActiveCity>,
+    people: Query<(&Person, &Transform)>,
+) {
+    if !keys.just_pressed(KeyCode::V) {
+        return;
+    }
+
+    let unreachable = unreachable_goals(
+        &city,
+        people
+            .iter()
+            .filter_map(|(person, tx)| Some((city.world_to_coords(tx.translation), person.goal?))),
+    );
+    eprintln!(
+        "{} of {} people have unreachable goals",
+        unreachable.len(),
+        people.iter().count()
+    );
+    for (from, goal) in unreachable {
+        eprintln!("  {:?} can't reach {:?}", from, goal);
+    }
+}
+
+/// Press Y to pick out (or stop picking out) buildings standing on their own.
+fn highlight_isolated_buildings(
+    keys: Res<Input<KeyCode>>,
+    city: Res<ActiveCity>,
+    mut isolated: Local<Option<Vec<(GridCoords, Height)>>>,
+    mut gizmos: Gizmos,
+) {
+    if keys.just_pressed(KeyCode::Y) {
+        *isolated = match *isolated {
+            Some(_) => None,
+            None => {
+                let found = city.isolated_buildings();
+                eprintln!("{} isolated buildings: {:?}", found.len(), found);
+                Some(found)
+            }
+        };
+    } else if city.is_changed() && isolated.is_some() {
+        *isolated = Some(city.isolated_buildings());
+    }
+
+    let rotation = Quat::from_rotation_x(PI * 0.5);
+    let cells = isolated.iter().flatten().flat_map(|&(coords, height)| {
+        city.building_cells(coords)
+            .into_iter()
+            .map(move |cell| (cell, height))
+    });
+    for (coords, height) in cells {
+        gizmos.rect(
+            city.coords_to_world(coords, height as f32),
+            rotation,
+            Vec2::splat(city.scale.0),
+            Color::ANTIQUE_WHITE,
+        );
+    }
+}
+
+/// Every (from, to, cost) move the pathfinder can make between walkable cells.
+fn navigation_edges(city: &City) -> Vec<(usize, usize, f32)> {
+    (0..city.x_len * city.y_len)
+        .filter(|&idx| city.is_walkable(city.index_to_coords(idx)))
+        .flat_map(|idx| {
+            city.get_available_exits(idx)
+                .into_iter()
+                .map(move |(exit, cost)| (idx, exit, cost))
+        })
+        .collect()
+}
+
+fn draw_center_of_mass(
+    options: Res<Options>,
+    city: Res<ActiveCity>,
+    mut center: Local<Option<Vec3>>,
+    mut gizmos: Gizmos,
+) {
+    if !options.show_center_of_mass {
+        return;
+    }
+    if city.is_changed() || options.is_changed() {
+        *center = city.center_of_mass();
+    }
+    let Some(center) = *center else {
+        return;
+    };
+
+    gizmos.sphere(center, Quat::IDENTITY, 0.15, Color::FUCHSIA);
+    // and a plumb line down to the ground, to see which cell it's over
+    gizmos.line(center, center * Vec3::new(1.0, 0.0, 1.0), Color::FUCHSIA);
+}
+
+/// how far above the ground to draw the grid, so it doesn't flicker in and out of it
+const GRID_LINE_HEIGHT: f32 = 0.01;
+
+/// The boundaries between cells, and around the edge of the city, as pairs of endpoints.
+fn grid_lines(city: &City) -> Vec<(Vec3, Vec3)> {
+    let (min, max) = city.world_bounds();
+    let y = GRID_LINE_HEIGHT;
+    let across = (0..=city.x_len).map(|i| {
+        let x = min.x + i as f32 * city.scale.0;
+        (Vec3::new(x, y, min.z), Vec3::new(x, y, max.z))
+    });
+    let along = (0..=city.y_len).map(|i| {
+        let z = min.z + i as f32 * city.scale.0;
+        (Vec3::new(min.x, y, z), Vec3::new(max.x, y, z))
+    });
+    across.chain(along).collect()
+}
+
+fn draw_grid(options: Res<Options>, city: Res<ActiveCity>, mut gizmos: Gizmos) {
+    if !options.draw_grid {
+        return;
+    }
+    for (from, to) in grid_lines(city.as_ref()) {
+        gizmos.line(from, to, Color::rgba(0.0, 0.0, 0.0, 0.3));
+    }
+}
+
+fn draw_navigation_graph(
+    options: Res<Options>,
+    city: Res<ActiveCity>,
+    mut edges: Local<Option<Vec<(usize, usize, f32)>>>,
+    mut gizmos: Gizmos,
+) {
+    if !options.show_navigation_graph {
+        *edges = None;
+        return;
+    }
+    // only worth redoing the search of the whole grid when it's changed
+    if city.is_changed() || edges.is_none() {
+        *edges = Some(navigation_edges(city.as_ref()));
+    }
+
+    for &(from, to, cost) in edges.iter().flatten() {
+        let start = city.index_to_world(from, 0.05);
+        let end = city.index_to_world(to, 0.05);
+        let color = if cost > 1.0 {
+            Color::ORANGE
+        } else {
+            Color::CYAN
+        };
+        // stop short of halfway, so a one-way exit doesn't look like a two-way one
+        gizmos.line(start, start.lerp(end, 0.45), color);
+    }
+}
+
+fn reset_paths_after_city_changes(city: Res<ActiveCity>, mut people: Query<&mut Person>) {
+    if city.is_changed() {
+        for mut person in &mut people {
+            person.reset_path();
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn people_walk(
+    time: Res<Time>,
+    city: Res<ActiveCity>,
+    mut query: Query<
+        (
+            Entity,
+            &mut Person,
+            &Transform,
+            &mut Velocity,
+            Option<&ArrivalTag>,
+        ),
+        Without<Despawning>,
+    >,
+    options: Res<Options>,
+    mut arrival_handlers: ResMut<ArrivalHandlers>,
+    mut trips: ResMut<TripStats>,
+    mut timing: ResMut<WalkTiming>,
+    mut traffic: ResMut<TrafficMap>,
+    mut reservations: ResMut<Reservations>,
+    mut rng: ResMut<SimRng>,
+    cursor_query: Query<&GridCoords, With<Cursor>>,
+    paused: Res<Paused>,
+    time_of_day: Res<TimeOfDay>,
+    sim_speed: Res<SimSpeed>,
+    mut gizmos: Gizmos,
+) {
+    if paused.0 {
+        return;
+    }
+    let started = Instant::now();
+    let secs = sim_speed.delta_seconds(&time);
+    // nobody flees a cursor that's off the ground
+    let cursor = cursor_query
+        .get_single()
+        .ok()
+        .filter(|_| options.flee_cursor)
+        .map(|&grid| city.coords_to_world(grid, 0.0));
+
+    for (entity, mut person, tx, mut velocity, arrival_tag) in &mut query {
+        let coords = city.world_to_coords(tx.translation);
+
+        let now = time.elapsed_seconds();
+        if let Some(goal) = person
+            .goal
+            .filter(|_| person.at_goal(city.as_ref(), coords))
+        {
+            trips.record(now - person.trip_started);
+            if let Some(&tag) = arrival_tag {
+                let arrival = Arrival {
+                    person: entity,
+                    goal,
+                    at: now,
+                };
+                arrival_handlers.arrived(tag, &arrival);
+            }
+        }
+
+        plan_trip(
+            &mut person,
+            entity,
+            coords,
+            city.as_ref(),
+            &options,
+            &mut reservations,
+            &time_of_day,
+            now,
+            secs,
+            &mut rng.0,
+        );
+        if person.is_dwelling() {
+            person.speed = 0.0;
+            velocity.0 = Vec3::ZERO;
+            continue;
+        }
+
+        if options.draw_paths {
+            let mut path_dbg_from = tx.translation;
+            for &step in person.steps() {
+                let path_dbg_to = city.index_to_world(step, PERSON_HEIGHT * 0.5);
+                gizmos.line(path_dbg_from, path_dbg_to, Color::rgba_u8(0, 0, 0, 100));
+                path_dbg_from = path_dbg_to;
+            }
+        }
+
+        if let Some(&step) = person.steps().first() {
+            let goal_coords = city.index_to_coords(step);
+
+            if person.reach_step(city.as_ref(), coords) {
+                traffic.record_visit(coords);
+                trace!("reached next step, steps now: {:?}", person.steps());
+                person.speed = arrival_speed(
+                    person.speed,
+                    options.pass_through_steps,
+                    person.steps().len(),
+                    options.person_acceleration,
+                    secs,
+                    sim_speed.person_speed,
+                );
+                let direction = if options.pass_through_steps {
+                    // head straight on for the next step rather than overshooting this one
+                    lookahead_target(city.as_ref(), person.steps(), options.steering_lookahead)
+                        .map_or(velocity.0, |target| target - tx.translation)
+                } else {
+                    velocity.0
+                };
+                velocity.0 = direction.normalize_or_zero() * person.speed;
+            } else {
+                let target =
+                    lookahead_target(city.as_ref(), person.steps(), options.steering_lookahead)
+                        .unwrap_or_else(|| city.coords_to_world(goal_coords, PERSON_HEIGHT * 0.5));
+                let direction = target - tx.translation;
+                person.speed = approach_speed(
+                    person.speed,
+                    sim_speed.person_speed,
+                    options.person_acceleration,
+                    secs,
+                    sim_speed.person_speed,
+                );
+                velocity.0 = direction.normalize_or_zero() * person.speed;
+            }
+        } else {
+            trace!("nowhere to go for now");
+            person.speed = approach_speed(
+                person.speed,
+                0.0,
+                options.person_acceleration,
+                secs,
+                sim_speed.person_speed,
+            );
+            velocity.0 = velocity.0.normalize_or_zero() * person.speed;
+        }
+
+        if let Some(cursor) = cursor {
+            let push = repulsion(
+                tx.translation,
+                cursor,
+                options.flee_radius * city.scale.0,
+                options.flee_strength,
+            );
+            // don't shoo anyone into a building or off the edge of the city
+            let pushed_into = city
+                .world_to_coords(tx.translation + push.normalize_or_zero() * 0.5 * city.scale.0);
+            if city.is_walkable(pushed_into) {
+                velocity.0 += push;
+            }
+        }
+    }
+
+    timing.last = started.elapsed();
+}
+
+/// Sends the person off home or to work once they've reached their last goal and rested a
+/// while, or straight away if day turns to night or back on the way.  Plans a path there if
+/// they've run out.  With planning switched off they just finish what they're doing and stop.
+#[allow(clippy::too_many_arguments)]
+fn plan_trip(
+    person: &mut Person,
+    entity: Entity,
+    coords: GridCoords,
+    city: &City,
+    options: &Options,
+    reservations: &mut Reservations,
+    time_of_day: &TimeOfDay,
+    now: f32,
+    secs: f32,
+    rng: &mut impl Rng,
+) {
+    // buildings are gone into by their entrances, and without one people stay put
+    let destination = person.destination(time_of_day);
+    let destination = if city.height_at_coords(destination).is_some() {
+        city.entrance(destination).unwrap_or(coords)
+    } else {
+        destination
+    };
+
+    if options.plan_paths && person.goal.is_some_and(|goal| goal != destination) {
+        person.reset_path();
+        if destination == coords {
+            // nothing to walk to, so no trip to finish either
+            debug!("change of plan, staying at {:?}", destination);
+            person.goal = None;
+        } else {
+            debug!("change of plan, heading for {:?}", destination);
+            person.goal = Some(destination);
+            person.trip_started = now;
+        }
+    }
+
+    if person.at_goal(city, coords) {
+        person.goal = None;
+        person.reset_path();
+        person.dwell = dwell_timer(options, rng);
+    }
+
+    if person.goal.is_none() {
+        person.dwell.tick(Duration::from_secs_f32(secs));
+        // already where they should be, so they'll wait for the clock
+        if person.dwell.remaining() > Duration::ZERO || !options.plan_paths || destination == coords
+        {
+            return;
+        }
+        let goal = destination;
+        debug!("new goal: {:?}", goal);
+        person.goal = Some(goal);
+        person.trip_started = now;
+
+        person.reset_path();
+    }
+
+    if person.steps().is_empty() && options.plan_paths {
+        trace!("empty path, replanning");
+        let goal = person.goal.unwrap(); // previous condition assigned it
+        let path = if options.cooperative_pathing {
+            city.valid_exit(coords)
+                .zip(city.valid_exit(goal))
+                .map(|(start, end)| cooperative_a_star(city, reservations, entity, start, end))
+                .filter(|path| !path.steps.is_empty())
+        } else {
+            city.find_path(coords, goal)
+        };
+
+        let Some(mut path) = path else {
+            debug!("unreachable goal, try again later");
+            person.goal = None;
+            person.stuck = true;
+            // wait a while before searching again, rather than failing the same search every
+            // frame
+            person.dwell = dwell_timer(options, rng);
+            return;
+        };
+        if options.manhattan_paths {
+            path.steps = manhattanize_path(city, &path.steps);
+        }
+        if options.cooperative_pathing {
+            reservations.reserve(entity, &path.steps);
+        }
+        person.set_path(path);
+        person.stuck = false;
+        if options.verbose_pathing {
+            debug!("planned path: {:?}", person.steps());
+        }
+    }
+}
+
+/// How long to rest before setting off again, somewhere in the range the options allow.
+fn dwell_timer(options: &Options, rng: &mut impl Rng) -> Timer {
+    let (shortest, longest) = options.dwell_time;
+    Timer::new(rng.gen_range(shortest..=longest), TimerMode::Once)
+}
+
+/// Rewrites a path so every step is to an orthogonal neighbour, by turning any other move into
+/// an L-shaped detour through walkable cells.  Gives back the path unchanged if there's no way
+/// to do that.
+fn manhattanize_path(city: &City, steps: &[usize]) -> Vec<usize> {
+    let Some(&first) = steps.first() else {
+        return vec![];
+    };
+    let walk = |from: GridCoords, to: GridCoords, x_first: bool| -> Option<Vec<GridCoords>> {
+        let mut cells = vec![];
+        let mut at = from;
+        while at != to {
+            if (x_first && at.x != to.x) || at.y == to.y {
+                at.x += (to.x - at.x).signum();
+            } else {
+                at.y += (to.y - at.y).signum();
+            }
+            city.exit_to(*cells.last().unwrap_or(&from), at)?;
+            cells.push(at);
+        }
+        Some(cells)
+    };
+
+    let mut straightened = vec![first];
+    for pair in steps.windows(2) {
+        let (from, to) = (city.index_to_coords(pair[0]), city.index_to_coords(pair[1]));
+        let Some(cells) = walk(from, to, true).or_else(|| walk(from, to, false)) else {
+            return steps.to_vec();
+        };
+        straightened.extend(
+            cells
+                .into_iter()
+                .filter_map(|cell| city.coords_to_index(cell)),
+        );
+    }
+    straightened
+}
+
+/// Where to steer for: `lookahead` steps beyond the next one, or the end of the path if it's
+/// shorter than that.
+fn lookahead_target(city: &City, steps: &[usize], lookahead: usize) -> Option<Vec3> {
+    let &step = steps.get(lookahead.min(steps.len().checked_sub(1)?))?;
+    Some(city.index_to_world(step, PERSON_HEIGHT * 0.5))
+}
+
+/// Speeds up or slows down towards the target speed, as fast as the acceleration allows and no
+/// faster than `top_speed`.
+fn approach_speed(current: f32, target: f32, acceleration: f32, secs: f32, top_speed: f32) -> f32 {
+    let max_change = acceleration * secs;
+    let speed = current + (target - current).clamp(-max_change, max_change);
+    speed.clamp(0.0, top_speed)
+}
+
+/// How fast to go on reaching a step of a path, with `remaining` steps still to go after it.
+fn arrival_speed(
+    current: f32,
+    pass_through: bool,
+    remaining: usize,
+    acceleration: f32,
+    secs: f32,
+    top_speed: f32,
+) -> f32 {
+    let target = if pass_through && remaining > 0 {
+        top_speed
+    } else {
+        0.0
+    };
+    approach_speed(current, target, acceleration, secs, top_speed)
+}
+
+/// Velocity pushing a person away from a point, strongest up close and fading out to nothing at
+/// `radius`.  Only pushes across the ground, never up or down.
+fn repulsion(person: Vec3, repulsor: Vec3, radius: f32, strength: f32) -> Vec3 {
+    let away = (person - repulsor) * Vec3::new(1.0, 0.0, 1.0);
+    let distance = away.length();
+    if distance >= radius {
+        return Vec3::ZERO;
+    }
+    away.normalize_or_zero() * strength * (1.0 - distance / radius)
+}
+
+/// Nudges people apart when they get too close to each other.
+fn avoid_each_other(
+    city: Res<ActiveCity>,
+    options: Res<Options>,
+    mut people: Query<(&Transform, &mut Velocity), With<Person>>,
+) {
+    let positions: Vec<_> = people.iter().map(|(tx, _)| tx.translation).collect();
+    for (tx, mut velocity) in &mut people {
+        let push: Vec3 = positions
+            .iter()
+            // everyone's pushed away from themselves by nothing, since they're no distance away
+            .map(|&other| {
+                repulsion(
+                    tx.translation,
+                    other,
+                    options.avoid_radius * city.scale.0,
+                    options.avoid_strength,
+                )
+            })
+            .sum();
+        if push == Vec3::ZERO {
+            continue;
+        }
+        // don't squeeze anyone into a building or off the edge of the city
+        let pushed_into =
+            city.world_to_coords(tx.translation + push.normalize_or_zero() * 0.5 * city.scale.0);
+        if city.is_walkable(pushed_into) {
+            velocity.0 += push;
+        }
+    }
+}
+
+/// How long `people_walk` took last frame.
+#[derive(Default, Resource)]
+struct WalkTiming {
+    last: Duration,
+    last_warning: Option<Duration>,
+}
+
+impl WalkTiming {
+    /// Whether the last frame went over budget, unless we warned about that recently.
+    fn should_warn(&mut self, budget_ms: f32, now: Duration) -> bool {
+        if self.last.as_secs_f32() * 1000.0 <= budget_ms {
+            return false;
+        }
+        if self
+            .last_warning
+            .is_some_and(|warned| now - warned < TICK_BUDGET_WARNING_INTERVAL)
+        {
+            return false;
+        }
+        self.last_warning = Some(now);
+        true
+    }
+}
+
+fn warn_over_tick_budget(time: Res<Time>, options: Res<Options>, mut timing: ResMut<WalkTiming>) {
+    if timing.should_warn(options.walk_budget_ms, time.elapsed()) {
+        warn!(
+            "people_walk took {:.1}ms, over budget of {:.1}ms",
+            timing.last.as_secs_f32() * 1000.0,
+            options.walk_budget_ms
+        );
+    }
+}
+
+/// Moves everyone along, their velocities being in cells a second.
+fn apply_velocities(
+    time: Res<Time>,
+    city: Res<ActiveCity>,
+    sim_speed: Res<SimSpeed>,
+    paused: Res<Paused>,
+    mut q: Query<(&mut Transform, &Velocity)>,
+) {
+    if paused.0 {
+        return;
+    }
+    let secs = sim_speed.delta_seconds(&time);
+    for (mut tx, &Velocity(v)) in &mut q {
+        tx.translation += v * secs * city.scale.0;
+    }
+}
+
+/// How fast someone has been going over the last few seconds.
+#[derive(Component, Default)]
+struct SpeedHistory {
+    /// (seconds, speed) for each recent tick, oldest first
+    samples: VecDeque<(f32, f32)>,
+}
+
+impl SpeedHistory {
+    /// Note the speed over a tick, forgetting ticks from more than `window` seconds ago.
+    fn record(&mut self, secs: f32, speed: f32, window: f32) {
+        self.samples.push_back((secs, speed));
+        while self.samples.len() > 1 && self.covered() - self.samples[0].0 >= window {
+            self.samples.pop_front();
+        }
+    }
+
+    fn covered(&self) -> f32 {
+        self.samples.iter().map(|&(secs, _)| secs).sum()
+    }
+
+    /// Whether they've averaged less than `speed` over the whole of the last `window` seconds.
+    fn is_jammed(&self, speed: f32, window: f32) -> bool {
+        let covered = self.covered();
+        if covered < window || covered <= 0.0 {
+            return false;
+        }
+        let distance: f32 = self.samples.iter().map(|&(secs, v)| secs * v).sum();
+        distance / covered < speed
+    }
+}
+
+/// How many jammed people there are in each cell that has any.
+#[derive(Resource, Default)]
+struct Jams(HashMap<GridCoords, usize>);
+
+fn detect_jams(
+    time: Res<Time>,
+    options: Res<Options>,
+    city: Res<ActiveCity>,
+    mut people: Query<(&Transform, &Velocity, &mut SpeedHistory)>,
+    mut jams: ResMut<Jams>,
+) {
+    let secs = time.delta_seconds();
+    let mut jammed = HashMap::new();
+    for (tx, &Velocity(v), mut history) in &mut people {
+        history.record(secs, v.length(), options.jam_window);
+        if history.is_jammed(options.jam_speed, options.jam_window) {
+            *jammed
+                .entry(city.world_to_coords(tx.translation))
+                .or_insert(0) += 1;
+        }
+    }
+
+    if jammed != jams.0 {
+        if options.show_jams && !jammed.is_empty() {
+            eprintln!("jams: {:?}", jammed);
+        }
+        jams.0 = jammed;
+    }
+}
+
+fn draw_jams(options: Res<Options>, city: Res<ActiveCity>, jams: Res<Jams>, mut gizmos: Gizmos) {
+    if options.show_jams {
+        let rotation = Quat::from_rotation_x(PI * 0.5);
+        for (&coords, &count) in &jams.0 {
+            let size = 0.5 + 0.5 * (count as f32 / 4.0).min(1.0);
+            gizmos.rect(
+                city.coords_to_world(coords, 0.01),
+                rotation,
+                Vec2::splat(size * city.scale.0),
+                Color::RED,
+            );
+        }
+    }
+}
+
+struct ReplayFrame {
+    people: Vec<(Entity, Vec3, Option<GridCoords>)>,
+}
+
+/// The last few hundred ticks of people's positions and goals, for replaying.
+#[derive(Resource)]
+struct ReplayBuffer {
+    frames: VecDeque<ReplayFrame>,
+    capacity: usize,
+    /// index of the next frame to play back, if playing
+    playback: Option<usize>,
+}
+
+impl ReplayBuffer {
+    fn new(capacity: usize) -> Self {
+        Self {
+            frames: VecDeque::with_capacity(capacity),
+            capacity,
+            playback: None,
+        }
+    }
+
+    fn record(&mut self, frame: ReplayFrame) {
+        if self.frames.len() == self.capacity {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame);
+    }
+
+    fn is_playing(&self) -> bool {
+        self.playback.is_some()
+    }
+
+    fn start_playback(&mut self) {
+        self.playback = Some(0);
+    }
+
+    fn stop_playback(&mut self) {
+        self.playback = None;
+    }
+
+    fn next_frame(&mut self) -> Option<&ReplayFrame> {
+        let idx = self.playback?;
+        if idx >= self.frames.len() {
+            self.stop_playback();
+            return None;
+        }
+        self.playback = Some(idx + 1);
+        self.frames.get(idx)
+    }
+}
+
+impl Default for ReplayBuffer {
+    fn default() -> Self {
+        Self::new(REPLAY_CAPACITY)
+    }
+}
+
+/// How fast the simulation runs.
+#[derive(Resource)]
+struct SimSpeed {
+    /// how fast people walk, in cells/s
+    person_speed: f32,
+    /// how many simulated seconds pass for every real one
+    time_scale: f32,
+}
+
+impl Default for SimSpeed {
+    fn default() -> Self {
+        Self {
+            person_speed: PERSON_SPEED,
+            time_scale: 1.0,
+        }
+    }
+}
+
+impl SimSpeed {
+    /// How many simulated seconds this frame lasted.
+    fn delta_seconds(&self, time: &Time) -> f32 {
+        time.delta_seconds() * self.time_scale
+    }
+}
+
+const MIN_TIME_SCALE: f32 = 0.25;
+const MAX_TIME_SCALE: f32 = 8.0;
+
+/// Press ] to run the simulation twice as fast, or [ to run it half as fast.
+fn keyboard_sim_speed(keys: Res<Input<KeyCode>>, mut sim_speed: ResMut<SimSpeed>) {
+    let factor = if keys.just_pressed(KeyCode::BracketRight) {
+        2.0
+    } else if keys.just_pressed(KeyCode::BracketLeft) {
+        0.5
+    } else {
+        return;
+    };
+    sim_speed.time_scale = (sim_speed.time_scale * factor).clamp(MIN_TIME_SCALE, MAX_TIME_SCALE);
+    info!("time scale: {}", sim_speed.time_scale);
+}
+
+/// Whether the people are frozen in place, so the city can be edited around them.
+#[derive(Resource, Default)]
+struct Paused(bool);
+
+/// Pauses the simulation when nobody's touched the keyboard or mouse for a while.
+#[derive(Resource)]
+struct IdlePause {
+    idle: Duration,
+    threshold: Duration,
+}
+
+impl Default for IdlePause {
+    fn default() -> Self {
+        Self {
+            idle: Duration::ZERO,
+            threshold: Duration::from_secs(120),
+        }
+    }
+}
+
+impl IdlePause {
+    fn tick(&mut self, delta: Duration, had_input: bool) {
+        if had_input {
+            self.idle = Duration::ZERO;
+        } else {
+            self.idle += delta;
+        }
+    }
+
+    fn is_paused(&self) -> bool {
+        self.idle >= self.threshold
+    }
+}
+
+fn watch_for_idleness(
+    time: Res<Time>,
+    mut idle: ResMut<IdlePause>,
+    mut keyboard: EventReader<bevy::input::keyboard::KeyboardInput>,
+    mut mouse_buttons: EventReader<bevy::input::mouse::MouseButtonInput>,
+    mut mouse_wheel: EventReader<MouseWheel>,
+    mut cursor_moved: EventReader<CursorMoved>,
+) {
+    // read them all, so none are left over to count next frame
+    let had_input = keyboard.iter().count()
+        + mouse_buttons.iter().count()
+        + mouse_wheel.iter().count()
+        + cursor_moved.iter().count()
+        > 0;
+    let was_paused = idle.is_paused();
+    idle.tick(time.delta(), had_input);
+    if idle.is_paused() != was_paused {
+        eprintln!(
+            "{}",
+            if was_paused {
+                "welcome back, resuming"
+            } else {
+                "nobody around, pausing"
+            }
+        );
+    }
+}
+
+fn not_idle(idle: Res<IdlePause>) -> bool {
+    !idle.is_paused()
+}
+
+fn not_replaying(replay: Res<ReplayBuffer>) -> bool {
+    !replay.is_playing()
+}
+
+fn keyboard_replay(keys: Res<Input<KeyCode>>, mut replay: ResMut<ReplayBuffer>) {
+    if keys.just_pressed(KeyCode::L) {
+        if replay.is_playing() {
+            replay.stop_playback();
+        } else {
+            replay.start_playback();
+        }
+    }
+}
+
+fn record_replay(mut replay: ResMut<ReplayBuffer>, people: Query<(Entity, &Transform, &Person)>) {
+    if replay.is_playing() {
+        return;
+    }
+
+    let people = people
+        .iter()
+        .map(|(entity, tx, person)| (entity, tx.translation, person.goal))
+        .collect();
+    replay.record(ReplayFrame { people });
+}
+
+fn play_replay(mut replay: ResMut<ReplayBuffer>, mut people: Query<(&mut Transform, &mut Person)>) {
+    let Some(frame) = replay.next_frame() else {
+        return;
+    };
+
+    for &(entity, translation, goal) in &frame.people {
+        // people may have been despawned since
+        if let Ok((mut tx, mut person)) = people.get_mut(entity) {
+            tx.translation = translation;
+            person.goal = goal;
+        }
+    }
+}
+
+/// The corners of the box a building of this height and footprint occupies: the four around
+/// its base, going anticlockwise as seen from below, then the four above them around its roof.
+/// The base sits on the terrain under the anchor cell.
+fn building_corners(
+    city: &City,
+    coords: GridCoords,
+    footprint: Footprint,
+    height: Height,
+) -> [Vec3; 8] {
+    let scale = city.scale;
+    let base = city.coords_to_world(coords, 0.0);
+    let top = height as f32;
+    // the footprint grows from the anchor cell towards +x and +y
+    let far = footprint.size() as f32 - 0.5;
+    let footprint = [(-0.5, -0.5), (far, -0.5), (far, far), (-0.5, far)];
+
+    let mut corners = [Vec3::ZERO; 8];
+    for (i, &(dx, dz)) in footprint.iter().enumerate() {
+        corners[i] = base + Vec3::new(dx, 0.0, dz) * scale.0;
+        corners[i + 4] = base + Vec3::new(dx, top, dz) * scale.0;
+    }
+    corners
+}
+
+/// The twelve edges of the box a building of this height and footprint occupies.
+fn building_outline_edges(
+    city: &City,
+    coords: GridCoords,
+    footprint: Footprint,
+    height: Height,
+) -> [(Vec3, Vec3); 12] {
+    let corners = building_corners(city, coords, footprint, height);
+
+    let mut edges = [(Vec3::ZERO, Vec3::ZERO); 12];
+    for i in 0..4 {
+        let next = (i + 1) % 4;
+        edges[i] = (corners[i], corners[next]);
+        edges[i + 4] = (corners[i + 4], corners[next + 4]);
+        edges[i + 8] = (corners[i], corners[i + 4]);
+    }
+    edges
+}
+
+/// The buildings and ground as a Wavefront OBJ, for loading into other 3D tools.
+fn city_to_obj(city: &City) -> String {
+    let mut obj = String::from("# citybee city\n");
+    // OBJ indices count from 1 across the whole file
+    let mut first_vertex = 1;
+    let mut write_vertices = |obj: &mut String, vertices: &[Vec3]| {
+        for v in vertices {
+            obj.push_str(&format!("v {} {} {}\n", v.x, v.y, v.z));
+        }
+        let first = first_vertex;
+        first_vertex += vertices.len();
+        first
+    };
+    let write_face = |obj: &mut String, first: usize, corners: [usize; 4]| {
+        let [a, b, c, d] = corners.map(|corner| first + corner);
+        obj.push_str(&format!("f {} {} {} {}\n", a, b, c, d));
+    };
+
+    let (min, max) = city.ground_bounds();
+    obj.push_str("o ground\n");
+    let ground = write_vertices(
+        &mut obj,
+        &[
+            Vec3::new(min.x, 0.0, min.z),
+            Vec3::new(min.x, 0.0, max.z),
+            Vec3::new(max.x, 0.0, max.z),
+            Vec3::new(max.x, 0.0, min.z),
+        ],
+    );
+    write_face(&mut obj, ground, [0, 1, 2, 3]);
+
+    for (coords, height) in city.distinct_buildings() {
+        obj.push_str(&format!("o building_{}_{}\n", coords.x, coords.y));
+        let corners = building_corners(city, coords, city.footprint_at(coords), height);
+        let first = write_vertices(&mut obj, &corners);
+        // faces wound anticlockwise seen from outside
+        write_face(&mut obj, first, [0, 1, 2, 3]);
+        write_face(&mut obj, first, [4, 7, 6, 5]);
+        for i in 0..4 {
+            let next = (i + 1) % 4;
+            write_face(&mut obj, first, [i, i + 4, next + 4, next]);
+        }
+    }
+
+    obj
+}
+
+/// Press F7 to export the city to an OBJ file.
+fn export_city_obj(keys: Res<Input<KeyCode>>, city: Res<ActiveCity>) {
+    if !keys.just_pressed(KeyCode::F7) {
+        return;
+    }
+    match fs::write(OBJ_EXPORT_FILE, city_to_obj(&city)) {
+        Ok(()) => eprintln!("exported city to {}", OBJ_EXPORT_FILE),
+        Err(e) => eprintln!("couldn't export city to {}: {}", OBJ_EXPORT_FILE, e),
+    }
+}
+
+/// Journeys people have finished.
+#[derive(Default, Resource)]
+struct TripStats {
+    completed: u32,
+    total_secs: f32,
+}
+
+impl TripStats {
+    fn record(&mut self, secs: f32) {
+        self.completed += 1;
+        self.total_secs += secs;
+    }
+}
+
+/// A snapshot of how the city's doing.
+#[derive(Debug, PartialEq)]
+struct StatsReport {
+    buildings: usize,
+    total_height: usize,
+    average_height: f32,
+    population: usize,
+    completed_trips: u32,
+    average_trip_secs: f32,
+    walkable_cells: usize,
+    blocked_cells: usize,
+}
+
+impl StatsReport {
+    fn gather(city: &City, population: usize, trips: &TripStats) -> Self {
+        let buildings = city.building_count();
+        let total_height = city.distinct_buildings().map(|(_, h)| h as usize).sum();
+        let blocked = city.buildings_iter().count();
+        let average = |total: f32, count: usize| {
+            if count == 0 {
+                0.0
+            } else {
+                total / count as f32
+            }
+        };
+        Self {
+            buildings,
+            total_height,
+            average_height: average(total_height as f32, buildings),
+            population,
+            completed_trips: trips.completed,
+            average_trip_secs: average(trips.total_secs, trips.completed as usize),
+            walkable_cells: city.cells() - blocked,
+            blocked_cells: blocked,
+        }
+    }
+
+    fn to_csv(&self) -> String {
+        format!(
+            "buildings,total_height,average_height,population,completed_trips,average_trip_secs,walkable_cells,blocked_cells\n\
+             {},{},{:.2},{},{},{:.2},{},{}\n",
+            self.buildings,
+            self.total_height,
+            self.average_height,
+            self.population,
+            self.completed_trips,
+            self.average_trip_secs,
+            self.walkable_cells,
+            self.blocked_cells,
+        )
+    }
+}
+
+/// Press F8 to write out a summary of the city's stats.
+fn export_stats(
+    keys: Res<Input<KeyCode>>,
+    city: Res<ActiveCity>,
+    trips: Res<TripStats>,
+    people: Query<(), With<Person>>,
+) {
+    if !keys.just_pressed(KeyCode::F8) {
+        return;
+    }
+    let report = StatsReport::gather(city.as_ref(), people.iter().count(), &trips);
+    match fs::write(STATS_EXPORT_FILE, report.to_csv()) {
+        Ok(()) => eprintln!("exported stats to {}", STATS_EXPORT_FILE),
+        Err(e) => eprintln!("couldn't export stats to {}: {}", STATS_EXPORT_FILE, e),
+    }
+}
+
+/// RGBA pixels for a texture spanning `(across, deep)` cells, white with a dark line along each
+/// cell boundary.  Cells are centred on whole numbers, so the first boundary is half a cell in.
+fn grid_texture_pixels((across, deep): (usize, usize), pixels_per_cell: usize) -> Vec<u8> {
+    let (width, height) = (across * pixels_per_cell, deep * pixels_per_cell);
+    let half_cell = pixels_per_cell / 2;
+    let on_line = |px: usize| px % pixels_per_cell == half_cell;
+
+    let mut pixels = Vec::with_capacity(width * height * 4);
+    for y in 0..height {
+        for x in 0..width {
+            let shade = if on_line(x) || on_line(y) { 90 } else { 255 };
+            pixels.extend([shade, shade, shade, 255]);
+        }
+    }
+    pixels
+}
+
+#[derive(Resource)]
+struct GroundGridTexture(Handle<Image>);
+
+fn create_ground_grid_texture(
+    city: Res<ActiveCity>,
+    mut images: ResMut<Assets<Image>>,
+    mut commands: Commands,
+) {
+    let cells = city.ground_cells();
+    let mut image = Image::new(
+        Extent3d {
+            width: (cells.0 * GRID_TEXTURE_PIXELS_PER_CELL) as u32,
+            height: (cells.1 * GRID_TEXTURE_PIXELS_PER_CELL) as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        grid_texture_pixels(cells, GRID_TEXTURE_PIXELS_PER_CELL),
+        TextureFormat::Rgba8UnormSrgb,
+    );
+    // keep the lines crisp
+    image.sampler_descriptor = ImageSampler::nearest();
+    commands.insert_resource(GroundGridTexture(images.add(image)));
+}
+
+fn apply_ground_grid_texture(
+    options: Res<Options>,
+    texture: Res<GroundGridTexture>,
+    ground_query: Query<&Handle<StandardMaterial>, With<Ground>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    if !options.is_changed() {
+        return;
+    }
+
+    let ground = materials.get_mut(ground_query.single()).unwrap();
+    ground.base_color_texture = options.grid_texture.then(|| texture.0.clone());
+}
+
+fn draw_blueprint(
+    city: Res<ActiveCity>,
+    options: Res<Options>,
+    mut building_query: Query<&mut Visibility, With<Building>>,
+    ground_query: Query<&Handle<StandardMaterial>, With<Ground>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut gizmos: Gizmos,
+) {
+    if options.is_changed() {
+        let ground = materials.get_mut(ground_query.single()).unwrap();
+        ground.base_color = if options.blueprint {
+            BLUEPRINT_GROUND_COLOR
+        } else {
+            GROUND_COLOR
+        };
+    }
+
+    // check every frame to catch newly added buildings
+    let visibility = if options.blueprint {
+        Visibility::Hidden
+    } else {
+        Visibility::Inherited
+    };
+    for mut building_visibility in &mut building_query {
+        if *building_visibility != visibility {
+            *building_visibility = visibility;
+        }
+    }
+
+    if options.blueprint {
+        for (coords, height) in city.distinct_buildings() {
+            let footprint = city.footprint_at(coords);
+            for (start, end) in building_outline_edges(&city, coords, footprint, height) {
+                gizmos.line(start, end, BLUEPRINT_LINE_COLOR);
+            }
+        }
+    }
+}
+
+/// Where to hang a building's label: the middle of its roof, up on whatever terrain it stands on.
+fn building_top(city: &City, coords: GridCoords, height: Height) -> Vec3 {
+    city.coords_to_world(coords, height as f32)
+}
+
+/// A UI label showing a building's height, kept over the building on screen.
+#[derive(Component)]
+struct HeightLabel {
+    building: Entity,
+}
+
+fn update_height_labels(
+    options: Res<Options>,
+    city: Res<ActiveCity>,
+    camera_query: Query<(&Camera, &GlobalTransform), Without<Minimap>>,
+    building_query: Query<(Entity, &GridCoords, &Building)>,
+    mut label_query: Query<(Entity, &HeightLabel, &mut Text, &mut Style)>,
+    mut commands: Commands,
+) {
+    if !options.show_height_labels {
+        for (label, ..) in &label_query {
+            commands.entity(label).despawn();
+        }
+        return;
+    }
+
+    let (camera, camera_gtx) = camera_query.single();
+    let mut labelled = HashSet::new();
+    for (label, &HeightLabel { building }, mut text, mut style) in &mut label_query {
+        let Ok((_, &coords, &Building { height, .. })) = building_query.get(building) else {
+            commands.entity(label).despawn();
+            continue;
+        };
+        labelled.insert(building);
+
+        let height_text = height.to_string();
+        if text.sections[0].value != height_text {
+            text.sections[0].value = height_text;
+        }
+        if let Some(screen) =
+            camera.world_to_viewport(camera_gtx, building_top(&city, coords, height))
+        {
+            // just above and centred-ish on the roof
+            style.left = Val::Px(screen.x - 4.0);
+            style.top = Val::Px(screen.y - 20.0);
+        }
+    }
+
+    for (building, _, &Building { height, .. }) in &building_query {
+        if labelled.contains(&building) {
+            continue;
+        }
+        commands
+            .spawn(
+                TextBundle::from_section(
+                    height.to_string(),
+                    TextStyle {
+                        font_size: 16.0,
+                        color: Color::WHITE,
+                        ..default()
+                    },
+                )
+                .with_style(Style {
+                    position_type: PositionType::Absolute,
+                    ..default()
+                }),
+            )
+            .insert(HeightLabel { building });
+    }
+}
+
+/// how long a flashed message stays up
+const FLASH_MESSAGE_TIME: Duration = Duration::from_secs(2);
+
+/// A message shown briefly in the middle of the screen.
+#[derive(Default, Resource)]
+struct FlashMessage {
+    message: Option<(String, Timer)>,
+}
+
+impl FlashMessage {
+    fn show(&mut self, text: String) {
+        self.message = Some((text, Timer::new(FLASH_MESSAGE_TIME, TimerMode::Once)));
+    }
+}
+
+#[derive(Component)]
+struct FlashMessageText;
+
+#[derive(Component)]
+struct HudText;
+
+fn spawn_hud(mut commands: Commands) {
+    commands
+        .spawn(
+            TextBundle::from_section(
+                "",
+                TextStyle {
+                    font_size: 18.0,
+                    color: Color::WHITE,
+                    ..default()
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                left: Val::Px(10.0),
+                top: Val::Px(10.0),
+                ..default()
+            }),
+        )
+        .insert(HudText);
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+fn hud_text(buildings: usize, people: usize, visitors: u32, options: &Options) -> String {
+    format!(
+        "buildings: {}\npeople: {}\nvisitor trips: {}\npaths: {}\nselection: {}",
+        buildings,
+        people,
+        visitors,
+        on_off(options.draw_paths),
+        on_off(options.draw_selection)
+    )
+}
+
+fn update_hud_text(
+    options: Res<Options>,
+    city: Res<ActiveCity>,
+    visitors: Res<VisitorScore>,
+    people: Query<(), With<Person>>,
+    mut hud_query: Query<(&mut Text, &mut Visibility), With<HudText>>,
+) {
+    let Ok((mut text, mut visibility)) = hud_query.get_single_mut() else {
+        return;
+    };
+    let wanted = if options.show_hud {
+        Visibility::Inherited
+    } else {
+        Visibility::Hidden
+    };
+    if *visibility != wanted {
+        *visibility = wanted;
+    }
+    if !options.show_hud {
+        return;
+    }
+
+    let shown = hud_text(
+        city.building_count(),
+        people.iter().len(),
+        visitors.get(),
+        &options,
+    );
+    if text.sections[0].value != shown {
+        text.sections[0].value = shown;
+    }
+}
+
+/// Describes the building under the cursor while the selection is being drawn.
+#[derive(Component)]
+struct InspectorText;
+
+fn spawn_inspector(mut commands: Commands) {
+    commands
+        .spawn(
+            TextBundle::from_section(
+                "",
+                TextStyle {
+                    font_size: 18.0,
+                    color: Color::WHITE,
+                    ..default()
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                left: Val::Px(10.0),
+                bottom: Val::Px(10.0),
+                ..default()
+            })
+            .with_background_color(Color::rgba(0.0, 0.0, 0.0, 0.6)),
+        )
+        .insert((InspectorText, Visibility::Hidden));
+}
+
+fn inspector_text(building: Option<&Building>) -> String {
+    match building {
+        Some(building) => format!("{}, height {}", building.kind.name(), building.height),
+        None => "empty".to_string(),
+    }
+}
+
+fn update_inspector(
+    options: Res<Options>,
+    cursor_query: Query<&GridCoords, With<Cursor>>,
+    index: Res<BuildingIndex>,
+    building_query: Query<Ref<Building>>,
+    mut inspector_query: Query<(&mut Text, &mut Visibility), With<InspectorText>>,
+    mut last_hovered: Local<(Option<GridCoords>, Option<Entity>)>,
+) {
+    let Ok((mut text, mut visibility)) = inspector_query.get_single_mut() else {
+        return;
+    };
+    let hovered = cursor_query.get_single().ok().copied();
+    let entity = hovered.and_then(|coords| index.get(coords));
+    let building = entity.and_then(|entity| building_query.get(entity).ok());
+    // only bother when there's something new to say
+    let building_changed = building
+        .as_ref()
+        .is_some_and(|building| building.is_changed());
+    if (hovered, entity) == *last_hovered && !building_changed && !options.is_changed() {
+        return;
+    }
+    *last_hovered = (hovered, entity);
+
+    *visibility = if options.draw_selection && hovered.is_some() {
+        Visibility::Inherited
+    } else {
+        Visibility::Hidden
+    };
+    text.sections[0].value = inspector_text(building.as_deref());
+}
+
+fn spawn_flash_message(mut commands: Commands) {
+    commands
+        .spawn(NodeBundle {
+            style: Style {
+                width: Val::Percent(100.0),
+                position_type: PositionType::Absolute,
+                top: Val::Percent(40.0),
+                justify_content: JustifyContent::Center,
+                ..default()
+            },
+            ..default()
+        })
+        .with_children(|parent| {
+            parent
+                .spawn(TextBundle::from_section(
+                    "",
+                    TextStyle {
+                        font_size: 24.0,
+                        color: Color::WHITE,
+                        ..default()
+                    },
+                ))
+                .insert(FlashMessageText);
+        });
+}
+
+fn show_flash_message(
+    time: Res<Time>,
+    mut flash: ResMut<FlashMessage>,
+    mut text_query: Query<&mut Text, With<FlashMessageText>>,
+) {
+    let Ok(mut text) = text_query.get_single_mut() else {
+        return;
+    };
+    if let Some((_, timer)) = &mut flash.message {
+        if timer.tick(time.delta()).finished() {
+            flash.message = None;
+        }
+    }
+    let shown = flash
+        .message
+        .as_ref()
+        .map_or("", |(message, _)| message.as_str());
+    if text.sections[0].value != shown {
+        text.sections[0].value = shown.to_string();
+    }
+}
+
+/// How long the cursor has rested on the cell it's hovering, so the tooltip doesn't flicker as
+/// the cursor sweeps across cell boundaries.
+#[derive(Resource)]
+struct HoverDwell {
+    cell: Option<GridCoords>,
+    timer: Timer,
+}
+
+impl HoverDwell {
+    fn new(delay: Duration) -> Self {
+        Self {
+            cell: None,
+            timer: Timer::new(delay, TimerMode::Once),
+        }
+    }
+
+    /// Note which cell is hovered this frame, returning whether its tooltip should be shown.
+    fn hover(&mut self, cell: Option<GridCoords>, delta: Duration) -> bool {
+        if cell != self.cell {
+            self.cell = cell;
+            self.timer.reset();
+            return false;
+        }
+        self.timer.tick(delta);
+        self.cell.is_some() && self.timer.finished()
+    }
+}
+
+fn tile_description(city: &City, coords: GridCoords) -> Option<String> {
+    let elevation = city.elevation_at_coords(coords)?;
+    let contents = match city.height_at_coords(coords) {
+        Some(height) => format!("building, height {height}"),
+        None if city.roads_iter().any(|road| road == coords) => "road".to_string(),
+        None => "empty".to_string(),
+    };
+    Some(format!(
+        "({}, {}) {contents}, elevation {elevation}",
+        coords.x, coords.y
+    ))
+}
+
+#[derive(Component)]
+struct TileTooltip;
+
+fn spawn_tile_tooltip(mut commands: Commands, options: Res<Options>) {
+    commands.insert_resource(HoverDwell::new(options.tooltip_delay));
+    commands
+        .spawn(
+            TextBundle::from_section(
+                "",
+                TextStyle {
+                    font_size: 16.0,
+                    color: Color::WHITE,
+                    ..default()
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                ..default()
+            })
+            .with_background_color(Color::rgba(0.0, 0.0, 0.0, 0.6)),
+        )
+        .insert(TileTooltip)
+        .insert(Visibility::Hidden);
+}
+
+#[allow(clippy::too_many_arguments)]
+fn update_tile_tooltip(
+    time: Res<Time>,
+    options: Res<Options>,
+    city: Res<ActiveCity>,
+    mut dwell: ResMut<HoverDwell>,
+    cursor_query: Query<&GridCoords, With<Cursor>>,
+    camera_query: Query<&Camera, Without<Minimap>>,
+    window_query: Query<&Window>,
+    primary_query: Query<Entity, With<PrimaryWindow>>,
+    mut tooltip_query: Query<(&mut Text, &mut Style, &mut Visibility), With<TileTooltip>>,
+) {
+    if options.is_changed() {
+        dwell.timer.set_duration(options.tooltip_delay);
+    }
+
+    let Ok(camera) = camera_query.get_single() else {
+        return;
+    };
+    let window = camera_window(camera, &window_query, primary_query.get_single().ok());
+    let hovered = if options.show_tooltip {
+        cursor_query
+            .get_single()
+            .ok()
+            .copied()
+            .filter(|&grid| city.coords_to_index(grid).is_some())
+    } else {
+        None
+    };
+
+    let show = dwell.hover(hovered, time.delta());
+    let (mut text, mut style, mut visibility) = tooltip_query.single_mut();
+    let wanted = if show {
+        Visibility::Inherited
+    } else {
+        Visibility::Hidden
+    };
+    if *visibility != wanted {
+        *visibility = wanted;
+    }
+    if !show || !dwell.timer.just_finished() && !city.is_changed() {
+        return;
+    }
+
+    if let Some(description) = hovered.and_then(|grid| tile_description(&city, grid)) {
+        text.sections[0].value = description;
+    }
+    if let Some(cursor) = window.and_then(Window::cursor_position) {
+        style.left = Val::Px(cursor.x + 12.0);
+        style.top = Val::Px(cursor.y + 12.0);
+    }
+}
+
+#[derive(Component)]
+struct HeightHistogram;
+
+#[derive(Component)]
+struct HeightHistogramBar(Height);
+
+fn spawn_height_histogram(mut commands: Commands) {
+    commands
+        .spawn(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                left: Val::Px(10.0),
+                bottom: Val::Px(10.0),
+                align_items: AlignItems::FlexEnd,
+                column_gap: Val::Px(4.0),
+                ..default()
+            },
+            visibility: Visibility::Hidden,
+            ..default()
+        })
+        .insert(HeightHistogram)
+        .with_children(|histogram| {
+            for height in 1..=MAX_BUILDING_HEIGHT {
+                histogram
+                    .spawn(NodeBundle {
+                        style: Style {
+                            flex_direction: FlexDirection::Column,
+                            align_items: AlignItems::Center,
+                            ..default()
+                        },
+                        ..default()
+                    })
+                    .with_children(|column| {
+                        column
+                            .spawn(NodeBundle {
+                                style: Style {
+                                    width: Val::Px(12.0),
+                                    height: Val::Px(0.0),
+                                    ..default()
+                                },
+                                background_color: BUILDING_COLOR.into(),
+                                ..default()
+                            })
+                            .insert(HeightHistogramBar(height));
+                        column.spawn(TextBundle::from_section(
+                            height.to_string(),
+                            TextStyle {
+                                font_size: 14.0,
+                                color: Color::WHITE,
+                                ..default()
+                            },
+                        ));
+                    });
+            }
+        });
+}
+
+fn update_height_histogram(
+    city: Res<ActiveCity>,
+    options: Res<Options>,
+    mut histogram_query: Query<&mut Visibility, With<HeightHistogram>>,
+    mut bar_query: Query<(&mut Style, &HeightHistogramBar)>,
+) {
+    if options.is_changed() {
+        let mut visibility = histogram_query.single_mut();
+        *visibility = if options.show_histogram {
+            Visibility::Inherited
+        } else {
+            Visibility::Hidden
+        };
+    }
+
+    if city.is_changed() {
+        let histogram = city.height_histogram();
+        for (mut style, &HeightHistogramBar(height)) in &mut bar_query {
+            let count = histogram[(height - 1) as usize];
+            style.height = Val::Px(count as f32 * HISTOGRAM_BAR_SCALE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STARTING_CITY: [Height; 25] = [
+        0, 0, 0, 0, 0, //
+        0, 0, 0, 0, 0, //
+        0, 0, 0, 0, 0, //
+        0, 0, 0, 0, 0, //
+        0, 0, 0, 0, 0, //
+    ];
+
+    #[test]
+    #[allow(clippy::useless_vec)]
+    fn test_grid_coords_roundtrip() {
+        for grid in vec![
+            GridCoords::ORIGIN,
+            GridCoords::new(1, 2),
+            GridCoords::new(-1, 0),
+            GridCoords::new(-2, -3),
+        ] {
+            let world = grid.to_world(0.5, WorldScale::default());
+            assert_eq!(grid, GridCoords::from_world(world, WorldScale::default()));
+        }
+
+        for world in vec![
+            Vec3::ZERO + 0.5 * Vec3::Y,
+            Vec3::X + 0.5 * Vec3::Y,
+            Vec3::Z + 0.5 * Vec3::Y,
+            Vec3::new(-4.0, 0.5, -1.0),
+            Vec3::new(4.0, -1.5, -1.0),
+        ] {
+            let grid = GridCoords::from_world(world, WorldScale::default());
+            assert_eq!(world, grid.to_world(world.y, WorldScale::default()));
+        }
+    }
+
+    #[test]
+    fn test_grid_from_world() {
+        for (world, grid) in vec![
+            (Vec3::ZERO, GridCoords::ORIGIN),
+            (Vec3::X, GridCoords::new(1, 0)),
+            (-Vec3::X, GridCoords::new(-1, 0)),
+            (Vec3::Z, GridCoords::new(0, 1)),
+            (-Vec3::Z, GridCoords::new(0, -1)),
+            // round
+            (Vec3::new(0.2, 0.0, 0.2), GridCoords::ORIGIN),
+            (Vec3::new(0.8, 0.0, 0.8), GridCoords::new(1, 1)),
+            (Vec3::new(-0.2, 0.0, -0.2), GridCoords::ORIGIN),
+            (Vec3::new(-0.8, 0.0, -0.8), GridCoords::new(-1, -1)),
+            // round away from zero
+            (Vec3::new(0.5, 0.0, 0.5), GridCoords::new(1, 1)),
+            (Vec3::new(-0.5, 0.0, -0.5), GridCoords::new(-1, -1)),
+            // all points in Y column map to the same grid coords
+            (Vec3::new(0.8, 1000.0, 0.8), GridCoords::new(1, 1)),
+        ] {
+            assert_eq!(
+                grid,
+                GridCoords::from_world(world, WorldScale::default()),
+                "{}",
+                world
+            );
+        }
+    }
+
+    #[test]
+    fn test_pick_building_through_tall_building() {
+        let mut city = City::new(STARTING_CITY);
+        city.set_height_at_coords(GridCoords::ORIGIN, Some(5));
+
+        // from roughly where the camera sits, aim at the middle of the building's body
+        let origin = Vec3::new(4.0, 5.0, 5.0);
+        let ray = Ray {
+            origin,
+            direction: (Vec3::new(0.0, 2.5, 0.0) - origin).normalize(),
+        };
+
+        // the ground behind the building is a different (off-grid) cell
+        let ground_distance = ray.intersect_plane(Vec3::ZERO, Vec3::Y).unwrap();
+        let ground_grid =
+            GridCoords::from_world(ray.get_point(ground_distance), WorldScale::default());
+        assert_ne!(GridCoords::ORIGIN, ground_grid);
+
+        let (grid, point) = city.pick_building(ray, 0.0).unwrap();
+        assert_eq!(GridCoords::ORIGIN, grid);
+        assert!(point.y > 0.0 && point.y <= 5.0, "{}", point);
+    }
+
+    #[test]
+    fn test_pick_building_margin() {
+        let mut city = City::new(STARTING_CITY);
+        city.set_height_at_coords(GridCoords::ORIGIN, Some(3));
+
+        // straight down, just outside the building's footprint
+        let ray = Ray {
+            origin: Vec3::new(0.6, 10.0, 0.0),
+            direction: -Vec3::Y,
+        };
+
+        assert_eq!(None, city.pick_building(ray, 0.0));
+        let (grid, point) = city.pick_building(ray, 0.2).unwrap();
+        assert_eq!(GridCoords::ORIGIN, grid);
+        assert!((point.y - 3.2).abs() < 1e-5, "{}", point);
+    }
+
+    #[test]
+    fn test_road_route_connects_buildings() {
+        let mut city = City::new([
+            0, 0, 0, 0, 0, //
+            0, 1, 0, 0, 0, //
+            0, 1, 1, 1, 0, //
+            0, 0, 0, 1, 0, //
+            0, 0, 0, 0, 0, //
+        ]);
+        let from = GridCoords::new(-1, -1);
+        let to = GridCoords::new(1, 1);
+        // block off the short way round between them
+        city.set_height_at_coords(GridCoords::new(0, -1), Some(1));
+
+        let route = city.road_route(from, to).unwrap();
+
+        assert!(route
+            .iter()
+            .all(|&coords| city.height_at_coords(coords).is_none()));
+        for pair in route.windows(2) {
+            assert_eq!(1, pair[0].manhattan_dist(pair[1]), "{:?}", route);
+        }
+        let start = *route.first().unwrap();
+        let end = *route.last().unwrap();
+        assert_eq!(1, start.manhattan_dist(from), "{:?}", route);
+        assert_eq!(1, end.manhattan_dist(to), "{:?}", route);
+
+        for &coords in &route {
+            city.set_road_at_coords(coords, true);
+        }
+        assert_eq!(route.len(), city.roads_iter().count());
+    }
+
+    #[test]
+    fn test_road_route_unreachable() {
+        let mut city = City::new(STARTING_CITY);
+        city.set_height_at_coords(GridCoords::new(-2, -2), Some(1));
+        city.set_height_at_coords(GridCoords::new(2, 2), Some(1));
+        // wall off the corner building
+        city.set_height_at_coords(GridCoords::new(-1, -2), Some(1));
+        city.set_height_at_coords(GridCoords::new(-2, -1), Some(1));
+
+        assert_eq!(
+            None,
+            city.road_route(GridCoords::new(-2, -2), GridCoords::new(2, 2))
+        );
+    }
+
+    #[test]
+    fn test_replay_reproduces_recorded_positions() {
+        let mut world = World::new();
+        world.insert_resource(ReplayBuffer::new(10));
+        let people: Vec<_> = (0..3)
+            .map(|i| {
+                world
+                    .spawn((Transform::from_xyz(i as f32, 0.0, 0.0), Person::default()))
+                    .id()
+            })
+            .collect();
+
+        let mut record = Schedule::default();
+        record.add_systems(record_replay);
+        let mut play = Schedule::default();
+        play.add_systems(play_replay);
+
+        let mut recorded = vec![];
+        for tick in 0..5 {
+            for &person in &people {
+                world.get_mut::<Transform>(person).unwrap().translation.z = tick as f32;
+            }
+            record.run(&mut world);
+            recorded.push(
+                people
+                    .iter()
+                    .map(|&person| world.get::<Transform>(person).unwrap().translation)
+                    .collect::<Vec<_>>(),
+            );
+        }
+
+        world.resource_mut::<ReplayBuffer>().start_playback();
+        for expected in recorded {
+            play.run(&mut world);
+            let replayed: Vec<_> = people
+                .iter()
+                .map(|&person| world.get::<Transform>(person).unwrap().translation)
+                .collect();
+            assert_eq!(expected, replayed);
+        }
+
+        // playback stops once it runs out of frames
+        play.run(&mut world);
+        assert!(!world.resource::<ReplayBuffer>().is_playing());
+    }
+
+    #[test]
+    fn test_replay_buffer_capacity() {
+        let mut replay = ReplayBuffer::new(2);
+        for _ in 0..3 {
+            replay.record(ReplayFrame { people: vec![] });
+        }
+        assert_eq!(2, replay.frames.len());
+    }
+
+    #[test]
+    fn test_path_prefers_flatter_route() {
+        let mut city = City::new([
+            0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, //
+            0, 0, 1, 0, 0, //
+            0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, //
+        ]);
+        // the way round to the left of the building is hilly
+        city.set_elevation_at_coords(GridCoords::new(-1, 0), 1);
+
+        let from = GridCoords::new(0, -1);
+        let to = GridCoords::new(0, 1);
+        let path = a_star_search(
+            city.coords_to_index(from).unwrap(),
+            city.coords_to_index(to).unwrap(),
+            &city,
+        );
+
+        assert!(path.success);
+        let route: Vec<_> = path
+            .steps
+            .iter()
+            .map(|&step| city.index_to_coords(step))
+            .collect();
+        assert_eq!(5, route.len(), "{:?}", route);
+        assert!(route.contains(&GridCoords::new(1, 0)), "{:?}", route);
+    }
+
+    #[test]
+    fn test_exits_blocked_by_steep_slope() {
+        let mut city = City::new(STARTING_CITY);
+        city.set_elevation_at_coords(GridCoords::new(0, 1), 1);
+        city.set_elevation_at_coords(GridCoords::new(0, -1), 2);
+
+        let exits = city.get_available_exits(city.coords_to_index(GridCoords::ORIGIN).unwrap());
+
+        let up = city.coords_to_index(GridCoords::new(0, 1)).unwrap();
+        let down = city.coords_to_index(GridCoords::new(0, -1)).unwrap();
+        assert!(exits.contains(&(up, 1.0 + UPHILL_COST)));
+        assert!(!exits.iter().any(|&(idx, _)| idx == down));
+    }
+
+    #[test]
+    fn test_tick_budget_warning() {
+        let mut timing = WalkTiming {
+            last: Duration::from_millis(3),
+            ..default()
+        };
+        assert!(!timing.should_warn(4.0, Duration::ZERO));
+
+        timing.last = Duration::from_millis(5);
+        assert!(timing.should_warn(4.0, Duration::ZERO));
+        // rate limited
+        assert!(!timing.should_warn(4.0, Duration::from_millis(500)));
+        assert!(timing.should_warn(4.0, Duration::from_millis(1500)));
+
+        timing.last = Duration::from_millis(3);
+        assert!(!timing.should_warn(4.0, Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_derelict_buildings() {
+        let quiet = GridCoords::new(-2, -2);
+        let busy = GridCoords::new(2, 2);
+
+        let mut traffic = TrafficMap::default();
+        traffic.record_visit(quiet.up());
+        for _ in 0..5 {
+            traffic.record_visit(busy.left());
+        }
+
+        assert!(traffic.is_derelict(quiet.neighbors(), 3));
+        assert!(!traffic.is_derelict(busy.neighbors(), 3));
+
+        traffic.clear();
+        assert!(traffic.is_derelict(busy.neighbors(), 3));
+
+        // a block is judged by the traffic past any side of it
+        let mut city = City::new(STARTING_CITY);
+        assert!(city.place_building(GridCoords::ORIGIN, Footprint::TwoByTwo, None));
+        for _ in 0..5 {
+            traffic.record_visit(GridCoords::new(2, 1));
+        }
+        assert!(!traffic.is_derelict(city.building_neighbors(GridCoords::ORIGIN), 3));
+        assert!(traffic.is_derelict(GridCoords::ORIGIN.neighbors(), 3));
+    }
+
+    #[test]
+    fn test_camera_speed_multiplier() {
+        let mut keys = Input::<KeyCode>::default();
+        assert_eq!(1.0, camera_speed_multiplier(&keys));
+
+        keys.press(KeyCode::ShiftLeft);
+        assert_eq!(CAMERA_FINE_MULTIPLIER, camera_speed_multiplier(&keys));
+
+        keys.release(KeyCode::ShiftLeft);
+        keys.press(KeyCode::ControlRight);
+        assert_eq!(CAMERA_COARSE_MULTIPLIER, camera_speed_multiplier(&keys));
+
+        // fine wins if both are held
+        keys.press(KeyCode::ShiftRight);
+        assert_eq!(CAMERA_FINE_MULTIPLIER, camera_speed_multiplier(&keys));
+    }
+
+    #[test]
+    fn test_city_try_new() {
+        let city = City::try_new(vec![0; 6], 3, 2).unwrap();
+        assert_eq!((3, 2), (city.x_len, city.y_len));
+
+        assert_eq!(
+            Err(CityError::DimensionMismatch {
+                x_len: 3,
+                y_len: 3,
+                cells: 6
+            }),
+            City::try_new(vec![0; 6], 3, 3).map(|_| ())
+        );
+        assert_eq!(
+            Err(CityError::Degenerate { x_len: 0, y_len: 6 }),
+            City::try_new(vec![0; 6], 0, 6).map(|_| ())
+        );
+        assert_eq!(
+            Err(CityError::Degenerate { x_len: 0, y_len: 0 }),
+            City::try_new(vec![], 0, 0).map(|_| ())
+        );
+        assert_eq!(
+            Err(CityError::TooLarge {
+                x_len: 200,
+                y_len: 1
+            }),
+            City::try_new(vec![0; 200], 200, 1).map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_camera_looks_at_focus() {
+        let mut world = World::new();
+        world.init_resource::<CameraFocus>();
+        let camera = world
+            .spawn((Camera::default(), Transform::from_xyz(4.0, 5.0, 5.0)))
+            .id();
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems(look_at_camera_focus);
+
+        let looking_at = |world: &World, target: Vec3| {
+            let tx = world.get::<Transform>(camera).unwrap();
+            let towards = (target - tx.translation).normalize();
+            tx.forward().dot(towards) > 0.9999
+        };
+
+        schedule.run(&mut world);
+        assert!(looking_at(&world, Vec3::ZERO));
+
+        world.resource_mut::<CameraFocus>().0 = Vec3::new(2.0, 0.0, -1.0);
+        schedule.run(&mut world);
+        assert!(!looking_at(&world, Vec3::ZERO));
+        assert!(looking_at(&world, Vec3::new(2.0, 0.0, -1.0)));
+    }
+
+    #[test]
+    fn test_height_histogram() {
+        assert_eq!([0; 10], City::new(STARTING_CITY).height_histogram());
+
+        let city = City::new([
+            1, 0, 0, 0, 0, //
+            0, 3, 0, 0, 0, //
+            0, 0, 1, 0, 0, //
+            0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 12, //
+        ]);
+        assert_eq!([2, 0, 1, 0, 0, 0, 0, 0, 0, 1], city.height_histogram());
+
+        // a block is one building, not one per cell
+        let mut city = City::new(STARTING_CITY);
+        assert!(city.place_building(GridCoords::ORIGIN, Footprint::TwoByTwo, None));
+        city.add_storey(GridCoords::new(1, 1), None);
+        assert_eq!([0, 1, 0, 0, 0, 0, 0, 0, 0, 0], city.height_histogram());
+    }
+
+    #[test]
+    fn test_building_outline_edges() {
+        let edges = building_outline_edges(
+            &City::new(STARTING_CITY),
+            GridCoords::new(1, -1),
+            Footprint::OneByOne,
+            3,
+        );
+
+        let mut corners: Vec<_> = edges
+            .iter()
+            .flat_map(|&(start, end)| [start, end])
+            .collect();
+        corners.sort_by(|a, b| a.to_array().partial_cmp(&b.to_array()).unwrap());
+        corners.dedup();
+        assert_eq!(8, corners.len(), "{:?}", corners);
+        for corner in &corners {
+            assert!([0.5, 1.5].contains(&corner.x), "{}", corner);
+            assert!([0.0, 3.0].contains(&corner.y), "{}", corner);
+            assert!([-1.5, -0.5].contains(&corner.z), "{}", corner);
+        }
+
+        // every edge runs along exactly one axis, a whole cell or the full height long
+        for (start, end) in edges {
+            let length = (end - start).abs();
+            let axes = length.to_array().iter().filter(|&&l| l > 0.0).count();
+            assert_eq!(1, axes, "{} -> {}", start, end);
+            assert!(
+                [1.0, 3.0].contains(&length.max_element()),
+                "{} -> {}",
+                start,
+                end
+            );
+        }
+        let vertical = edges.iter().filter(|(start, end)| start.y != end.y).count();
+        assert_eq!(4, vertical);
+    }
+
+    #[test]
+    fn test_cooperative_paths_through_chokepoint() {
+        // two ways through the wall, but the gap on the left is quicker for both
+        let city = City::new([
+            0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, //
+            1, 0, 1, 0, 1, //
+            0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, //
+        ]);
+        let mut world = World::new();
+        let alice = world.spawn_empty().id();
+        let bob = world.spawn_empty().id();
+        let idx = |x, y| city.coords_to_index(GridCoords::new(x, y)).unwrap();
+
+        let mut reservations = Reservations::default();
+        let alice_path = cooperative_a_star(&city, &reservations, alice, idx(-1, -2), idx(-1, 2));
+        assert!(alice_path.success);
+        reservations.reserve(alice, &alice_path.steps);
+
+        // bob is coming the other way through the same gap at the same time
+        let bob_path = cooperative_a_star(&city, &reservations, bob, idx(-1, 2), idx(-1, -2));
+        assert!(bob_path.success);
+        reservations.reserve(bob, &bob_path.steps);
+
+        for (time, (&a, &b)) in alice_path.steps.iter().zip(&bob_path.steps).enumerate() {
+            assert_ne!(a, b, "both in the same cell at time {}", time);
+        }
+        for time in 0..alice_path.steps.len().min(bob_path.steps.len()) - 1 {
+            let swapped = alice_path.steps[time] == bob_path.steps[time + 1]
+                && alice_path.steps[time + 1] == bob_path.steps[time];
+            assert!(!swapped, "swapped places at time {}", time);
+        }
+        // the naive plans would have collided
+        let naive = a_star_search(idx(-1, 2), idx(-1, -2), &city);
+        assert_ne!(naive.steps, bob_path.steps);
+    }
+
+    #[test]
+    fn test_reservations_expire() {
+        let mut world = World::new();
+        let alice = world.spawn_empty().id();
+        let bob = world.spawn_empty().id();
+
+        let mut reservations = Reservations::default();
+        reservations.reserve(alice, &[3, 4]);
+        assert!(!reservations.is_free(3, 0, bob));
+        assert!(reservations.is_free(3, 0, alice));
+
+        reservations.advance();
+        assert!(reservations.is_free(3, 0, bob));
+        assert!(!reservations.is_free(4, 1, bob));
+
+        reservations.release(alice);
+        assert!(reservations.is_free(4, 1, bob));
+    }
+
+    #[test]
+    fn test_light_stays_put_when_not_animated() {
+        let mut world = World::new();
+        world.init_resource::<Time>();
+        world.insert_resource(ActiveCity::new(STARTING_CITY));
+        world.insert_resource(Options {
+            animate_light: false,
+            ..default()
+        });
+        world.init_resource::<TimeOfDay>();
+        let start = Transform::from_xyz(1.0, 8.0, 2.0);
+        let light = world.spawn((PointLight::default(), start)).id();
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems(move_light);
+
+        schedule.run(&mut world);
+        assert_eq!(start, *world.get::<Transform>(light).unwrap());
+
+        world.resource_mut::<Options>().animate_light = true;
+        schedule.run(&mut world);
+        assert_ne!(start, *world.get::<Transform>(light).unwrap());
+    }
+
+    #[test]
+    fn test_generate_city() {
+        let generate = |seed| City::generate((5, 5), &mut ChaCha8Rng::seed_from_u64(seed), 0.3, 4);
+
+        for seed in 0..20 {
+            let city = generate(seed);
+            assert_eq!(city.heights, generate(seed).heights);
+
+            let count = city.buildings_iter().count();
+            assert!((2..=14).contains(&count), "{} buildings", count);
+            assert!(city.buildings_iter().all(|(_, height)| height <= 4));
+        }
+
+        // nothing, or everything
+        assert_eq!(
+            0,
+            City::generate((5, 5), &mut ChaCha8Rng::seed_from_u64(0), 0.0, 4)
+                .buildings_iter()
+                .count()
+        );
+        assert_eq!(
+            25,
+            City::generate((5, 5), &mut ChaCha8Rng::seed_from_u64(0), 1.0, 4)
+                .buildings_iter()
+                .count()
+        );
+    }
+
+    #[test]
+    fn test_largest_walkable_region() {
+        let city = City::new([
+            0, 1, 0, 0, 0, //
+            1, 1, 0, 0, 0, //
+            0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, //
+        ]);
+        assert_eq!(21, city.largest_walkable_region());
+    }
+
+    #[test]
+    fn test_paths_reset_after_city_changes() {
+        let mut world = World::new();
+        world.insert_resource(City::new(STARTING_CITY));
+        let person = world.spawn(Person::default()).id();
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems(reset_paths_after_city_changes);
+        // the city counts as changed when it's first added
+        schedule.run(&mut world);
+
+        let give_path = |world: &mut World| {
+            let mut person = world.get_mut::<Person>(person).unwrap();
+            person.path.steps = vec![12, 13, 14];
+        };
+        let path_len = |world: &World| world.get::<Person>(person).unwrap().path.steps.len();
+
+        give_path(&mut world);
+        schedule.run(&mut world);
+        assert_eq!(3, path_len(&world), "path reset without city changing");
+
+        world
+            .resource_mut::<City>()
+            .set_height_at_coords(GridCoords::new(1, 0), Some(1));
+        schedule.run(&mut world);
+        assert_eq!(0, path_len(&world));
+    }
+
+    #[test]
+    fn test_grid_neighbors() {
+        let coords = GridCoords::new(1, -1);
+
+        let neighbors: Vec<_> = coords.neighbors().collect();
+        assert_eq!(
+            vec![
+                GridCoords::new(1, 0),
+                GridCoords::new(1, -2),
+                GridCoords::new(0, -1),
+                GridCoords::new(2, -1),
+            ],
+            neighbors
+        );
+
+        let neighbors8: Vec<_> = coords.neighbors8().collect();
+        assert_eq!(8, neighbors8.len());
+        assert_eq!(&neighbors[..], &neighbors8[..4]);
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                let neighbor = GridCoords::new(coords.x + dx, coords.y + dy);
+                assert_eq!(
+                    neighbor != coords,
+                    neighbors8.contains(&neighbor),
+                    "{:?}",
+                    neighbor
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_building_top() {
+        assert_eq!(
+            Vec3::new(0.0, 1.0, 0.0),
+            building_top(&City::new(STARTING_CITY), GridCoords::ORIGIN, 1)
+        );
+        assert_eq!(
+            Vec3::new(-2.0, 7.0, 1.0),
+            building_top(&City::new(STARTING_CITY), GridCoords::new(-2, 1), 7)
+        );
+    }
+
+    #[test]
+    fn test_repulsion() {
+        let cursor = Vec3::new(1.0, 0.0, 1.0);
+
+        let push = repulsion(Vec3::new(1.5, 0.05, 1.0), cursor, 1.0, 2.0);
+        assert!(
+            (push - Vec3::new(1.0, 0.0, 0.0)).length() < 1e-5,
+            "{}",
+            push
+        );
+
+        let closer = repulsion(Vec3::new(1.0, 0.05, 0.75), cursor, 1.0, 2.0);
+        assert!(
+            (closer - Vec3::new(0.0, 0.0, -1.5)).length() < 1e-5,
+            "{}",
+            closer
+        );
+
+        assert_eq!(
+            Vec3::ZERO,
+            repulsion(Vec3::new(3.0, 0.0, 1.0), cursor, 1.0, 2.0)
+        );
+        // right on top of the cursor there's no way to tell which way to go
+        assert_eq!(Vec3::ZERO, repulsion(cursor, cursor, 1.0, 2.0));
+    }
+
+    #[test]
+    fn test_city_origin_offset_roundtrip() {
+        let offset = GridCoords::new(3, -2);
+        let city = City::new(STARTING_CITY).with_origin_offset(offset);
+
+        for idx in 0..25 {
+            let coords = city.index_to_coords(idx);
+            assert_eq!(Some(idx), city.coords_to_index(coords), "{:?}", coords);
+            assert_eq!(
+                coords,
+                GridCoords::from_world(
+                    coords.to_world(0.5, WorldScale::default()),
+                    WorldScale::default()
+                )
+            );
+        }
+
+        assert_eq!(GridCoords::new(1, -4), city.index_to_coords(0));
+        assert_eq!(Some(12), city.coords_to_index(offset));
+        // the old corner is now off the grid
+        assert_eq!(None, city.coords_to_index(GridCoords::new(-2, -2)));
+        assert_eq!(None, city.coords_to_index(GridCoords::new(6, 1)));
+        assert_eq!(Some(24), city.coords_to_index(GridCoords::new(5, 0)));
+    }
+
+    #[test]
+    fn test_save_rng_state() {
+        let mut rng = ChaCha8Rng::seed_from_u64(SIM_SEED);
+        for _ in 0..17 {
+            rng.gen::<u32>();
+        }
+
+        let ron = SaveGame {
+            rng: rng.clone(),
+            districts: vec![],
+            city: None,
+        }
+        .to_ron()
+        .unwrap();
+        let mut loaded = SaveGame::from_ron(&ron).unwrap().rng;
+
+        for _ in 0..100 {
+            assert_eq!(rng.gen::<u64>(), loaded.gen::<u64>());
+        }
+    }
+
+    #[test]
+    fn test_load_malformed_save() {
+        assert!(matches!(
+            SaveGame::from_ron("(rng: 12)"),
+            Err(SaveError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_longest_path() {
+        let mut city = City::new(STARTING_CITY);
+        city.set_elevation_at_coords(GridCoords::new(0, 1), 1);
+        let mut world = World::new();
+        let mut person_with_path = |steps: Vec<usize>| {
+            let mut person = Person::default();
+            person.path.steps = steps;
+            let entity = world.spawn_empty().id();
+            (entity, person)
+        };
+
+        let people = [
+            person_with_path(vec![0, 1, 2]),
+            // fewer steps but uphill
+            person_with_path(vec![7, 12, 17]),
+            person_with_path(vec![]),
+            person_with_path(vec![5, 6]),
+        ];
+        let uphill = people[1].0;
+        assert_eq!(
+            Some(uphill),
+            longest_path(&city, people.iter().map(|(e, p)| (*e, p)))
+        );
+
+        let people = [
+            person_with_path(vec![20, 21, 22, 23, 24]),
+            person_with_path(vec![7, 12, 17]),
+        ];
+        let longest = people[0].0;
+        assert_eq!(
+            Some(longest),
+            longest_path(&city, people.iter().map(|(e, p)| (*e, p)))
+        );
+
+        assert_eq!(None, longest_path(&city, std::iter::empty()));
+    }
+
+    #[test]
+    fn test_grid_texture_pixels() {
+        let pixels = grid_texture_pixels((3, 3), 8);
+        let side = 24;
+        assert_eq!(side * side * 4, pixels.len());
+
+        let shade = |x: usize, y: usize| pixels[(y * side + x) * 4];
+        let lines: Vec<_> = (0..side).filter(|&x| shade(x, 0) < 255).collect();
+        assert_eq!(vec![4, 12, 20], lines);
+        for x in 0..side {
+            // a horizontal line runs right across
+            assert!(shade(x, 12) < 255, "gap at {}", x);
+        }
+        assert_eq!(255, shade(0, 0));
+        assert_eq!(255, shade(7, 9));
+
+        // wider than it is deep
+        let pixels = grid_texture_pixels((3, 2), 8);
+        assert_eq!(24 * 16 * 4, pixels.len());
+    }
+
+    #[test]
+    fn test_path_coords() {
+        let city = City::new(STARTING_CITY);
+        let steps = [0, 1, 6, 11, 12];
+
+        let coords = path_coords(&city, &steps);
+
+        assert_eq!(
+            vec![
+                GridCoords::new(-2, -2),
+                GridCoords::new(-1, -2),
+                GridCoords::new(-1, -1),
+                GridCoords::new(-1, 0),
+                GridCoords::ORIGIN,
+            ],
+            coords
+        );
+        for (&step, coords) in steps.iter().zip(coords) {
+            assert_eq!(city.index_to_coords(step), coords);
+        }
+    }
+
+    #[test]
+    fn test_approach_speed() {
+        // 0.25 units/s faster each tick
+        let (acceleration, secs) = (2.5, 0.1);
+
+        let mut speed = 0.0;
+        for tick in 1..=4 {
+            speed = approach_speed(speed, PERSON_SPEED, acceleration, secs, PERSON_SPEED);
+            if tick < 4 {
+                assert!(speed < PERSON_SPEED, "full speed after {} ticks", tick);
+            }
+        }
+        assert!((speed - PERSON_SPEED).abs() < 1e-5, "{}", speed);
+
+        // no faster than full speed
+        speed = approach_speed(PERSON_SPEED, PERSON_SPEED, acceleration, secs, PERSON_SPEED);
+        assert_eq!(PERSON_SPEED, speed);
+        assert_eq!(
+            PERSON_SPEED,
+            approach_speed(0.0, 10.0, 100.0, secs, PERSON_SPEED)
+        );
+
+        // and back down again
+        for _ in 0..4 {
+            speed = approach_speed(speed, 0.0, acceleration, secs, PERSON_SPEED);
+        }
+        assert!(speed.abs() < 1e-5, "{}", speed);
+    }
+
+    #[test]
+    fn test_cycle_background() {
+        let mut world = World::new();
+        world.init_resource::<Input<KeyCode>>();
+        world.insert_resource(ClearColor(BackgroundPreset::default().color()));
+        let mut schedule = Schedule::default();
+        schedule.add_systems(cycle_background);
+
+        let mut press_x = |world: &mut World| {
+            let mut keys = world.resource_mut::<Input<KeyCode>>();
+            keys.clear();
+            keys.press(KeyCode::X);
+            schedule.run(world);
+            world.resource_mut::<Input<KeyCode>>().release(KeyCode::X);
+            world.resource::<ClearColor>().0
+        };
+
+        assert_eq!(BackgroundPreset::Night.color(), press_x(&mut world));
+        assert_eq!(BackgroundPreset::NeutralGray.color(), press_x(&mut world));
+        assert_eq!(BackgroundPreset::DaySky.color(), press_x(&mut world));
+
+        // nothing changes without the key
+        world.resource_mut::<Input<KeyCode>>().clear();
+        schedule.run(&mut world);
+        assert_eq!(
+            BackgroundPreset::DaySky.color(),
+            world.resource::<ClearColor>().0
+        );
+    }
+
+    #[test]
+    fn test_city_to_obj() {
+        let mut city = City::new(STARTING_CITY);
+        city.set_height_at_coords(GridCoords::new(1, 0), Some(1));
+
+        let obj = city_to_obj(&city);
+        let (_, building) = obj.split_once("o building_1_0\n").unwrap();
+
+        let vertices: Vec<Vec3> = building
+            .lines()
+            .filter_map(|line| line.strip_prefix("v "))
+            .map(|v| {
+                let xyz: Vec<f32> = v.split(' ').map(|n| n.parse().unwrap()).collect();
+                Vec3::from_slice(&xyz)
+            })
+            .collect();
+        assert_eq!(8, vertices.len());
+        for v in &vertices {
+            assert!([0.5, 1.5].contains(&v.x), "{}", v);
+            assert!([0.0, 1.0].contains(&v.y), "{}", v);
+            assert!([-0.5, 0.5].contains(&v.z), "{}", v);
+        }
+
+        let faces: Vec<Vec<usize>> = building
+            .lines()
+            .filter_map(|line| line.strip_prefix("f "))
+            .map(|f| f.split(' ').map(|n| n.parse().unwrap()).collect())
+            .collect();
+        assert_eq!(6, faces.len());
+        for face in &faces {
+            assert_eq!(4, face.len());
+            // after the ground's four vertices
+            assert!(face.iter().all(|&i| (5..=12).contains(&i)), "{:?}", face);
+        }
+
+        // the ground is in there too
+        assert_eq!(
+            12,
+            obj.lines().filter(|line| line.starts_with("v ")).count()
+        );
+        assert_eq!(7, obj.lines().filter(|line| line.starts_with("f ")).count());
+    }
+
+    #[test]
+    fn test_tooltip_waits_for_dwell() {
+        let mut dwell = HoverDwell::new(Duration::from_millis(300));
+        let frame = Duration::from_millis(100);
+        let a = GridCoords::new(0, 0);
+        let b = GridCoords::new(1, 0);
+
+        let shown: Vec<bool> = [
+            Some(a),
+            Some(a),
+            Some(a),
+            Some(a),
+            // moving off hides it straight away, and the delay starts again
+            Some(b),
+            Some(b),
+            Some(a),
+            Some(a),
+            Some(a),
+            Some(a),
+            Some(a),
+            None,
+            None,
+            None,
+            None,
+        ]
+        .into_iter()
+        .map(|cell| dwell.hover(cell, frame))
+        .collect();
+
+        assert_eq!(
+            [
+                false, false, false, true, false, false, false, false, false, true, true, false,
+                false, false, false,
+            ],
+            shown.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_person_lod_buckets() {
+        let thresholds = [8.0, 16.0];
+        assert_eq!(PersonLod::High, PersonLod::for_distance(0.0, thresholds));
+        assert_eq!(PersonLod::High, PersonLod::for_distance(8.0, thresholds));
+        assert_eq!(PersonLod::Medium, PersonLod::for_distance(8.5, thresholds));
+        assert_eq!(PersonLod::Medium, PersonLod::for_distance(16.0, thresholds));
+        assert_eq!(PersonLod::Low, PersonLod::for_distance(100.0, thresholds));
+    }
+
+    #[test]
+    fn test_near_people_keep_high_detail() {
+        let mut world = World::new();
+        let mesh = || Handle::weak(bevy::asset::HandleId::random::<Mesh>());
+        let person_meshes = PersonMeshes {
+            high: mesh(),
+            medium: mesh(),
+            low: mesh(),
+        };
+        let high = person_meshes.get(PersonLod::High);
+        world.insert_resource(Options::default());
+        world.insert_resource(person_meshes);
+        world.spawn((
+            Camera::default(),
+            GlobalTransform::default(),
+            Projection::Orthographic(OrthographicProjection::default()),
+        ));
+        let near = world
+            .spawn((
+                Person::default(),
+                PersonLod::High,
+                high.clone(),
+                Transform::from_xyz(1.0, 0.0, 1.0),
+            ))
+            .id();
+        let far = world
+            .spawn((
+                Person::default(),
+                PersonLod::High,
+                high.clone(),
+                Transform::from_xyz(50.0, 0.0, 0.0),
+            ))
+            .id();
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems(update_person_lod);
+        schedule.run(&mut world);
+
+        assert_eq!(PersonLod::High, *world.get::<PersonLod>(near).unwrap());
+        assert_eq!(&high, world.get::<Handle<Mesh>>(near).unwrap());
+        assert_eq!(PersonLod::Low, *world.get::<PersonLod>(far).unwrap());
+        assert_ne!(&high, world.get::<Handle<Mesh>>(far).unwrap());
+    }
+
+    #[test]
+    fn test_unreachable_goals() {
+        let mut city = City::new([0; 25]);
+        // wall off the right-hand column
+        for y in -2..=2 {
+            city.set_height_at_coords(GridCoords::new(1, y), Some(1));
+        }
+
+        let unreachable = unreachable_goals(
+            &city,
+            [
+                (GridCoords::new(-2, -2), GridCoords::new(0, 2)),
+                (GridCoords::new(-2, -2), GridCoords::new(2, 2)),
+                (GridCoords::new(2, -2), GridCoords::new(2, 2)),
+                (GridCoords::new(2, 0), GridCoords::new(-1, 0)),
+                // off the map
+                (GridCoords::new(0, 0), GridCoords::new(5, 5)),
+            ],
+        );
+
+        assert_eq!(
+            [
+                (GridCoords::new(-2, -2), GridCoords::new(2, 2)),
+                (GridCoords::new(2, 0), GridCoords::new(-1, 0)),
+                (GridCoords::new(0, 0), GridCoords::new(5, 5)),
+            ],
+            unreachable.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_sun_follows_time_of_day() {
+        let mut world = World::new();
+        world.init_resource::<Time>();
+        world.insert_resource(ActiveCity::new(STARTING_CITY));
+        world.init_resource::<Options>();
+        world.insert_resource(TimeOfDay {
+            elapsed: 30.0,
+            day_length: 120.0,
+        });
+        let sun = world
+            .spawn((DirectionalLight::default(), Transform::default()))
+            .id();
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems(move_light);
+        schedule.run(&mut world);
+
+        // noon
+        let (elevation, azimuth) = sun_angles(0.25);
+        assert_eq!(
+            sun_rotation(azimuth, elevation),
+            world.get::<Transform>(sun).unwrap().rotation
+        );
+        assert_eq!(
+            SUN_ILLUMINANCE,
+            world.get::<DirectionalLight>(sun).unwrap().illuminance
+        );
+
+        world.resource_mut::<TimeOfDay>().elapsed = 90.0;
+        schedule.run(&mut world);
+        assert_eq!(0.0, world.get::<DirectionalLight>(sun).unwrap().illuminance);
+    }
+
+    #[test]
+    fn test_sun_angles() {
+        let close = |(elevation, azimuth): (f32, f32), expected: (f32, f32)| {
+            assert!(
+                (elevation - expected.0).abs() < 1e-5 && (azimuth - expected.1).abs() < 1e-5,
+                "{:?} vs {:?}",
+                (elevation, azimuth),
+                expected
+            );
+        };
+        // up in the east, over the south, down in the west
+        close(sun_angles(0.0), (0.0, PI * 0.5));
+        close(sun_angles(0.25), (SUN_MAX_ELEVATION, 0.0));
+        close(sun_angles(0.5), (0.0, -PI * 0.5));
+        // and round underneath overnight
+        close(sun_angles(0.75), (-SUN_MAX_ELEVATION, -PI));
+
+        // facing down into the city at noon, from the south
+        let (elevation, azimuth) = sun_angles(0.25);
+        let forward = sun_rotation(azimuth, elevation) * Vec3::NEG_Z;
+        assert!(forward.y < 0.0 && forward.z < 0.0, "{}", forward);
+
+        assert_eq!(0.0, sun_illuminance(-0.1));
+        assert!(sun_illuminance(SUN_TWILIGHT_ELEVATION * 0.5) < SUN_ILLUMINANCE);
+        assert_eq!(SUN_ILLUMINANCE, sun_illuminance(SUN_MAX_ELEVATION));
+    }
+
+    #[test]
+    fn test_slow_people_are_jammed() {
+        let (speed, window) = (0.05, 2.0);
+
+        let mut stuck = SpeedHistory::default();
+        let mut moving = SpeedHistory::default();
+        for tick in 0..30 {
+            stuck.record(0.1, 0.01, window);
+            moving.record(0.1, 0.5, window);
+            // not until they've been stuck for the whole window
+            assert_eq!(tick >= 19, stuck.is_jammed(speed, window), "tick {}", tick);
+            assert!(!moving.is_jammed(speed, window));
+        }
+
+        // getting going again frees them up once the average picks up
+        for _ in 0..5 {
+            stuck.record(0.1, 0.5, window);
+        }
+        assert!(!stuck.is_jammed(speed, window));
+    }
+
+    #[test]
+    fn test_detect_jams_counts_jammed_people() {
+        let mut world = World::new();
+        let mut time = Time::default();
+        let start = time.startup();
+        time.update_with_instant(start);
+        world.insert_resource(time);
+        world.init_resource::<Options>();
+        world.init_resource::<Jams>();
+        world.insert_resource(ActiveCity::new(STARTING_CITY));
+        for velocity in [Vec3::ZERO, Vec3::ZERO, Vec3::X] {
+            world.spawn((
+                Transform::default(),
+                Velocity(velocity),
+                SpeedHistory::default(),
+            ));
+        }
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems(detect_jams);
+        for tick in 1..=30 {
+            world
+                .resource_mut::<Time>()
+                .update_with_instant(start + Duration::from_millis(100 * tick));
+            schedule.run(&mut world);
+        }
+
+        assert_eq!(
+            HashMap::from([(GridCoords::new(0, 0), 2)]),
+            world.resource::<Jams>().0
+        );
+    }
+
+    #[test]
+    fn test_headlamp_follows_camera() {
+        let mut world = World::new();
+        world.init_resource::<Time>();
+        world.insert_resource(ActiveCity::new(STARTING_CITY));
+        world.insert_resource(Options {
+            headlamp: true,
+            ..default()
+        });
+        world.init_resource::<TimeOfDay>();
+        let camera = world
+            .spawn((Camera::default(), GlobalTransform::from_xyz(4.0, 5.0, 5.0)))
+            .id();
+        let light = world
+            .spawn((PointLight::default(), Transform::from_xyz(0.0, 8.0, 0.0)))
+            .id();
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems(move_light);
+        schedule.run(&mut world);
+        assert_eq!(
+            Vec3::new(4.0, 5.0, 5.0),
+            world.get::<Transform>(light).unwrap().translation
+        );
+
+        *world.get_mut::<GlobalTransform>(camera).unwrap() =
+            GlobalTransform::from_xyz(-1.0, 2.0, 3.0);
+        schedule.run(&mut world);
+        assert_eq!(
+            Vec3::new(-1.0, 2.0, 3.0),
+            world.get::<Transform>(light).unwrap().translation
+        );
+    }
+
+    #[test]
+    fn test_lookahead_target() {
+        let city = City::new([0; 25]);
+        let path: Vec<usize> = [(0, 0), (1, 0), (1, 1), (1, 2)]
+            .into_iter()
+            .map(|(x, y)| city.coords_to_index(GridCoords::new(x, y)).unwrap())
+            .collect();
+        let target = |lookahead| {
+            lookahead_target(&city, &path, lookahead).map(|world| city.world_to_coords(world))
+        };
+
+        assert_eq!(Some(GridCoords::new(0, 0)), target(0));
+        assert_eq!(Some(GridCoords::new(1, 0)), target(1));
+        assert_eq!(Some(GridCoords::new(1, 2)), target(3));
+        // clamped to the end of the path
+        assert_eq!(Some(GridCoords::new(1, 2)), target(10));
+        assert_eq!(None, lookahead_target(&city, &[], 2));
+    }
+
+    #[test]
+    fn test_isolated_buildings() {
+        let mut city = City::new([0; 25]);
+        // a cluster of three in one corner
+        city.set_height_at_coords(GridCoords::new(-2, -2), Some(1));
+        city.set_height_at_coords(GridCoords::new(-1, -2), Some(2));
+        city.set_height_at_coords(GridCoords::new(-2, -1), Some(3));
+        // only diagonally next to the cluster, which doesn't count
+        city.set_height_at_coords(GridCoords::new(0, -1), Some(1));
+        // out on its own
+        city.set_height_at_coords(GridCoords::new(2, 2), Some(4));
+
+        assert_eq!(
+            [(GridCoords::new(0, -1), 1), (GridCoords::new(2, 2), 4)],
+            city.isolated_buildings().as_slice()
+        );
+
+        // a block doesn't count as its own neighbour, but does reach further than one cell
+        let mut city = City::new(STARTING_CITY);
+        assert!(city.place_building(GridCoords::new(-2, -2), Footprint::TwoByTwo, None));
+        assert_eq!(
+            [(GridCoords::new(-2, -2), 1)],
+            city.isolated_buildings().as_slice()
+        );
+        city.set_height_at_coords(GridCoords::new(0, -1), Some(1));
+        assert!(city.isolated_buildings().is_empty());
+    }
+
+    #[test]
+    fn test_arrival_speed() {
+        let (accel, secs) = (4.0, 0.01);
+        let stop = arrival_speed(PERSON_SPEED, false, 3, accel, secs, PERSON_SPEED);
+        let pass = arrival_speed(PERSON_SPEED, true, 3, accel, secs, PERSON_SPEED);
+        assert!(stop < PERSON_SPEED);
+        assert_eq!(PERSON_SPEED, pass);
+
+        // everyone stops at the end of the path
+        assert!(arrival_speed(PERSON_SPEED, true, 0, accel, secs, PERSON_SPEED) < PERSON_SPEED);
+        assert_eq!(
+            arrival_speed(PERSON_SPEED, false, 0, accel, secs, PERSON_SPEED),
+            arrival_speed(PERSON_SPEED, true, 0, accel, secs, PERSON_SPEED)
+        );
+    }
+
+    #[test]
+    fn test_world_bounds() {
+        let city = City::new(STARTING_CITY);
+        assert_eq!(
+            (Vec3::new(-2.5, 0.0, -2.5), Vec3::new(2.5, 0.0, 2.5)),
+            city.world_bounds()
+        );
+
+        let city = City::new(STARTING_CITY).with_origin_offset(GridCoords::new(3, -1));
+        assert_eq!(
+            (Vec3::new(0.5, 0.0, -3.5), Vec3::new(5.5, 0.0, 1.5)),
+            city.world_bounds()
+        );
+    }
+
+    #[test]
+    fn test_navigation_edges_match_exits() {
+        let mut city = City::new([0; 25]);
+        city.set_height_at_coords(GridCoords::new(1, 0), Some(2));
+        city.set_elevation_at_coords(GridCoords::new(0, 1), 1);
+        let edges = navigation_edges(&city);
+
+        let sample = city.coords_to_index(GridCoords::new(0, 0)).unwrap();
+        let from_sample: Vec<_> = edges
+            .iter()
+            .filter(|&&(from, ..)| from == sample)
+            .map(|&(_, to, cost)| (to, cost))
+            .collect();
+        assert_eq!(city.get_available_exits(sample).into_vec(), from_sample);
+        assert_eq!(3, from_sample.len());
+
+        // nothing from inside a building
+        let building = city.coords_to_index(GridCoords::new(1, 0)).unwrap();
+        assert!(edges.iter().all(|&(from, ..)| from != building));
+    }
+
+    #[test]
+    fn test_arrival_handlers() {
+        use std::sync::{Arc, Mutex};
+
+        let mut handlers = ArrivalHandlers::default();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_by_handler = seen.clone();
+        handlers.register("tourist", move |arrival| {
+            seen_by_handler.lock().unwrap().push(*arrival)
+        });
+
+        let mut world = World::new();
+        let arrival = Arrival {
+            person: world.spawn_empty().id(),
+            goal: GridCoords::new(1, 2),
+            at: 3.0,
+        };
+        handlers.arrived(ArrivalTag("tourist"), &arrival);
+        handlers.arrived(ArrivalTag("commuter"), &arrival);
+
+        assert_eq!([arrival], seen.lock().unwrap().as_slice());
+
+        // the visitor score is one of them, registered at startup
+        world.init_resource::<ArrivalHandlers>();
+        world.init_resource::<VisitorScore>();
+        let mut schedule = Schedule::default();
+        schedule.add_systems(register_visitor_score);
+        schedule.run(&mut world);
+        let mut handlers = world.resource_mut::<ArrivalHandlers>();
+        handlers.arrived(VISITOR_TAG, &arrival);
+        handlers.arrived(ArrivalTag("tourist"), &arrival);
+        handlers.arrived(VISITOR_TAG, &arrival);
+        assert_eq!(2, world.resource::<VisitorScore>().get());
+    }
+
+    #[test]
+    fn test_equalize_heights_to_average() {
+        let mut city = City::new([0; 25]);
+        let cells = [
+            GridCoords::new(-1, 0),
+            GridCoords::new(0, 0),
+            GridCoords::new(1, 0),
+        ];
+        for (coords, height) in cells.into_iter().zip([1, 3, 5]) {
+            city.set_height_at_coords(coords, Some(height));
+        }
+        city.set_height_at_coords(GridCoords::new(2, 2), Some(7));
+
+        assert_eq!(Some(3), city.equalize_heights(cells, None));
+        for coords in cells {
+            assert_eq!(Some(3), city.height_at_coords(coords));
+        }
+        // anything not selected is left alone
+        assert_eq!(Some(7), city.height_at_coords(GridCoords::new(2, 2)));
+
+        assert_eq!(Some(2), city.equalize_heights(cells, Some(2)));
+        assert_eq!(Some(2), city.height_at_coords(GridCoords::new(1, 0)));
+        assert_eq!(None, city.equalize_heights([GridCoords::new(0, 2)], None));
+    }
+
+    #[test]
+    fn test_toggle_vsync() {
+        use bevy::window::PresentMode;
+
+        let mut world = World::new();
+        world.init_resource::<Input<KeyCode>>();
+        world.init_resource::<Options>();
+        world.init_resource::<Paused>();
+        world.insert_resource(ActiveCity::new(STARTING_CITY));
+        let window = world.spawn(Window::default()).id();
+        let mut schedule = Schedule::default();
+        schedule.add_systems((
+            keyboard_set_options,
+            apply_present_mode.after(keyboard_set_options),
+        ));
+
+        schedule.run(&mut world);
+        assert!(world.resource::<Options>().vsync);
+        assert_eq!(
+            PresentMode::AutoVsync,
+            world.get::<Window>(window).unwrap().present_mode
+        );
+
+        world.resource_mut::<Input<KeyCode>>().press(KeyCode::Key5);
+        schedule.run(&mut world);
+        assert!(!world.resource::<Options>().vsync);
+        assert_eq!(
+            PresentMode::AutoNoVsync,
+            world.get::<Window>(window).unwrap().present_mode
+        );
+    }
+
+    #[test]
+    fn test_fit_scale() {
+        // looking straight down
+        let down = Quat::from_rotation_x(-PI * 0.5);
+        let small = City::new(STARTING_CITY).world_bounds();
+        assert!((fit_scale(small.0, small.1, down, 1.0) - 2.5 * CAMERA_FIT_MARGIN).abs() < 1e-4);
+        // a wide window has room to spare sideways, a tall one needs to zoom out
+        assert!((fit_scale(small.0, small.1, down, 2.0) - 2.5 * CAMERA_FIT_MARGIN).abs() < 1e-4);
+        assert!((fit_scale(small.0, small.1, down, 0.5) - 5.0 * CAMERA_FIT_MARGIN).abs() < 1e-4);
+
+        let (min, max) = (Vec3::new(-50.0, 0.0, -50.0), Vec3::new(50.0, 0.0, 50.0));
+        assert!((fit_scale(min, max, down, 1.0) - 50.0 * CAMERA_FIT_MARGIN).abs() < 1e-3);
+
+        // from the default camera angle, every corner ends up on screen
+        let rotation = Transform::from_xyz(4.0, 5.0, 5.0)
+            .looking_at(Vec3::ZERO, Vec3::Y)
+            .rotation;
+        let (min, max) = (small.0, small.1 + Vec3::Y * 3.0);
+        let aspect = 16.0 / 9.0;
+        let scale = fit_scale(min, max, rotation, aspect);
+        for corner in [
+            min,
+            max,
+            Vec3::new(min.x, max.y, max.z),
+            Vec3::new(max.x, min.y, min.z),
+        ] {
+            let view = rotation.inverse() * (corner - (min + max) * 0.5);
+            assert!(view.y.abs() <= scale, "{}", view);
+            assert!(view.x.abs() <= scale * aspect, "{}", view);
+        }
+    }
+
+    #[test]
+    fn test_districts_tint_and_save() {
+        let mut app = App::new();
+        app.add_plugins((TaskPoolPlugin::default(), AssetPlugin::default()))
+            .add_asset::<StandardMaterial>()
+            .add_asset::<Image>()
+            .add_systems(Update, tint_districts);
+        let world = &mut app.world;
+        let buildings: Vec<_> = [(0, 0), (1, 0), (2, 2)]
+            .into_iter()
+            .map(|(x, y)| {
+                let material = world
+                    .resource_mut::<Assets<StandardMaterial>>()
+                    .add(BUILDING_COLOR.into());
+                world
+                    .spawn((Building::new(1), GridCoords::new(x, y), material))
+                    .id()
+            })
+            .collect();
+        let color = |world: &World, building| {
+            let material = world.get::<Handle<StandardMaterial>>(building).unwrap();
+            world
+                .resource::<Assets<StandardMaterial>>()
+                .get(material)
+                .unwrap()
+                .base_color
+        };
+
+        for &building in &buildings[..2] {
+            app.world.entity_mut(building).insert(District(1));
+        }
+        app.update();
+        assert_eq!(District(1).color(), color(&app.world, buildings[0]));
+        assert_eq!(District(1).color(), color(&app.world, buildings[1]));
+        assert_eq!(BUILDING_COLOR, color(&app.world, buildings[2]));
+
+        app.world.entity_mut(buildings[1]).remove::<District>();
+        app.update();
+        assert_eq!(BUILDING_COLOR, color(&app.world, buildings[1]));
+
+        let districts = vec![
+            (GridCoords::new(0, 0), District(1)),
+            (GridCoords::new(2, 2), District(3)),
+        ];
+        let ron = SaveGame {
+            rng: ChaCha8Rng::seed_from_u64(SIM_SEED),
+            districts: districts.clone(),
+            city: None,
+        }
+        .to_ron()
+        .unwrap();
+        assert_eq!(districts, SaveGame::from_ron(&ron).unwrap().districts);
+    }
+
+    #[test]
+    fn test_idle_pause() {
+        let mut idle = IdlePause {
+            threshold: Duration::from_secs(10),
+            ..default()
+        };
+        let second = Duration::from_secs(1);
+
+        for _ in 0..9 {
+            idle.tick(second, false);
+            assert!(!idle.is_paused());
+        }
+        idle.tick(second, false);
+        assert!(idle.is_paused());
+
+        // any input wakes it straight up, and restarts the clock
+        idle.tick(second, true);
+        assert!(!idle.is_paused());
+        for _ in 0..9 {
+            idle.tick(second, false);
+        }
+        assert!(!idle.is_paused());
+
+        // and it never pauses while input keeps coming
+        for _ in 0..100 {
+            idle.tick(second, true);
+            assert!(!idle.is_paused());
+        }
+    }
+
+    #[test]
+    fn test_manhattanize_path() {
+        let mut city = City::new([0; 25]);
+        let grid = City::new([0; 25]);
+        let index = |x, y| grid.coords_to_index(GridCoords::new(x, y)).unwrap();
+        // diagonal steps, and a jump of two cells
+        let path = [index(-2, -2), index(-1, -1), index(0, 0), index(0, 2)];
+
+        let orthogonal = |city: &City, steps: &[usize]| {
+            steps.windows(2).all(|pair| {
+                let (from, to) = (city.index_to_coords(pair[0]), city.index_to_coords(pair[1]));
+                from.manhattan_dist(to) == 1 && city.exit_to(from, to).is_some()
+            })
+        };
+
+        let straightened = manhattanize_path(&city, &path);
+        assert!(orthogonal(&city, &straightened), "{:?}", straightened);
+        assert_eq!(7, straightened.len());
+        assert_eq!(path[0], straightened[0]);
+        assert_eq!(path[3], straightened[6]);
+
+        // goes round the other way if the first corner is blocked
+        city.set_height_at_coords(GridCoords::new(-1, -2), Some(1));
+        let straightened = manhattanize_path(&city, &path);
+        assert!(orthogonal(&city, &straightened), "{:?}", straightened);
+        assert!(straightened.contains(&index(-2, -1)));
+
+        // and gives up if both are
+        city.set_height_at_coords(GridCoords::new(-2, -1), Some(1));
+        assert_eq!(path.as_slice(), manhattanize_path(&city, &path));
+    }
+
+    #[test]
+    fn test_building_cap() {
+        let mut city = City::new([0; 25]);
+        let cap = Some(2);
+
+        assert_eq!(Some(1), city.add_storey(GridCoords::new(0, 0), cap));
+        assert_eq!(Some(1), city.add_storey(GridCoords::new(1, 0), cap));
+        // full up
+        assert_eq!(None, city.add_storey(GridCoords::new(2, 0), cap));
+        assert_eq!(None, city.height_at_coords(GridCoords::new(2, 0)));
+        assert_eq!(2, city.buildings_iter().count());
+
+        // but the ones already there can still go up
+        assert_eq!(Some(2), city.add_storey(GridCoords::new(0, 0), cap));
+        assert_eq!(Some(2), city.height_at_coords(GridCoords::new(0, 0)));
+
+        assert_eq!(Some(1), city.add_storey(GridCoords::new(2, 0), None));
+        assert_eq!(None, city.add_storey(GridCoords::new(9, 9), None));
+    }
+
+    #[test]
+    fn test_people_shrink_away_before_despawning() {
+        let mut world = World::new();
+        let mut time = Time::default();
+        let start = time.startup();
+        time.update_with_instant(start);
+        world.insert_resource(time);
+        world.insert_resource(ActiveCity::new(STARTING_CITY));
+        let person = world
+            .spawn((
+                Person::default(),
+                Transform::from_xyz(0.0, PERSON_HEIGHT * 0.5, 0.0),
+                Velocity(Vec3::X),
+                Despawning(Timer::new(PERSON_DESPAWN_TIME, TimerMode::Once)),
+            ))
+            .id();
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems(despawn_people);
+        let mut last_scale = 1.0;
+        for frame in 1.. {
+            world
+                .resource_mut::<Time>()
+                .update_with_instant(start + Duration::from_millis(100 * frame));
+            schedule.run(&mut world);
+            let Some(tx) = world.get::<Transform>(person) else {
+                break;
+            };
+            assert!(tx.scale.x <= last_scale);
+            last_scale = tx.scale.x;
+            assert_eq!(Vec3::ZERO, world.get::<Velocity>(person).unwrap().0);
+            assert!(frame < 20, "never despawned");
+        }
+        assert_eq!(0.0, last_scale);
+    }
+
+    #[test]
+    fn test_rotate_and_mirror_city() {
+        // an L in one corner, plus a stray, so no rotation or reflection looks the same
+        let mut city = City::new([0; 25]);
+        city.set_height_at_coords(GridCoords::new(-2, -2), Some(3));
+        city.set_height_at_coords(GridCoords::new(-1, -2), Some(2));
+        city.set_height_at_coords(GridCoords::new(-2, -1), Some(1));
+        city.set_height_at_coords(GridCoords::new(1, 2), Some(5));
+        let original = city.heights.clone();
+
+        let mut rotated = city.clone();
+        for turn in 1..=4 {
+            rotated.heights = rotate_cw(&rotated);
+            assert_eq!(
+                turn == 4,
+                rotated.heights == original,
+                "after {} turns",
+                turn
+            );
+        }
+
+        rotated.heights = rotate_cw(&city);
+        // +x goes to +z
+        assert_eq!(Some(3), rotated.height_at_coords(GridCoords::new(2, -2)));
+        assert_eq!(Some(2), rotated.height_at_coords(GridCoords::new(2, -1)));
+        assert_eq!(Some(1), rotated.height_at_coords(GridCoords::new(1, -2)));
+
+        let mut mirrored = city.clone();
+        mirrored.heights = mirror_x(&city);
+        assert_ne!(original, mirrored.heights);
+        assert_eq!(Some(3), mirrored.height_at_coords(GridCoords::new(2, -2)));
+        assert_eq!(Some(5), mirrored.height_at_coords(GridCoords::new(-1, 2)));
+        mirrored.heights = mirror_x(&mirrored);
+        assert_eq!(original, mirrored.heights);
+
+        assert_eq!(
+            original,
+            mirror_layer(&city, &mirror_layer(&city, &original, false), false)
+        );
+    }
+
+    #[test]
+    fn test_shadow_extent() {
+        let (min, max) = City::new(STARTING_CITY).world_bounds();
+        let max = max + Vec3::Y * MAX_BUILDING_HEIGHT as f32;
+        assert_eq!(1024, shadow_map_size(min, max, WorldScale::default()));
+        let small = shadow_distance(min, max, 8.0);
+        // reaches past the far corner of the city, but not wastefully far
+        assert!(small > 8.0 + min.distance(max) * 0.5, "{}", small);
+        assert!(small < 20.0, "{}", small);
+
+        let (min, max) = (Vec3::new(-50.0, 0.0, -50.0), Vec3::new(50.0, 10.0, 50.0));
+        assert_eq!(4096, shadow_map_size(min, max, WorldScale::default()));
+        let large = shadow_distance(min, max, 8.0);
+        assert!(large > 8.0 + 50.0 * 2.0f32.sqrt(), "{}", large);
+
+        let (min, max) = (Vec3::new(-10.0, 0.0, -10.0), Vec3::new(10.0, 10.0, 10.0));
+        assert_eq!(4096, shadow_map_size(min, max, WorldScale::default()));
+        let (min, max) = (Vec3::new(-6.0, 0.0, -6.0), Vec3::new(6.0, 10.0, 6.0));
+        assert_eq!(2048, shadow_map_size(min, max, WorldScale::default()));
+    }
+
+    #[test]
+    fn test_shift_click_drops_person() {
+        let mut app = App::new();
+        app.add_plugins((TaskPoolPlugin::default(), AssetPlugin::default()))
+            .add_asset::<StandardMaterial>()
+            .add_asset::<Image>()
+            .init_resource::<Input<MouseButton>>()
+            .init_resource::<Input<KeyCode>>()
+            .insert_resource(Population { target: 0 })
+            .add_systems(Update, drop_person);
+        let mesh = || Handle::weak(bevy::asset::HandleId::random::<Mesh>());
+        app.insert_resource(PersonMeshes {
+            high: mesh(),
+            medium: mesh(),
+            low: mesh(),
+        });
+        let mut city = City::new([0; 25]);
+        city.set_height_at_coords(GridCoords::new(1, 1), Some(2));
+        app.insert_resource(city);
+        let cursor = app.world.spawn((Cursor, GridCoords::new(-1, 2))).id();
+
+        let click = |app: &mut App, shift: bool| {
+            let mut keys = app.world.resource_mut::<Input<KeyCode>>();
+            keys.reset_all();
+            if shift {
+                keys.press(KeyCode::ShiftLeft);
+            }
+            let mut buttons = app.world.resource_mut::<Input<MouseButton>>();
+            buttons.reset_all();
+            buttons.press(MouseButton::Left);
+            app.update();
+            app.world
+                .query_filtered::<&Transform, With<Person>>()
+                .iter(&app.world)
+                .map(|tx| GridCoords::from_world(tx.translation, WorldScale::default()))
+                .collect::<Vec<_>>()
+        };
+
+        // a plain click is for buildings
+        assert_eq!(Vec::<GridCoords>::new(), click(&mut app, false));
+        assert_eq!(vec![GridCoords::new(-1, 2)], click(&mut app, true));
+        // counted as a visitor when they get where they're going
+        let tags: Vec<_> = app
+            .world
+            .query_filtered::<&ArrivalTag, With<Person>>()
+            .iter(&app.world)
+            .copied()
+            .collect();
+        assert_eq!(vec![VISITOR_TAG], tags);
+
+        // not into a building
+        *app.world.get_mut::<GridCoords>(cursor).unwrap() = GridCoords::new(1, 1);
+        assert_eq!(1, click(&mut app, true).len());
+    }
+
+    #[test]
+    fn test_coords_roundtrip_larger_cities() {
+        fn roundtrip(side: usize) {
+            let city = City::empty((side, side));
+            assert_eq!((side, side), (city.x_len, city.y_len));
+            let mut seen = HashSet::new();
+            for idx in 0..city.cells() {
+                let coords = city.index_to_coords(idx);
+                assert!(seen.insert(coords), "{:?} twice", coords);
+                assert_eq!(Some(idx), city.coords_to_index(coords));
+            }
+            // just off each edge
+            let (first, last) = (
+                city.index_to_coords(0),
+                city.index_to_coords(city.cells() - 1),
+            );
+            for coords in [first.left(), first.down(), last.right(), last.up()] {
+                assert_eq!(None, city.coords_to_index(coords), "{:?}", coords);
+            }
+        }
+        roundtrip(7);
+        roundtrip(10);
+    }
+
+    #[test]
+    fn test_stats_report() {
+        let mut city = City::new([0; 25]);
+        city.set_height_at_coords(GridCoords::new(0, 0), Some(1));
+        city.set_height_at_coords(GridCoords::new(1, 0), Some(2));
+        city.set_height_at_coords(GridCoords::new(2, 2), Some(6));
+        let mut trips = TripStats::default();
+        trips.record(4.0);
+        trips.record(6.0);
+
+        let report = StatsReport::gather(&city, 7, &trips);
+        assert_eq!(
+            StatsReport {
+                buildings: 3,
+                total_height: 9,
+                average_height: 3.0,
+                population: 7,
+                completed_trips: 2,
+                average_trip_secs: 5.0,
+                walkable_cells: 22,
+                blocked_cells: 3,
+            },
+            report
+        );
+        assert_eq!(
+            "buildings,total_height,average_height,population,completed_trips,average_trip_secs,walkable_cells,blocked_cells\n\
+             3,9,3.00,7,2,5.00,22,3\n",
+            report.to_csv()
+        );
+
+        // nothing to average over
+        let empty = StatsReport::gather(&City::new([0; 25]), 0, &TripStats::default());
+        assert_eq!(0.0, empty.average_height);
+        assert_eq!(0.0, empty.average_trip_secs);
+    }
+
+    #[test]
+    fn test_rectangular_city_row_stride() {
+        // 3 wide by 4 tall, so x runs -1..=1 and y runs -2..=1
+        let mut heights = [0; 12];
+        for (idx, height) in heights.iter_mut().enumerate() {
+            *height = idx as Height + 1;
+        }
+        let city = City::try_new(heights.to_vec(), 3, 4).unwrap();
+
+        for idx in 0..12 {
+            let expected = GridCoords::new((idx % 3) as i8 - 1, (idx / 3) as i8 - 2);
+            assert_eq!(expected, city.index_to_coords(idx), "index {}", idx);
+            assert_eq!(Some(idx), city.coords_to_index(expected), "{:?}", expected);
+            assert_eq!(Some(idx as Height + 1), city.height_at_coords(expected));
+        }
+        assert_eq!(None, city.coords_to_index(GridCoords::new(2, 0)));
+        assert_eq!(None, city.coords_to_index(GridCoords::new(0, 2)));
+        assert_eq!(None, city.coords_to_index(GridCoords::new(0, -3)));
+    }
+
+    #[test]
+    fn test_connectivity() {
+        let mut city = City::new([0; 25]);
+        let center = city.coords_to_index(GridCoords::new(0, 0)).unwrap();
+        let diagonal = city.coords_to_index(GridCoords::new(1, 1)).unwrap();
+        let exits = |city: &City| -> Vec<usize> {
+            city.get_available_exits(center)
+                .into_iter()
+                .map(|(idx, _)| idx)
+                .collect()
+        };
+
+        assert_eq!(4, exits(&city).len());
+        assert!(!exits(&city).contains(&diagonal));
+
+        let mut world = World::new();
+        world.init_resource::<Options>();
+        world.init_resource::<Paused>();
+        let mut keys = Input::<KeyCode>::default();
+        keys.press(KeyCode::F2);
+        world.insert_resource(keys);
+        world.insert_resource(city);
+        let mut schedule = Schedule::default();
+        schedule.add_systems(keyboard_set_options);
+        schedule.run(&mut world);
+        city = world.remove_resource::<ActiveCity>().unwrap();
+
+        assert_eq!(8, exits(&city).len());
+        assert!(exits(&city).contains(&diagonal));
+        let (_, cost) = city
+            .get_available_exits(center)
+            .into_iter()
+            .find(|&(idx, _)| idx == diagonal)
+            .unwrap();
+        assert!((cost - std::f32::consts::SQRT_2).abs() < 1e-6);
+        assert!(city.get_pathing_distance(center, diagonal) <= cost);
+
+        // no cutting the corner of a building
+        city.set_height_at_coords(GridCoords::new(1, 0), Some(1));
+        assert!(!exits(&city).contains(&diagonal));
+        assert_eq!(5, exits(&city).len());
+    }
+
+    #[test]
+    fn test_demolished_building_is_walkable_again() {
+        let mut city = City::new([0; 25]);
+        let building = GridCoords::new(1, 0);
+        city.set_height_at_coords(building, Some(2));
+        let building_idx = city.coords_to_index(building).unwrap();
+        let next_door = city.coords_to_index(GridCoords::new(0, 0)).unwrap();
+        let exits = |city: &City| -> Vec<usize> {
+            city.get_available_exits(next_door)
+                .into_iter()
+                .map(|(idx, _)| idx)
+                .collect()
+        };
+        assert!(!exits(&city).contains(&building_idx));
+
+        city.set_height_at_coords(building, None);
+        assert_eq!(0, city.buildings_iter().count());
+        assert!(exits(&city).contains(&building_idx));
+    }
+
+    #[test]
+    fn test_cell_tint() {
+        let mut city = City::new(STARTING_CITY);
+        for idx in 0..25 {
+            let coords = city.index_to_coords(idx);
+            assert_eq!(
+                CellTint::Walkable,
+                CellTint::of(&city, coords),
+                "{:?}",
+                coords
+            );
+        }
+
+        city.set_height_at_coords(GridCoords::new(1, -1), Some(1));
+        assert_eq!(
+            CellTint::Blocked,
+            CellTint::of(&city, GridCoords::new(1, -1))
+        );
+        assert_eq!(
+            CellTint::Walkable,
+            CellTint::of(&city, GridCoords::new(1, 0))
+        );
+    }
+
+    #[test]
+    fn test_building_index() {
+        let mut world = World::new();
+        world.init_resource::<BuildingIndex>();
+        let cells = [
+            GridCoords::new(0, 0),
+            GridCoords::new(1, -2),
+            GridCoords::new(-2, 2),
+        ];
+        let entities: Vec<_> = cells
+            .iter()
+            .map(|&coords| world.spawn((Building::new(1), coords)).id())
+            .collect();
+        // not a building, so shouldn't be indexed
+        world.spawn(GridCoords::new(2, 2));
+
+        let mut startup = Schedule::default();
+        startup.add_systems(rebuild_building_index);
+        startup.run(&mut world);
+
+        let index = world.resource::<BuildingIndex>();
+        for (&coords, &entity) in cells.iter().zip(&entities) {
+            assert_eq!(Some(entity), index.get(coords), "{:?}", coords);
+        }
+        assert_eq!(None, index.get(GridCoords::new(2, 2)));
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems(track_buildings);
+        schedule.run(&mut world);
+
+        world.despawn(entities[1]);
+        let replacement = world.spawn((Building::new(2), cells[1])).id();
+        let added = world.spawn((Building::new(3), GridCoords::new(2, 0))).id();
+        schedule.run(&mut world);
+
+        let index = world.resource::<BuildingIndex>();
+        assert_eq!(Some(entities[0]), index.get(cells[0]));
+        assert_eq!(Some(replacement), index.get(cells[1]));
+        assert_eq!(Some(entities[2]), index.get(cells[2]));
+        assert_eq!(Some(added), index.get(GridCoords::new(2, 0)));
+        assert_eq!(4, index.0.len());
+    }
+
+    #[test]
+    fn test_plan_trip_paused() {
+        let city = City::new(STARTING_CITY);
+        let options = Options {
+            plan_paths: false,
+            ..default()
+        };
+        let mut reservations = Reservations::default();
+        let entity = Entity::from_raw(0);
+
+        let from = GridCoords::new(-2, 0);
+        let goal = GridCoords::new(2, 0);
+        let steps: Vec<_> = (-1..=2)
+            .map(|x| city.coords_to_index(GridCoords::new(x, 0)).unwrap())
+            .collect();
+        let mut person = Person {
+            goal: Some(goal),
+            path: NavigationPath {
+                destination: *steps.last().unwrap(),
+                success: true,
+                steps: steps.clone(),
+            },
+            ..default()
+        };
+
+        // partway there: keeps going where they were going
+        plan_trip(
+            &mut person,
+            entity,
+            from,
+            &city,
+            &options,
+            &mut reservations,
+            &TimeOfDay::default(),
+            1.0,
+            0.1,
+            &mut SimRng::default().0,
+        );
+        assert_eq!(Some(goal), person.goal);
+        assert_eq!(steps, person.path.steps);
+
+        // arrived: stops rather than picking somewhere else to go
+        person.path.steps.clear();
+        for _ in 0..3 {
+            plan_trip(
+                &mut person,
+                entity,
+                goal,
+                &city,
+                &options,
+                &mut reservations,
+                &TimeOfDay::default(),
+                2.0,
+                0.1,
+                &mut SimRng::default().0,
+            );
+            assert_eq!(None, person.goal);
+            assert!(person.path.steps.is_empty());
+        }
+
+        // and picks up again once planning is back on
+        let options = Options::default();
+        plan_trip(
+            &mut person,
+            entity,
+            goal,
+            &city,
+            &options,
+            &mut reservations,
+            &TimeOfDay::default(),
+            3.0,
+            // long enough to have finished resting
+            10.0,
+            &mut SimRng::default().0,
+        );
+        assert!(person.goal.is_some());
+    }
+
+    #[test]
+    fn test_cursor_to_grid() {
+        use bevy::render::camera::{camera_system, ManualTextureViews};
+        use bevy::window::{PrimaryWindow, WindowCreated, WindowResized, WindowResolution};
+
+        let mut app = App::new();
+        app.add_plugins((TaskPoolPlugin::default(), AssetPlugin::default()))
+            .add_asset::<Image>()
+            .init_resource::<ManualTextureViews>()
+            .add_event::<WindowCreated>()
+            .add_event::<WindowResized>()
+            .add_systems(Update, camera_system::<Projection>);
+
+        let mut window = Window {
+            resolution: WindowResolution::new(800.0, 600.0),
+            ..default()
+        };
+        // dead centre, so straight down the camera's line of sight
+        window.set_cursor_position(Some(Vec2::new(400.0, 300.0)));
+        app.world.spawn((window, PrimaryWindow));
+
+        // looking straight down on the middle of cell (2, -1)
+        let camera_tx =
+            Transform::from_xyz(2.1, 10.0, -0.8).looking_at(Vec3::new(2.1, 0.0, -0.8), Vec3::NEG_Z);
+        let camera = app
+            .world
+            .spawn(Camera3dBundle {
+                transform: camera_tx,
+                global_transform: camera_tx.into(),
+                ..default()
+            })
+            .id();
+        app.update();
+
+        let mut window = app.world.query::<&Window>().single(&app.world).clone();
+        let camera = app.world.get::<Camera>(camera).unwrap();
+        let city = ActiveCity::new(STARTING_CITY);
+        let options = Options::default();
+
+        let (grid, point) = cursor_to_grid(
+            Some(&window),
+            camera,
+            &camera_tx.into(),
+            &GlobalTransform::IDENTITY,
+            &city,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(GridCoords::new(2, -1), grid);
+        assert!(
+            point.distance(Vec3::new(2.1, 0.0, -0.8)) < 1e-3,
+            "{}",
+            point
+        );
+
+        window.set_cursor_position(None);
+        assert_eq!(
+            None,
+            cursor_to_grid(
+                Some(&window),
+                camera,
+                &camera_tx.into(),
+                &GlobalTransform::IDENTITY,
+                &city,
+                &options,
+            )
+        );
+        // and with the window gone there's nothing to point at
+        assert_eq!(
+            None,
+            cursor_to_grid(
+                None,
+                camera,
+                &camera_tx.into(),
+                &GlobalTransform::IDENTITY,
+                &city,
+                &options,
+            )
+        );
+    }
+
+    #[test]
+    fn test_dwell_at_goal() {
+        let city = City::new(STARTING_CITY);
+        let options = Options {
+            dwell_time: (Duration::from_secs(1), Duration::from_secs(1)),
+            ..default()
+        };
+        let mut reservations = Reservations::default();
+        let entity = Entity::from_raw(0);
+
+        let goal = GridCoords::new(1, 1);
+        let mut person = Person {
+            goal: Some(goal),
+            ..Person::commuting(GridCoords::ORIGIN, goal)
+        };
+        let night = TimeOfDay {
+            elapsed: 90.0,
+            ..default()
+        };
+
+        for frame in 0..3 {
+            plan_trip(
+                &mut person,
+                entity,
+                goal,
+                &city,
+                &options,
+                &mut reservations,
+                &TimeOfDay::default(),
+                frame as f32 * 0.3,
+                0.3,
+                &mut SimRng::default().0,
+            );
+            assert!(person.is_dwelling(), "frame {}", frame);
+            assert_eq!(None, person.goal);
+            assert!(person.path.steps.is_empty());
+        }
+
+        plan_trip(
+            &mut person,
+            entity,
+            goal,
+            &city,
+            &options,
+            &mut reservations,
+            &night,
+            0.9,
+            0.3,
+            &mut SimRng::default().0,
+        );
+        assert!(!person.is_dwelling());
+        // done for the day, so off home
+        assert_eq!(Some(GridCoords::ORIGIN), person.goal);
+    }
+
+    #[test]
+    fn test_save_city_round_trip() {
+        let mut heights = STARTING_CITY;
+        heights[3] = 2;
+        heights[7] = 1;
+        heights[24] = 5;
+        let city = City::new(heights).with_origin_offset(GridCoords::new(1, 1));
+
+        let ron = SaveGame {
+            rng: ChaCha8Rng::seed_from_u64(SIM_SEED),
+            districts: vec![],
+            city: Some(save_city(&city)),
+        }
+        .to_ron()
+        .unwrap();
+        let saved = SaveGame::from_ron(&ron).unwrap().city.unwrap();
+        let loaded = load_city(&saved, &City::new(STARTING_CITY)).unwrap();
+        assert_eq!(heights.to_vec(), loaded.heights);
+        assert_eq!((5, 5), (loaded.x_len, loaded.y_len));
+
+        // a different size of city is fine, as long as the cells add up
+        let other_size = SavedCity {
+            x_len: 3,
+            y_len: 2,
+            heights: vec![0; 6],
+            elevations: vec![],
+            roads: vec![],
+            blocks: vec![],
+        };
+        let loaded = load_city(&other_size, &city).unwrap();
+        assert_eq!((3, 2), (loaded.x_len, loaded.y_len));
+        assert_eq!(6, loaded.elevations.len());
+
+        let wrong_size = SavedCity {
+            x_len: 3,
+            y_len: 3,
+            ..other_size
+        };
+        assert!(matches!(
+            load_city(&wrong_size, &city),
+            Err(SaveError::City(CityError::DimensionMismatch {
+                cells: 6,
+                ..
+            }))
+        ));
+        let wrong_terrain = SavedCity {
+            elevations: vec![0; 4],
+            ..save_city(&city)
+        };
+        assert!(load_city(&wrong_terrain, &city).is_err());
+    }
+
+    #[test]
+    fn test_next_selection() {
+        let people: Vec<_> = [7, 2, 5, 0, 3].into_iter().map(Entity::from_raw).collect();
+        assert_eq!(None, next_selection(&[], None, false));
+
+        let mut visited = vec![];
+        let mut selected = None;
+        for _ in 0..people.len() {
+            selected = next_selection(&people, selected, false);
+            visited.push(selected.unwrap().index());
+        }
+        assert_eq!(vec![0, 2, 3, 5, 7], visited);
+        // and round again
+        assert_eq!(
+            Some(Entity::from_raw(0)),
+            next_selection(&people, selected, false)
+        );
+
+        let mut visited = vec![];
+        let mut selected = None;
+        for _ in 0..people.len() {
+            selected = next_selection(&people, selected, true);
+            visited.push(selected.unwrap().index());
+        }
+        assert_eq!(vec![7, 5, 3, 2, 0], visited);
+
+        // someone who's gone is as good as nobody
+        assert_eq!(
+            Some(Entity::from_raw(0)),
+            next_selection(&people, Some(Entity::from_raw(4)), false)
+        );
+    }
+
+    #[test]
+    fn test_building_height_cap() {
+        let mut app = App::new();
+        app.add_plugins((TaskPoolPlugin::default(), AssetPlugin::default()))
+            .add_asset::<Mesh>()
+            .add_asset::<StandardMaterial>()
+            .init_resource::<Input<MouseButton>>()
+            .init_resource::<Input<KeyCode>>()
+            .init_resource::<Options>()
+            .init_resource::<FlashMessage>()
+            .init_resource::<BuildingIndex>()
+            .insert_resource(ActiveCity::new(STARTING_CITY))
+            .add_systems(Update, (track_buildings, add_buildings).chain());
+        let coords = GridCoords::new(1, 0);
+        app.world.spawn((Cursor, coords));
+
+        for _ in 0..=MAX_BUILDING_HEIGHT {
+            let mut buttons = app.world.resource_mut::<Input<MouseButton>>();
+            buttons.release(MouseButton::Left);
+            buttons.clear();
+            buttons.press(MouseButton::Left);
+            app.update();
+
+            let height = app.world.resource::<ActiveCity>().height_at_coords(coords);
+            assert!(height.is_some_and(|height| height <= MAX_BUILDING_HEIGHT));
+        }
+        assert_eq!(
+            Some(MAX_BUILDING_HEIGHT),
+            app.world.resource::<ActiveCity>().height_at_coords(coords)
+        );
+        let building = app.world.query::<&Building>().single(&app.world);
+        assert_eq!(MAX_BUILDING_HEIGHT, building.height);
+
+        let mut city = app.world.resource_mut::<ActiveCity>();
+        city.set_height_at_coords(coords, Some(MAX_BUILDING_HEIGHT + 1));
+        assert_eq!(Some(MAX_BUILDING_HEIGHT), city.height_at_coords(coords));
+    }
+
+    #[test]
+    fn test_beveled_building_mesh() {
+        use bevy::render::mesh::VertexAttributeValues;
+
+        for height in [1, 3, MAX_BUILDING_HEIGHT] {
+            let flat = building_mesh(height, BuildingStyle::Flat, default())
+                .compute_aabb()
+                .unwrap();
+            let beveled_mesh = building_mesh(height, BuildingStyle::Beveled, default());
+            let beveled = beveled_mesh.compute_aabb().unwrap();
+            assert_eq!(flat.min(), beveled.min(), "height {}", height);
+            assert_eq!(flat.max(), beveled.max(), "height {}", height);
+
+            // the whole footprint sits on the ground, not just the middle of it
+            let Some(VertexAttributeValues::Float32x3(positions)) =
+                beveled_mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+            else {
+                panic!("no positions");
+            };
+            let base: Vec<_> = positions.iter().filter(|p| p[1] == -0.5).collect();
+            for corner in [[-0.5, -0.5], [0.5, -0.5], [0.5, 0.5], [-0.5, 0.5]] {
+                assert!(
+                    base.iter().any(|p| p[0] == corner[0] && p[2] == corner[1]),
+                    "{:?}",
+                    corner
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_building_mesh_height() {
+        for height in 1..=MAX_BUILDING_HEIGHT {
+            let aabb = Building::new(height)
+                .mesh(BuildingStyle::Flat)
+                .compute_aabb()
+                .unwrap();
+            assert_eq!(-0.5 + height as f32, aabb.max().y, "height {}", height);
+            assert_eq!(-0.5, aabb.min().y);
+        }
+    }
+
+    #[test]
+    fn test_update_building_meshes() {
+        let mut app = App::new();
+        app.add_plugins((TaskPoolPlugin::default(), AssetPlugin::default()))
+            .add_asset::<Mesh>()
+            .init_resource::<Options>()
+            .add_systems(Update, update_building_meshes);
+        let mesh = app
+            .world
+            .resource_mut::<Assets<Mesh>>()
+            .add(Building::new(1).mesh(default()));
+        let building = app.world.spawn((Building::new(1), mesh.clone())).id();
+        app.update();
+
+        app.world.get_mut::<Building>(building).unwrap().height = 4;
+        app.update();
+
+        let meshes = app.world.resource::<Assets<Mesh>>();
+        let aabb = meshes.get(&mesh).unwrap().compute_aabb().unwrap();
+        assert_eq!(3.5, aabb.max().y);
+    }
+
+    #[test]
+    fn test_center_of_mass() {
+        assert_eq!(None, City::new(STARTING_CITY).center_of_mass());
+
+        let mut city = City::new(STARTING_CITY);
+        city.set_height_at_coords(GridCoords::new(-2, 0), Some(1));
+        city.set_height_at_coords(GridCoords::new(1, 2), Some(3));
+        // x: (-2·1 + 1·3) / 4, z: (0·1 + 2·3) / 4, y: (0.5·1 + 1.5·3) / 4
+        let center = city.center_of_mass().unwrap();
+        assert!(
+            center.distance(Vec3::new(0.25, 1.25, 1.5)) < 1e-6,
+            "{}",
+            center
+        );
+    }
+
+    #[test]
+    fn test_diagonal_paths_around_obstacle() {
+        let mut city = City::new([0; 25]);
+        city.set_height_at_coords(GridCoords::new(0, 0), Some(1));
+        let start = city.coords_to_index(GridCoords::new(-2, -2)).unwrap();
+        let end = city.coords_to_index(GridCoords::new(2, 2)).unwrap();
+
+        let orthogonal = a_star_search(start, end, &city);
+        assert!(orthogonal.success);
+        assert_eq!(8, orthogonal.steps.len() - 1);
+
+        city.connectivity = Connectivity::Eight;
+        let diagonal = a_star_search(start, end, &city);
+        assert!(diagonal.success);
+        // shorter than going orthogonally, but it can't go straight through the building
+        let moves = diagonal.steps.len() - 1;
+        assert!(moves > 4 && moves < 8, "{:?}", diagonal.steps);
+        let center = city.coords_to_index(GridCoords::new(0, 0)).unwrap();
+        assert!(!diagonal.steps.contains(&center));
+    }
+
+    #[test]
+    fn test_paused_people_stay_put() {
+        let mut world = World::new();
+        let mut time = Time::default();
+        let start = Instant::now();
+        time.update_with_instant(start);
+        time.update_with_instant(start + Duration::from_millis(100));
+        world.insert_resource(time);
+        world.init_resource::<Input<KeyCode>>();
+        world.init_resource::<Options>();
+        world.init_resource::<Paused>();
+        world.init_resource::<SimSpeed>();
+        world.insert_resource(ActiveCity::new(STARTING_CITY));
+        let person = world
+            .spawn((
+                Transform::from_xyz(1.0, 0.0, 1.0),
+                Velocity(Vec3::new(1.0, 0.0, 0.0)),
+            ))
+            .id();
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems((keyboard_set_options, apply_velocities).chain());
+
+        world.resource_mut::<Input<KeyCode>>().press(KeyCode::Space);
+        schedule.run(&mut world);
+        assert!(world.resource::<Paused>().0);
+        assert_eq!(
+            Vec3::new(1.0, 0.0, 1.0),
+            world.get::<Transform>(person).unwrap().translation
+        );
+
+        // and they carry on once unpaused
+        let mut keys = world.resource_mut::<Input<KeyCode>>();
+        keys.release(KeyCode::Space);
+        keys.clear();
+        keys.press(KeyCode::Space);
+        schedule.run(&mut world);
+        assert!(!world.resource::<Paused>().0);
+        assert!(world.get::<Transform>(person).unwrap().translation.x > 1.0);
+    }
+
+    #[test]
+    fn test_commute_follows_time_of_day() {
+        let city = City::new(STARTING_CITY);
+        let options = Options::default();
+        let mut reservations = Reservations::default();
+        let entity = Entity::from_raw(0);
+        let mut time_of_day = TimeOfDay {
+            elapsed: 0.0,
+            day_length: 10.0,
+        };
+
+        let (home, work) = (GridCoords::new(-2, -2), GridCoords::new(2, 1));
+        let mut person = Person::commuting(home, work);
+        plan_trip(
+            &mut person,
+            entity,
+            home,
+            &city,
+            &options,
+            &mut reservations,
+            &time_of_day,
+            0.0,
+            0.1,
+            &mut SimRng::default().0,
+        );
+        assert!(time_of_day.is_daytime());
+        assert_eq!(Some(work), person.goal);
+        assert!(!person.path.steps.is_empty());
+
+        // night falls on the way there
+        time_of_day.tick(4.9);
+        assert!(time_of_day.is_daytime());
+        time_of_day.tick(0.2);
+        assert!(!time_of_day.is_daytime());
+        plan_trip(
+            &mut person,
+            entity,
+            GridCoords::new(0, -1),
+            &city,
+            &options,
+            &mut reservations,
+            &time_of_day,
+            5.1,
+            0.1,
+            &mut SimRng::default().0,
+        );
+        assert_eq!(Some(home), person.goal);
+        let end = city.coords_to_index(home).unwrap();
+        assert_eq!(Some(&end), person.path.steps.last());
+
+        // and the next morning it's back to work
+        time_of_day.tick(5.0);
+        assert!(time_of_day.is_daytime());
+        assert!(time_of_day.elapsed < 1.0);
+        assert_eq!(work, person.destination(&time_of_day));
+    }
+
+    #[test]
+    fn test_walk_long_path_in_place() {
+        let city = City::new([0; 25]);
+        // snake back and forth over the whole city
+        let cells: Vec<_> = (-2..=2)
+            .flat_map(|y| {
+                let xs: Vec<_> = if y % 2 == 0 {
+                    (-2..=2).collect()
+                } else {
+                    (-2..=2).rev().collect()
+                };
+                xs.into_iter().map(move |x| GridCoords::new(x, y))
+            })
+            .collect();
+        let steps: Vec<_> = cells
+            .iter()
+            .map(|&cell| city.coords_to_index(cell).unwrap())
+            .collect();
+
+        let mut person = Person::default();
+        person.set_path(NavigationPath {
+            destination: *steps.last().unwrap(),
+            success: true,
+            steps: steps.clone(),
+        });
+        let buffer = person.path.steps.as_ptr();
+
+        // somewhere off the path doesn't count as progress
+        assert!(!person.reach_step(&city, GridCoords::new(3, 3)));
+        for (taken, &cell) in cells.iter().enumerate() {
+            assert_eq!(&steps[taken..], person.steps());
+            assert!(person.reach_step(&city, cell));
+            assert_eq!(buffer, person.path.steps.as_ptr());
+        }
+        assert!(person.steps().is_empty());
+        assert!(!person.reach_step(&city, *cells.last().unwrap()));
+
+        person.reset_path();
+        assert!(person.steps().is_empty());
+        assert_eq!(0, person.next_step);
+    }
+
+    #[test]
+    fn test_terrain_raises_world_positions() {
+        let mut city = City::new(STARTING_CITY);
+        let hill = GridCoords::new(1, -1);
+        city.set_elevation_at_coords(hill, 2);
+
+        let idx = city.coords_to_index(hill).unwrap();
+        assert_eq!(
+            Vec3::new(1.0, 2.0 * ELEVATION_STEP + 0.5, -1.0),
+            city.index_to_world(idx, 0.5)
+        );
+        assert_eq!(
+            Vec3::new(0.0, 0.5, 0.0),
+            city.coords_to_world(GridCoords::ORIGIN, 0.5)
+        );
+        // off the edge of the city is flat ground
+        assert_eq!(
+            Vec3::new(9.0, 0.0, 9.0),
+            city.coords_to_world(GridCoords::new(9, 9), 0.0)
+        );
+
+        // buildings stand on top of the hill
+        let mut world = World::new();
+        world.insert_resource(city);
+        let building = world
+            .spawn((Building::new(1), hill, Transform::default()))
+            .id();
+        let mut schedule = Schedule::default();
+        schedule.add_systems(position_objects_on_grid);
+        schedule.run(&mut world);
+        assert_eq!(
+            2.0 * ELEVATION_STEP + 0.5,
+            world.get::<Transform>(building).unwrap().translation.y
+        );
+
+        // and so do their outlines, labels and the people sent there
+        let city = world.resource::<City>();
+        let hilltop = 2.0 * ELEVATION_STEP;
+        assert_eq!(
+            Vec3::new(1.0, hilltop + 1.0, -1.0),
+            building_top(city, hill, 1)
+        );
+        let corners = building_corners(city, hill, Footprint::OneByOne, 1);
+        assert!(corners[..4].iter().all(|corner| corner.y == hilltop));
+        assert!(corners[4..].iter().all(|corner| corner.y == hilltop + 1.0));
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for _ in 0..20 {
+            let position = spawn_position(city, &mut rng);
+            let ground = city.coords_to_world(city.world_to_coords(position), 0.0).y;
+            assert_eq!(ground + PERSON_HEIGHT * 0.5, position.y);
+        }
+    }
+
+    #[test]
+    fn test_cliff_blocks_exits() {
+        // a cliff running right across the city, with one ramp up partway along
+        let mut city = City::new(STARTING_CITY);
+        for x in -2..=2 {
+            city.set_elevation_at_coords(GridCoords::new(x, 1), 3);
+            city.set_elevation_at_coords(GridCoords::new(x, 2), 3);
+        }
+        let (below, above) = (GridCoords::new(0, 0), GridCoords::new(0, 1));
+        assert_eq!(None, city.exit_to(below, above));
+        assert_eq!(None, city.exit_to(above, below));
+
+        let path = |city: &City| {
+            a_star_search(
+                city.coords_to_index(GridCoords::new(0, -2)).unwrap(),
+                city.coords_to_index(GridCoords::new(0, 2)).unwrap(),
+                city,
+            )
+        };
+        assert!(!path(&city).success);
+
+        // cut a ramp into the cliff at the far end
+        city.set_elevation_at_coords(GridCoords::new(2, 0), 1);
+        city.set_elevation_at_coords(GridCoords::new(2, 1), 2);
+        let route = path(&city);
+        assert!(route.success);
+        assert!(route
+            .steps
+            .contains(&city.coords_to_index(GridCoords::new(2, 1)).unwrap()));
+    }
+
+    #[test]
+    fn test_paths_prefer_roads() {
+        // two equally long ways round the building in the middle, one of them paved
+        let mut city = City::new(STARTING_CITY);
+        city.set_height_at_coords(GridCoords::ORIGIN, Some(1));
+        let (from, to) = (GridCoords::new(0, -1), GridCoords::new(0, 1));
+        for road in [
+            GridCoords::new(1, -1),
+            GridCoords::new(1, 0),
+            GridCoords::new(1, 1),
+        ] {
+            city.set_road_at_coords(road, true);
+        }
+        assert_eq!(Some(TileKind::Road), city.tile_kind(GridCoords::new(1, 0)));
+        assert_eq!(Some(TileKind::Building), city.tile_kind(GridCoords::ORIGIN));
+        assert_eq!(
+            Some(TileKind::Empty),
+            city.tile_kind(GridCoords::new(-1, 0))
+        );
+
+        let start = city.coords_to_index(from).unwrap();
+        let end = city.coords_to_index(to).unwrap();
+        let route = path_coords(&city, &a_star_search(start, end, &city).steps);
+        assert_eq!(
+            vec![
+                from,
+                GridCoords::new(1, -1),
+                GridCoords::new(1, 0),
+                GridCoords::new(1, 1),
+                to
+            ],
+            route
+        );
+
+        // and the other way once the road's moved over there
+        for x in [1, -1] {
+            for y in -1..=1 {
+                city.set_road_at_coords(GridCoords::new(x, y), x < 0);
+            }
+        }
+        let route = path_coords(&city, &a_star_search(start, end, &city).steps);
+        assert!(route.contains(&GridCoords::new(-1, 0)), "{:?}", route);
+    }
+
+    #[test]
+    fn test_orbit_camera() {
+        let focus = Vec3::new(1.0, 0.0, -1.0);
+        let mut camera_tx = Transform::from_xyz(1.0, 5.0, 7.0).looking_at(focus, Vec3::Y);
+        let distance = camera_tx.translation.distance(focus);
+
+        orbit_camera(&mut camera_tx, focus, PI * 0.5);
+        assert!(camera_tx.translation.distance(Vec3::new(9.0, 5.0, -1.0)) < 1e-4);
+
+        for _ in 0..7 {
+            orbit_camera(&mut camera_tx, focus, 0.3);
+            assert!((camera_tx.translation.y - 5.0).abs() < 1e-4);
+            assert!((camera_tx.translation.distance(focus) - distance).abs() < 1e-4);
+            // still looking straight at the focus
+            let towards = (focus - camera_tx.translation).normalize();
+            assert!(camera_tx.forward().distance(towards) < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_camera_zoom_clamps() {
+        let unscaled = WorldScale::default();
+        assert_eq!(CAMERA_MIN_SCALE, clamp_camera_scale(0.5, unscaled));
+        assert_eq!(CAMERA_MAX_SCALE, clamp_camera_scale(1000.0, unscaled));
+        assert_eq!(12.0, clamp_camera_scale(12.0, unscaled));
+        // smaller cells let the camera in closer, as setup starts it there
+        let half = WorldScale(0.5);
+        assert_eq!(3.0 * half.0, clamp_camera_scale(3.0 * half.0, half));
+        assert_eq!(CAMERA_MIN_SCALE * 0.5, clamp_camera_scale(0.5, half));
+
+        // scrolling up zooms in, by the same amount a notch at a time or all at once
+        let one_notch = wheel_zoom_factor(MouseScrollUnit::Line, 1.0);
+        assert!(one_notch < 1.0);
+        assert!(wheel_zoom_factor(MouseScrollUnit::Line, -1.0) > 1.0);
+        let trackpad = wheel_zoom_factor(MouseScrollUnit::Pixel, 2.0 * PIXELS_PER_WHEEL_NOTCH);
+        assert!((trackpad - one_notch * one_notch).abs() < 1e-6);
+        assert_eq!(1.0, wheel_zoom_factor(MouseScrollUnit::Pixel, 0.0));
+    }
+
+    #[test]
+    fn test_spawn_outside_buildings() {
+        let mut rng = ChaCha8Rng::seed_from_u64(SIM_SEED);
+        // built up everywhere but the odd corner
+        let mut heights = [3; 25];
+        heights[0] = 0;
+        heights[18] = 0;
+        let city = City::new(heights);
+        for _ in 0..200 {
+            let coords =
+                GridCoords::from_world(spawn_position(&city, &mut rng), WorldScale::default());
+            assert_eq!(None, city.height_at_coords(coords), "{:?}", coords);
+            assert!(city.coords_to_index(coords).is_some(), "{:?}", coords);
+        }
+
+        // and with only one gap, so it'll usually have to fall back on finding it
+        heights[0] = 3;
+        let city = City::new(heights);
+        for _ in 0..10 {
+            let coords =
+                GridCoords::from_world(spawn_position(&city, &mut rng), WorldScale::default());
+            assert_eq!(city.index_to_coords(18), coords);
+        }
+    }
+
+    #[test]
+    fn test_hud_text() {
+        let options = Options {
+            draw_paths: true,
+            draw_selection: false,
+            ..default()
+        };
+        assert_eq!(
+            "buildings: 7\npeople: 12\nvisitor trips: 3\npaths: on\nselection: off",
+            hud_text(7, 12, 3, &options)
+        );
+    }
+
+    #[test]
+    fn test_people_avoid_each_other() {
+        let mut world = World::new();
+        let mut time = Time::default();
+        let mut now = Instant::now();
+        time.update_with_instant(now);
+        world.insert_resource(time);
+        world.init_resource::<Options>();
+        world.init_resource::<Paused>();
+        world.init_resource::<SimSpeed>();
+        world.insert_resource(City::new(STARTING_CITY));
+
+        // walking straight at each other
+        let heading = [Vec3::X * 0.5, Vec3::NEG_X * 0.5];
+        let people = [
+            world
+                .spawn((Person::default(), Transform::from_xyz(-0.15, 0.0, 0.05)))
+                .insert(Velocity(heading[0]))
+                .id(),
+            world
+                .spawn((Person::default(), Transform::from_xyz(0.15, 0.0, -0.05)))
+                .insert(Velocity(heading[1]))
+                .id(),
+        ];
+        let separation = |world: &World| {
+            let [a, b] = people.map(|person| world.get::<Transform>(person).unwrap().translation);
+            a.distance(b)
+        };
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems((avoid_each_other, apply_velocities).chain());
+        let mut last = separation(&world);
+        for _ in 0..5 {
+            // people_walk would set them back on course every frame
+            for (&person, &heading) in people.iter().zip(&heading) {
+                world.get_mut::<Velocity>(person).unwrap().0 = heading;
+            }
+            now += Duration::from_millis(50);
+            world.resource_mut::<Time>().update_with_instant(now);
+            schedule.run(&mut world);
+
+            let apart = separation(&world);
+            assert!(apart > last, "{} then {}", last, apart);
+            last = apart;
+        }
+    }
+
+    #[test]
+    fn test_match_population() {
+        let mut app = App::new();
+        app.add_plugins((TaskPoolPlugin::default(), AssetPlugin::default()))
+            .add_asset::<StandardMaterial>()
+            .insert_resource(Population { target: 3 })
+            .insert_resource(ActiveCity::new(STARTING_CITY))
+            .init_resource::<Reservations>()
+            .add_systems(Update, match_population);
+        let mesh = || Handle::weak(bevy::asset::HandleId::random::<Mesh>());
+        app.insert_resource(PersonMeshes {
+            high: mesh(),
+            medium: mesh(),
+            low: mesh(),
+        });
+        let count = |app: &mut App| {
+            let mut people = app
+                .world
+                .query_filtered::<(), (With<Person>, Without<Despawning>)>();
+            let mut leaving = app
+                .world
+                .query_filtered::<(), (With<Person>, With<Despawning>)>();
+            (
+                people.iter(&app.world).count(),
+                leaving.iter(&app.world).count(),
+            )
+        };
+
+        app.update();
+        assert_eq!((3, 0), count(&mut app));
+        // nothing to do once it's right
+        app.update();
+        assert_eq!((3, 0), count(&mut app));
+
+        app.world.resource_mut::<Population>().target = 7;
+        app.update();
+        assert_eq!((7, 0), count(&mut app));
+
+        app.world.resource_mut::<Population>().target = 2;
+        app.update();
+        assert_eq!((2, 5), count(&mut app));
+        app.update();
+        assert_eq!((2, 5), count(&mut app));
+    }
+
+    #[test]
+    fn test_building_kind_colors() {
+        assert_eq!(BUILDING_COLOR, BuildingKind::Residential.color());
+        assert_eq!(
+            Color::rgb(0.45, 0.6, 0.85),
+            BuildingKind::Commercial.color()
+        );
+        assert_eq!(
+            Color::rgb(0.55, 0.55, 0.5),
+            BuildingKind::Industrial.color()
+        );
+        assert_eq!(BuildingKind::Residential, Building::new(1).kind);
+    }
+
+    #[test]
+    fn test_snapped_cursor_position() {
+        let mut city = ActiveCity::new(STARTING_CITY);
+        let empty = GridCoords::new(1, -1);
+        let built = GridCoords::new(2, 0);
+        city.set_height_at_coords(built, Some(2));
+
+        assert_eq!(
+            Vec3::new(1.0, 0.0, -1.0),
+            snapped_cursor_position(&city, empty)
+        );
+        assert_eq!(
+            Vec3::new(2.0, 2.0, 0.0),
+            snapped_cursor_position(&city, built)
+        );
+    }
+
+    #[test]
+    fn test_find_path_straight_line() {
+        let city = ActiveCity::new(STARTING_CITY);
+        let path = city
+            .find_path(GridCoords::new(-2, 0), GridCoords::new(2, 0))
+            .unwrap();
+        let route: Vec<_> = path
+            .steps
+            .iter()
+            .map(|&step| city.index_to_coords(step))
+            .collect();
+        let expected: Vec<_> = (-2..=2).map(|x| GridCoords::new(x, 0)).collect();
+        assert_eq!(expected, route);
+    }
+
+    #[test]
+    fn test_find_path_around_obstacle() {
+        let city = ActiveCity::new([
+            0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, //
+            0, 1, 1, 1, 0, //
+            0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, //
+        ]);
+        let from = GridCoords::new(0, -1);
+        let to = GridCoords::new(0, 1);
+
+        let path = city.find_path(from, to).unwrap();
+        let route: Vec<_> = path
+            .steps
+            .iter()
+            .map(|&step| city.index_to_coords(step))
+            .collect();
+        assert_eq!(Some(&from), route.first());
+        assert_eq!(Some(&to), route.last());
+        assert_eq!(7, route.len(), "{:?}", route);
+        assert!(route
+            .iter()
+            .all(|&cell| city.height_at_coords(cell).is_none()));
+    }
+
+    #[test]
+    fn test_find_path_unreachable() {
+        // the top left corner is walled off
+        let city = ActiveCity::new([
+            0, 1, 0, 0, 0, //
+            1, 1, 0, 0, 0, //
+            0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, //
+        ]);
+        let corner = city.index_to_coords(0);
+        assert!(city.find_path(GridCoords::ORIGIN, corner).is_none());
+        // nor can anyone walk into a building, or off the grid
+        let building = city.index_to_coords(1);
+        assert!(city.find_path(GridCoords::ORIGIN, building).is_none());
+        assert!(city
+            .find_path(GridCoords::ORIGIN, GridCoords::new(5, 0))
+            .is_none());
+    }
+
+    #[test]
+    fn test_goals_avoid_buildings() {
+        let city = ActiveCity::new([
+            1, 1, 1, 1, 1, //
+            1, 1, 1, 1, 1, //
+            1, 1, 0, 1, 1, //
+            1, 1, 1, 1, 1, //
+            1, 1, 1, 1, 0, //
+        ]);
+        let mut rng = ChaCha8Rng::seed_from_u64(SIM_SEED);
+        for _ in 0..100 {
+            if let Some(goal) = city.random_empty_coords(&mut rng) {
+                assert_eq!(None, city.height_at_coords(goal), "{:?}", goal);
+            }
+        }
+        let full = ActiveCity::new([1; 25]);
+        assert_eq!(None, full.random_empty_coords(&mut rng));
+
+        // a workplace that's since been built on keeps them at home
+        let home = GridCoords::ORIGIN;
+        let work = GridCoords::new(-2, -2);
+        let mut person = Person::commuting(home, work);
+        let time_of_day = TimeOfDay::default();
+        assert!(time_of_day.is_daytime());
+        plan_trip(
+            &mut person,
+            Entity::from_raw(0),
+            home,
+            &city,
+            &Options::default(),
+            &mut Reservations::default(),
+            &time_of_day,
+            0.0,
+            0.1,
+            &mut SimRng::default().0,
+        );
+        assert_eq!(None, person.goal);
+    }
+
+    #[test]
+    fn test_time_scale_speeds_up_walking() {
+        let distance_moved = |time_scale| {
+            let mut world = World::new();
+            let mut time = Time::default();
+            let start = Instant::now();
+            time.update_with_instant(start);
+            time.update_with_instant(start + Duration::from_millis(100));
+            world.insert_resource(time);
+            world.init_resource::<Paused>();
+            world.insert_resource(ActiveCity::new(STARTING_CITY));
+            world.insert_resource(SimSpeed {
+                time_scale,
+                ..default()
+            });
+            let person = world
+                .spawn((Transform::IDENTITY, Velocity(Vec3::new(1.0, 0.0, 0.0))))
+                .id();
+            let mut schedule = Schedule::default();
+            schedule.add_systems(apply_velocities);
+            schedule.run(&mut world);
+            world.get::<Transform>(person).unwrap().translation.x
+        };
+
+        let normal = distance_moved(1.0);
+        assert!((normal - 0.1).abs() < 1e-5, "{}", normal);
+        assert!((distance_moved(2.0) - 2.0 * normal).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_grid_lines() {
+        let city = ActiveCity::new(STARTING_CITY);
+        let lines = grid_lines(&city);
+        assert_eq!(12, lines.len());
+
+        let y = GRID_LINE_HEIGHT;
+        assert_eq!(
+            (Vec3::new(-2.5, y, -2.5), Vec3::new(-2.5, y, 2.5)),
+            lines[0]
+        );
+        assert_eq!((Vec3::new(2.5, y, -2.5), Vec3::new(2.5, y, 2.5)), lines[5]);
+        assert_eq!(
+            (Vec3::new(-2.5, y, -2.5), Vec3::new(2.5, y, -2.5)),
+            lines[6]
+        );
+        assert_eq!((Vec3::new(-2.5, y, 0.5), Vec3::new(2.5, y, 0.5)), lines[9]);
+        assert_eq!((Vec3::new(-2.5, y, 2.5), Vec3::new(2.5, y, 2.5)), lines[11]);
+    }
+
+    #[test]
+    fn test_clear_city() {
+        let mut world = World::new();
+        world.init_resource::<Input<KeyCode>>();
+        world.init_resource::<BuildingIndex>();
+        let city = ActiveCity::new([
+            0, 1, 0, 0, 0, //
+            0, 2, 0, 3, 0, //
+            0, 0, 0, 0, 0, //
+            1, 0, 0, 0, 0, //
+            0, 0, 0, 0, 4, //
+        ]);
+        for (coords, height) in city.buildings_iter() {
+            world.spawn((Building::new(height), coords));
+        }
+        world.insert_resource(city);
+        let mut schedule = Schedule::default();
+        schedule.add_systems((rebuild_building_index, clear_city).chain());
+
+        let mut keys = world.resource_mut::<Input<KeyCode>>();
+        keys.press(KeyCode::ShiftLeft);
+        keys.press(KeyCode::Back);
+        schedule.run(&mut world);
+
+        let city = world.resource::<ActiveCity>();
+        assert_eq!(0, city.buildings_iter().count());
+        assert!((0..25).all(|idx| city.valid_exit(city.index_to_coords(idx)).is_some()));
+        assert!(world.resource::<BuildingIndex>().0.is_empty());
+        assert_eq!(
+            0,
+            world
+                .query_filtered::<(), With<Building>>()
+                .iter(&world)
+                .count()
+        );
+    }
+
+    #[test]
+    fn test_place_building_rejects_overlap() {
+        let mut city = ActiveCity::new([
+            0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, //
+            0, 0, 0, 1, 0, //
+            0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, //
+        ]);
+        let block = Footprint::TwoByTwo;
+        // over the existing building
+        assert!(!city.place_building(GridCoords::new(0, -1), block, None));
+        // hanging off the edge
+        assert!(!city.place_building(GridCoords::new(2, 2), block, None));
+        // more than allowed
+        assert!(!city.place_building(GridCoords::new(-2, -2), block, Some(1)));
+        assert_eq!(1, city.buildings_iter().count());
+
+        // a block only counts as one building
+        assert!(city.place_building(GridCoords::new(-2, -2), block, Some(2)));
+        assert_eq!(5, city.buildings_iter().count());
+        assert_eq!(2, city.building_count());
+        assert_eq!(Some(2), city.add_storey(GridCoords::new(1, 0), Some(2)));
+        assert_eq!(None, city.add_storey(GridCoords::new(2, -2), Some(2)));
+        assert!(!city.place_building(GridCoords::new(-1, -1), block, None));
+        assert_eq!(5, city.buildings_iter().count());
+    }
+
+    #[test]
+    fn test_footprint_cells_impassable() {
+        let mut city = ActiveCity::new(STARTING_CITY);
+        let anchor = GridCoords::new(-1, -1);
+        assert!(city.place_building(anchor, Footprint::TwoByTwo, None));
+
+        let covered: Vec<_> = Footprint::TwoByTwo.cells(anchor).collect();
+        assert_eq!(4, covered.len());
+        let blocked: HashSet<_> = covered
+            .iter()
+            .map(|&cell| city.coords_to_index(cell).unwrap())
+            .collect();
+        for idx in 0..25 {
+            let exits = city.get_available_exits(idx);
+            assert!(exits.iter().all(|(exit, _)| !blocked.contains(exit)));
+        }
+        for &cell in &covered {
+            assert_eq!(None, city.valid_exit(cell));
+            assert_eq!(4, city.building_cells(cell).len());
+        }
+
+        // it grows and comes down as one
+        city.add_storey(GridCoords::new(0, 0), None);
+        assert!(covered
+            .iter()
+            .all(|&cell| city.height_at_coords(cell) == Some(2)));
+        city.remove_building(GridCoords::new(0, -1));
+        assert_eq!(0, city.buildings_iter().count());
+
+        let building = Building {
+            footprint: Footprint::TwoByTwo,
+            ..Building::new(1)
+        };
+        let aabb = building.mesh(default()).compute_aabb().unwrap();
+        assert_eq!(Vec3::new(0.5, 0.0, 0.5), Vec3::from(aabb.center));
+        assert_eq!(Vec3::new(1.0, 0.5, 1.0), Vec3::from(aabb.half_extents));
+    }
+
+    #[test]
+    fn test_minimap_viewport() {
+        let viewport = minimap_viewport(1280, 720);
+        assert_eq!(UVec2::splat(180), viewport.physical_size);
+        assert_eq!(UVec2::new(1090, 10), viewport.physical_position);
+
+        // never bigger than the window, even a tiny one
+        let viewport = minimap_viewport(4, 4);
+        assert!(viewport.physical_position.x + viewport.physical_size.x <= 4);
+    }
+
+    #[test]
+    fn test_focus_region() {
+        let area = Rect::new(-2.0, -1.0, 2.0, 1.0);
+        let focus = Vec3::new(1.0, 0.0, 1.0);
+
+        // looking straight down, the ground seen is just the view
+        let overhead = Transform::from_xyz(1.0, 10.0, 1.0).looking_at(focus, Vec3::NEG_Z);
+        let (center, _, size) = focus_region(area, &overhead, focus);
+        assert_eq!(focus, center);
+        assert!((size - Vec2::new(4.0, 2.0)).length() < 1e-4, "{}", size);
+
+        // at 45 degrees it stretches away along the ground
+        let tilted = Transform::from_xyz(1.0, 5.0, 6.0).looking_at(focus, Vec3::Y);
+        let (_, rotation, size) = focus_region(area, &tilted, focus);
+        assert!((size.y - 2.0 * 2f32.sqrt()).abs() < 1e-4, "{}", size);
+        // lying flat on the ground
+        assert!((rotation * Vec3::Z).y.abs() > 0.999);
+    }
+
+    #[test]
+    fn test_inspector_text() {
+        assert_eq!(
+            "residential, height 3",
+            inspector_text(Some(&Building::new(3)))
+        );
+        let factory = Building {
+            kind: BuildingKind::Industrial,
+            ..Building::new(1)
+        };
+        assert_eq!("industrial, height 1", inspector_text(Some(&factory)));
+        assert_eq!("empty", inspector_text(None));
+    }
+
+    #[test]
+    fn test_building_entrance() {
+        let city = ActiveCity::new([
+            1, 1, 0, 0, 0, //
+            1, 1, 1, 0, 0, //
+            0, 1, 0, 0, 0, //
+            0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, //
+        ]);
+        // boxed in by the edge and its neighbours
+        let walled_in = GridCoords::new(-2, -2);
+        assert_eq!(None, Building::new(1).entrance(walled_in, &city));
+        assert_eq!(None, city.entrance(walled_in));
+
+        // out in the open, any side will do
+        let open = GridCoords::new(0, -1);
+        let entrance = Building::new(1).entrance(open, &city).unwrap();
+        assert!(open.neighbors().any(|neighbor| neighbor == entrance));
+        assert!(city.valid_exit(entrance).is_some());
+        assert_eq!(Some(entrance), city.entrance(open));
+
+        // a workplace is always somewhere people can stand
+        let mut rng = ChaCha8Rng::seed_from_u64(SIM_SEED);
+        for _ in 0..20 {
+            let work = city.random_workplace(&mut rng).unwrap();
+            assert!(city.valid_exit(work).is_some(), "{:?}", work);
+        }
+    }
+
+    #[test]
+    fn test_random_city() {
+        let layout = |seed| City::random((10, 10), seed, 0.3).heights;
+        assert_eq!(layout(7), layout(7));
+        assert_ne!(layout(7), layout(8));
+
+        let seeds = 20;
+        let built: usize = (0..seeds)
+            .map(|seed| layout(seed).iter().filter(|&&h| h > 0).count())
+            .sum();
+        let fraction = built as f32 / (seeds * 100) as f32;
+        assert!((fraction - 0.3).abs() < 0.05, "{}", fraction);
+
+        assert!(layout(0).iter().all(|&h| h <= RANDOM_CITY_MAX_HEIGHT));
+        assert_eq!(0, City::random((5, 5), 0, 0.0).buildings_iter().count());
+    }
+
+    #[test]
+    fn test_step_cursor_clamps_to_grid() {
+        let city = ActiveCity::new(STARTING_CITY);
+        let middle = GridCoords::ORIGIN;
+        assert_eq!(GridCoords::new(1, 0), step_cursor(&city, middle, 1, 0));
+        assert_eq!(GridCoords::new(0, -1), step_cursor(&city, middle, 0, -1));
+
+        let corner = GridCoords::new(2, -2);
+        assert_eq!(corner, step_cursor(&city, corner, 1, 0));
+        assert_eq!(corner, step_cursor(&city, corner, 0, -1));
+        assert_eq!(GridCoords::new(1, -2), step_cursor(&city, corner, -1, 0));
+
+        // and it follows the city wherever it's been moved to
+        let moved = ActiveCity::new(STARTING_CITY).with_origin_offset(GridCoords::new(5, 5));
+        assert_eq!(
+            GridCoords::new(7, 5),
+            step_cursor(&moved, GridCoords::new(7, 5), 1, 0)
+        );
+        assert_eq!(
+            GridCoords::new(6, 5),
+            step_cursor(&moved, GridCoords::new(7, 5), -1, 0)
+        );
+    }
+
+    #[test]
+    fn test_keyboard_editing() {
+        let mut app = App::new();
+        app.add_plugins((TaskPoolPlugin::default(), AssetPlugin::default()))
+            .add_asset::<Mesh>()
+            .add_asset::<StandardMaterial>()
+            .init_resource::<Input<MouseButton>>()
+            .init_resource::<Input<KeyCode>>()
+            .init_resource::<Options>()
+            .init_resource::<FlashMessage>()
+            .init_resource::<BuildingIndex>()
+            .init_resource::<KeyboardCursor>()
+            .init_resource::<BulldozeDrag>()
+            .insert_resource(ActiveCity::new(STARTING_CITY))
+            .add_systems(
+                Update,
+                (
+                    track_buildings,
+                    keyboard_move_cursor,
+                    (add_buildings, remove_buildings),
+                )
+                    .chain(),
+            );
+        let cursor = app
+            .world
+            .spawn((Cursor, Transform::default(), GridCoords::ORIGIN))
+            .id();
+
+        let press = |app: &mut App, pressed: &[KeyCode]| {
+            let mut keys = app.world.resource_mut::<Input<KeyCode>>();
+            keys.reset_all();
+            keys.press(KeyCode::AltLeft);
+            for &key in pressed {
+                keys.press(key);
+            }
+            app.update();
+        };
+        let right = GridCoords::new(1, 0);
+        press(&mut app, &[KeyCode::Right]);
+        assert_eq!(Some(&right), app.world.get::<GridCoords>(cursor));
+        assert!(app.world.resource::<KeyboardCursor>().0);
+
+        press(&mut app, &[KeyCode::Return]);
+        press(&mut app, &[KeyCode::Return]);
+        let city = app.world.resource::<ActiveCity>();
+        assert_eq!(Some(2), city.height_at_coords(right));
+        assert_eq!(None, city.height_at_coords(GridCoords::ORIGIN));
+
+        press(&mut app, &[KeyCode::Back]);
+        assert_eq!(
+            None,
+            app.world.resource::<ActiveCity>().height_at_coords(right)
+        );
+    }
+
+    #[test]
+    fn test_manhattan_dist_far_apart() {
+        let from = GridCoords::new(-100, -100);
+        let to = GridCoords::new(100, 120);
+        assert_eq!(420, from.manhattan_dist(to));
+        assert_eq!(420, to.manhattan_dist(from));
+        assert_eq!(
+            510,
+            GridCoords::new(i8::MIN, i8::MIN).manhattan_dist(GridCoords::new(i8::MAX, i8::MAX))
+        );
+    }
+
+    #[test]
+    fn test_state_color() {
+        let idle = Person::default();
+        assert_eq!(PERSON_COLOR, state_color(&idle));
+
+        let walking = Person {
+            goal: Some(GridCoords::new(1, 1)),
+            ..default()
+        };
+        assert_eq!(PERSON_WALKING_COLOR, state_color(&walking));
+
+        let stuck = Person {
+            stuck: true,
+            ..default()
+        };
+        assert_eq!(PERSON_STUCK_COLOR, state_color(&stuck));
+
+        // planning for a walled-off goal leaves them stuck
+        let city = ActiveCity::new([
+            0, 1, 0, 0, 0, //
+            1, 1, 0, 0, 0, //
+            0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, //
+            0, 0, 0, 0, 0, //
+        ]);
+        let mut person = Person::commuting(GridCoords::ORIGIN, GridCoords::new(-2, -2));
+        plan_trip(
+            &mut person,
+            Entity::from_raw(0),
+            GridCoords::ORIGIN,
+            &city,
+            &Options::default(),
+            &mut Reservations::default(),
+            &TimeOfDay::default(),
+            0.0,
+            0.1,
+            &mut SimRng::default().0,
+        );
+        assert_eq!(PERSON_STUCK_COLOR, state_color(&person));
+    }
+
+    #[test]
+    fn test_grid_world_round_trip_at_scale_two() {
+        let scale = WorldScale(2.0);
+        assert_eq!(
+            Vec3::new(2.0, 1.0, -6.0),
+            GridCoords::new(1, -3).to_world(0.5, scale)
+        );
+        for grid in [
+            GridCoords::ORIGIN,
+            GridCoords::new(1, 2),
+            GridCoords::new(-1, 0),
+            GridCoords::new(-2, -3),
+        ] {
+            assert_eq!(
+                grid,
+                GridCoords::from_world(grid.to_world(0.5, scale), scale)
+            );
+        }
+        // anywhere in the cell maps back to it
+        assert_eq!(
+            GridCoords::new(1, -1),
+            GridCoords::from_world(Vec3::new(2.9, 0.0, -1.1), scale)
+        );
+
+        let city = ActiveCity::new(STARTING_CITY).with_scale(scale);
+        let (min, max) = city.world_bounds();
+        assert_eq!(Vec3::new(-5.0, 0.0, -5.0), min);
+        assert_eq!(Vec3::new(5.0, 0.0, 5.0), max);
+        for idx in 0..25 {
+            let coords = city.index_to_coords(idx);
+            assert_eq!(
+                coords,
+                city.world_to_coords(city.coords_to_world(coords, 0.5))
+            );
+        }
+    }
+
+    #[test]
+    fn test_rescale_world_keeps_people_on_their_cells() {
+        let mut world = World::new();
+        world.insert_resource(ActiveCity::new(STARTING_CITY));
+        world.insert_resource(CameraFocus(Vec3::new(1.0, 0.0, 1.0)));
+        world.init_resource::<Events<RescaleWorld>>();
+        world
+            .resource_mut::<Events<RescaleWorld>>()
+            .send(RescaleWorld(WorldScale(2.0)));
+        let home = GridCoords::new(1, -2);
+        let person = world
+            .spawn((
+                Person::commuting(home, home),
+                Transform::from_translation(home.to_world(0.5, WorldScale::default())),
+            ))
+            .id();
+        let camera = world
+            .spawn((
+                Transform::from_xyz(4.0, 5.0, 5.0),
+                Projection::Orthographic(OrthographicProjection::default()),
+            ))
+            .id();
+        let mut schedule = Schedule::default();
+        schedule.add_systems(rescale_world);
+        schedule.run(&mut world);
+
+        let city = world.resource::<ActiveCity>();
+        assert_eq!(WorldScale(2.0), city.scale);
+        let tx = world.get::<Transform>(person).unwrap();
+        assert_eq!(Vec3::new(2.0, 1.0, -4.0), tx.translation);
+        assert_eq!(Vec3::splat(2.0), tx.scale);
+        assert_eq!(home, city.world_to_coords(tx.translation));
+        assert_eq!(Vec3::new(2.0, 0.0, 2.0), world.resource::<CameraFocus>().0);
+        let camera_tx = world.get::<Transform>(camera).unwrap();
+        assert_eq!(Vec3::new(8.0, 10.0, 10.0), camera_tx.translation);
+        let Some(Projection::Orthographic(ortho)) = world.get::<Projection>(camera) else {
+            panic!("camera lost its projection");
+        };
+        assert_eq!(2.0, ortho.scale);
+    }
+
+    #[test]
+    fn test_walking_makes_progress_every_tick() {
+        let city = City::new(STARTING_CITY);
+        let options = Options::default();
+        let mut reservations = Reservations::default();
+        let entity = Entity::from_raw(0);
+
+        let work = GridCoords::new(2, 2);
+        let mut coords = GridCoords::new(-2, -2);
+        let mut person = Person::commuting(coords, work);
+        let mut ticks = 0;
+        while !person.is_dwelling() {
+            assert!(ticks < 20, "still walking after {} ticks", ticks);
+            plan_trip(
+                &mut person,
+                entity,
+                coords,
+                &city,
+                &options,
+                &mut reservations,
+                &TimeOfDay::default(),
+                ticks as f32 * 0.1,
+                0.1,
+                &mut SimRng::default().0,
+            );
+            if person.is_dwelling() {
+                break;
+            }
+            assert_ne!(Some(coords), person.goal, "tick {}", ticks);
+            // paths start where they're standing
+            person.reach_step(&city, coords);
+            // then walk straight onto the next step
+            let step = *person.steps().first().expect("a path to follow");
+            let next = city.index_to_coords(step);
+            assert!(
+                next.manhattan_dist(work) < coords.manhattan_dist(work),
+                "tick {}: {:?} to {:?}",
+                ticks,
+                coords,
+                next
+            );
+            coords = next;
+            ticks += 1;
+        }
+        assert_eq!(work, coords);
+        assert_eq!(8, ticks);
+    }
+
+    #[test]
+    fn test_at_goal_only_at_the_end_of_the_path() {
+        let city = City::new(STARTING_CITY);
+        let goal = GridCoords::ORIGIN;
+        let steps: Vec<_> = [goal, GridCoords::new(1, 0), goal]
+            .into_iter()
+            .map(|coords| city.coords_to_index(coords).unwrap())
+            .collect();
+        let mut person = Person {
+            goal: Some(goal),
+            ..default()
+        };
+        person.set_path(NavigationPath {
+            destination: steps[2],
+            success: true,
+            steps,
+        });
+
+        // passing over the goal on the way round doesn't count
+        assert!(!person.at_goal(&city, goal));
+        assert!(person.reach_step(&city, goal));
+        assert!(!person.at_goal(&city, GridCoords::new(1, 0)));
+        assert!(person.reach_step(&city, GridCoords::new(1, 0)));
+        assert!(person.at_goal(&city, goal));
+
+        // a change of plan to where they're already standing isn't a new trip
+        let mut reservations = Reservations::default();
+        let home = GridCoords::new(-1, 0);
+        let mut person = Person {
+            goal: Some(GridCoords::new(2, 0)),
+            ..Person::commuting(home, GridCoords::new(2, 0))
+        };
+        let night = TimeOfDay {
+            elapsed: 90.0,
             ..default()
         };
-        Self { building, pbr }
+        plan_trip(
+            &mut person,
+            Entity::from_raw(0),
+            home,
+            &city,
+            &Options::default(),
+            &mut reservations,
+            &night,
+            0.0,
+            0.1,
+            &mut SimRng::default().0,
+        );
+        assert_eq!(None, person.goal);
+        assert!(person.steps().is_empty());
     }
-}
 
-fn move_cursor(
-    mut cursor_query: Query<&mut Transform, With<Cursor>>,
-    camera_query: Query<(&Camera, &GlobalTransform)>,
-    ground_query: Query<&GlobalTransform, With<Ground>>,
-    window_query: Query<&Window>,
-    building_query: Query<(&GridCoords, &Building)>,
-    options: Res<Options>,
-    mut gizmos: Gizmos,
-) {
-    let mut cursor_tx = cursor_query.single_mut();
-    let (camera, camera_gtx) = camera_query.single();
-    let ground_gtx = ground_query.single();
-    let window = window_query.single();
+    #[test]
+    fn test_is_walkable_at_edges_and_corners() {
+        let mut heights = STARTING_CITY;
+        // two corners and the middle of two edges built on
+        heights[0] = 1;
+        heights[24] = 3;
+        heights[2] = 1;
+        heights[14] = 2;
+        let city = City::new(heights);
+        let built = [
+            GridCoords::new(-2, -2),
+            GridCoords::new(2, 2),
+            GridCoords::new(0, -2),
+            GridCoords::new(2, 0),
+        ];
 
-    let Some((grid, point)) = cursor_to_grid(window, camera, camera_gtx, ground_gtx) else {
-        return;
-    };
-    // TODO store grid coords on cursor
+        // a ring of cells past the edge too
+        for x in -3..=3 {
+            for y in -3..=3 {
+                let coords = GridCoords::new(x, y);
+                let on_grid = x.abs() <= 2 && y.abs() <= 2;
+                let expected = on_grid && !built.contains(&coords);
+                assert_eq!(expected, city.is_walkable(coords), "{:?}", coords);
+                assert_eq!(expected, city.valid_exit(coords).is_some(), "{:?}", coords);
+            }
+        }
+        // the far ends of the coords' range are nowhere near the grid
+        for coords in [
+            GridCoords::new(i8::MIN, i8::MIN),
+            GridCoords::new(i8::MAX, 0),
+            GridCoords::new(0, i8::MIN),
+        ] {
+            assert!(!city.is_walkable(coords), "{:?}", coords);
+        }
 
-    cursor_tx.translation = point;
+        let mut occupied: Vec<_> = city.occupied_coords().collect();
+        occupied.sort_by_key(|coords| (coords.x, coords.y));
+        let mut expected = built.to_vec();
+        expected.sort_by_key(|coords| (coords.x, coords.y));
+        assert_eq!(expected, occupied);
+    }
 
-    if options.draw_selection {
-        // TODO make this not a linear scan each time
-        let building = building_query
-            .iter()
-            .find(|(&coords, _)| grid == coords)
-            .map(|(_, building)| building);
-        let height = building.map_or(0, |b| b.height) as f32;
-        let selection_center = grid.to_world(height);
+    #[test]
+    fn test_occupied_coords_covers_whole_blocks() {
+        let mut city = City::new(STARTING_CITY);
+        assert_eq!(0, city.occupied_coords().count());
+        // a 2×2 block tucked into the far corner
+        assert!(city.place_building(GridCoords::new(1, 1), Footprint::TwoByTwo, None));
+        let occupied: HashSet<_> = city.occupied_coords().collect();
+        let block: HashSet<_> = Footprint::TwoByTwo.cells(GridCoords::new(1, 1)).collect();
+        assert_eq!(block, occupied);
+        for &cell in &block {
+            assert!(!city.is_walkable(cell), "{:?}", cell);
+        }
+        // and the edge cell beside it can only step away from it
+        let edge = city.coords_to_index(GridCoords::new(2, 0)).unwrap();
+        let exits: Vec<_> = city
+            .get_available_exits(edge)
+            .into_iter()
+            .map(|(idx, _)| city.index_to_coords(idx))
+            .collect();
+        assert_eq!(vec![GridCoords::new(2, -1), GridCoords::new(1, 0)], exits);
 
-        let rotation = Quat::from_rotation_x(PI * 0.5);
-        gizmos.rect(selection_center, rotation, Vec2::ONE, Color::ANTIQUE_WHITE);
+        city.remove_building(GridCoords::new(2, 2));
+        assert_eq!(0, city.occupied_coords().count());
+        assert!(block.iter().all(|&cell| city.is_walkable(cell)));
     }
-}
 
-fn cursor_to_grid(
-    window: &Window,
-    camera: &Camera,
-    camera_gtx: &GlobalTransform,
-    ground_gtx: &GlobalTransform,
-) -> Option<(GridCoords, Vec3)> {
-    let cursor_pos = window.cursor_position()?;
+    #[test]
+    fn test_camera_window_follows_render_target() {
+        use bevy::ecs::system::SystemState;
+        use bevy::window::WindowRef;
 
-    let ray = camera.viewport_to_world(camera_gtx, cursor_pos)?;
+        let mut world = World::new();
+        let mut state: SystemState<Query<&Window>> = SystemState::new(&mut world);
+        let primary_camera = Camera::default();
+        // no windows open at all
+        assert!(camera_window(&primary_camera, &state.get(&world), None).is_none());
 
-    let distance = ray.intersect_plane(ground_gtx.translation(), ground_gtx.up())?;
-    let point = ray.get_point(distance);
-    let grid = GridCoords::from_world(point);
-    Some((grid, point))
-}
+        world.spawn((
+            Window {
+                title: "main".into(),
+                ..default()
+            },
+            PrimaryWindow,
+        ));
+        let second = world
+            .spawn(Window {
+                title: "second".into(),
+                ..default()
+            })
+            .id();
+        let second_camera = Camera {
+            target: RenderTarget::Window(WindowRef::Entity(second)),
+            ..default()
+        };
+        let image_camera = Camera {
+            target: RenderTarget::Image(default()),
+            ..default()
+        };
 
-fn add_buildings(
-    // TODO clean this up once cursor carries its grid coords
-    buttons: Res<Input<MouseButton>>,
-    camera_query: Query<(&Camera, &GlobalTransform)>,
-    ground_query: Query<&GlobalTransform, With<Ground>>,
-    window_query: Query<&Window>,
-    // TODO clean these up once building adding is refactored
-    mut building_query: Query<(&GridCoords, &mut Handle<Mesh>, &mut Building)>,
-    mut meshes: ResMut<Assets<Mesh>>,
-    mut materials: ResMut<Assets<StandardMaterial>>,
-    mut commands: Commands,
-    mut city: ResMut<City<25>>,
-) {
-    if !buttons.just_pressed(MouseButton::Left) {
-        return;
+        let primary = world
+            .query_filtered::<Entity, With<PrimaryWindow>>()
+            .get_single(&world)
+            .ok();
+        let windows = state.get(&world);
+        let title = |camera: &Camera| camera_window(camera, &windows, primary).map(|w| &w.title);
+        assert_eq!(Some(&"main".to_string()), title(&primary_camera));
+        assert_eq!(Some(&"second".to_string()), title(&second_camera));
+        assert_eq!(None, title(&image_camera));
     }
 
-    let (camera, camera_gtx) = camera_query.single();
-    let ground_gtx = ground_query.single();
-    let window = window_query.single();
+    #[test]
+    fn test_ray_to_grid_from_screen_centre() {
+        // the middle of the screen sees straight down the camera's line of sight, which for
+        // the starting camera ends at the middle of the city
+        let city = ActiveCity::new(STARTING_CITY);
+        let options = Options {
+            pick_buildings: false,
+            ..default()
+        };
+        let camera = Vec3::new(4.0, 5.0, 5.0);
+        let ray = Ray {
+            origin: camera,
+            direction: -camera.normalize(),
+        };
+        let (grid, point) = ray_to_grid(ray, &GlobalTransform::IDENTITY, &city, &options).unwrap();
+        assert_eq!(GridCoords::ORIGIN, grid);
+        assert!(point.length() < 1e-4, "{}", point);
 
-    let Some((grid, _)) = cursor_to_grid(window, camera, camera_gtx, ground_gtx) else {
-        return;
-    };
+        // aimed a cell and a bit over, it lands there
+        let target = Vec3::new(1.2, 0.0, -0.9);
+        let ray = Ray {
+            origin: camera,
+            direction: (target - camera).normalize(),
+        };
+        let (grid, point) = ray_to_grid(ray, &GlobalTransform::IDENTITY, &city, &options).unwrap();
+        assert_eq!(GridCoords::new(1, -1), grid);
+        assert!(point.distance(target) < 1e-4, "{}", point);
 
-    // TODO make this not a linear scan each time
-    let building = building_query
-        .iter_mut()
-        .find(|(&coords, _, _)| grid == coords)
-        .map(|(_, mesh, building)| (mesh, building));
+        // and pointing at the sky finds nothing
+        let up = Ray {
+            origin: camera,
+            direction: Vec3::Y,
+        };
+        assert_eq!(
+            None,
+            ray_to_grid(up, &GlobalTransform::IDENTITY, &city, &options)
+        );
+    }
 
-    if let Some((mesh, mut building)) = building {
-        // TODO make mesh update from the building height
-        // use change detection https://bevy-cheatbook.github.io/programming/change-detection.html
-        building.height += 1;
+    #[test]
+    fn test_bulldoze_drag_visits_each_cell_once() {
+        let mut drag = BulldozeDrag::default();
+        let there = GridCoords::new(1, 0);
+        let back = GridCoords::ORIGIN;
+        // back and forth over the same two cells
+        let first_visits: Vec<_> = [back, there, back, there, there]
+            .into_iter()
+            .map(|coords| drag.visit(coords))
+            .collect();
+        assert_eq!(vec![true, true, false, false, false], first_visits);
 
-        city.set_height_at_coords(grid, Some(building.height));
+        // a new drag starts afresh
+        drag.end();
+        assert!(drag.visit(there));
+        assert!(!drag.visit(there));
+    }
 
-        let mesh = meshes.get_mut(&mesh).unwrap();
-        *mesh = Mesh::from(shape::Box {
-            min_x: -0.5,
-            max_x: 0.5,
-            min_y: -0.5,
-            max_y: -0.5 + (building.height as f32),
-            min_z: -0.5,
-            max_z: 0.5,
-        });
-    } else {
-        city.set_height_at_coords(grid, Some(1));
+    #[test]
+    fn test_bulldoze_drag_knocks_down_a_line() {
+        let mut app = App::new();
+        app.init_resource::<Input<MouseButton>>()
+            .init_resource::<Input<KeyCode>>()
+            .init_resource::<BuildingIndex>()
+            .init_resource::<BulldozeDrag>()
+            .add_systems(Update, remove_buildings);
+        let mut heights = STARTING_CITY;
+        // a row of three along y = 0, and one off to the side
+        heights[10..13].fill(1);
+        heights[0] = 1;
+        app.insert_resource(ActiveCity::new(heights));
+        let cursor = app.world.spawn((Cursor, GridCoords::new(-2, 0))).id();
 
-        commands
-            .spawn(BuildingBundle::add(
-                &mut meshes,
-                &mut materials,
-                Building { height: 1 },
-            ))
-            .insert(grid);
+        app.world
+            .resource_mut::<Input<MouseButton>>()
+            .press(MouseButton::Right);
+        for x in [-2, -1, 0, -1] {
+            *app.world.get_mut::<GridCoords>(cursor).unwrap() = GridCoords::new(x, 0);
+            app.update();
+            app.world.resource_mut::<Input<MouseButton>>().clear();
+        }
+        let city = app.world.resource::<ActiveCity>();
+        assert_eq!(
+            vec![(GridCoords::new(-2, -2), 1)],
+            city.buildings_iter().collect::<Vec<_>>()
+        );
+
+        // letting go ends the drag
+        let mut buttons = app.world.resource_mut::<Input<MouseButton>>();
+        buttons.release(MouseButton::Right);
+        app.update();
+        assert!(app.world.resource::<BulldozeDrag>().visited.is_empty());
     }
-}
 
-#[derive(Component)]
-struct Velocity(Vec3);
+    #[test]
+    fn test_rectangular_city_round_trips_coords() {
+        // 4 across, 3 deep, with a building at the east end of the middle row
+        let heights = vec![
+            0, 0, 0, 0, //
+            0, 0, 0, 2, //
+            0, 0, 0, 0, //
+        ];
+        let city = City::with_dimensions(heights, 4, 3);
+        assert_eq!((4, 3), (city.x_len, city.y_len));
 
-impl Velocity {
-    const ZERO: Self = Self(Vec3::ZERO);
-}
+        // rows run along x, so the stride is the width
+        assert_eq!(GridCoords::new(-2, -1), city.index_to_coords(0));
+        assert_eq!(GridCoords::new(1, -1), city.index_to_coords(3));
+        assert_eq!(GridCoords::new(-2, 0), city.index_to_coords(4));
+        assert_eq!(GridCoords::new(1, 1), city.index_to_coords(11));
+        assert_eq!(Some(2), city.height_at_coords(GridCoords::new(1, 0)));
 
-#[derive(Component)]
-struct Person {
-    goal: Option<GridCoords>,
-    path: NavigationPath,
-}
+        for idx in 0..12 {
+            let coords = city.index_to_coords(idx);
+            assert_eq!(Some(idx), city.coords_to_index(coords), "{:?}", coords);
+        }
+        for x in -3..=2 {
+            for y in -2..=2 {
+                let coords = GridCoords::new(x, y);
+                let on_grid = (-2..=1).contains(&x) && (-1..=1).contains(&y);
+                assert_eq!(
+                    on_grid,
+                    city.coords_to_index(coords)
+                        .is_some_and(|idx| city.index_to_coords(idx) == coords),
+                    "{:?}",
+                    coords
+                );
+            }
+        }
 
-impl Person {
-    fn reset_path(&mut self) {
-        self.path = default();
+        let (min, max) = city.world_bounds();
+        assert_eq!(Vec3::new(-2.5, 0.0, -1.5), min);
+        assert_eq!(Vec3::new(1.5, 0.0, 1.5), max);
+        // and people can still find their way around the long way
+        let path = city
+            .find_path(GridCoords::new(-2, 0), GridCoords::new(1, 1))
+            .unwrap();
+        assert_eq!(
+            Some(&city.coords_to_index(GridCoords::new(1, 1)).unwrap()),
+            path.steps.last()
+        );
     }
-}
 
-impl Default for Person {
-    fn default() -> Self {
-        Person {
-            goal: None,
-            path: default(),
-        }
+    #[test]
+    #[should_panic(expected = "a 3×3 city can't have 12 cells")]
+    fn test_with_dimensions_checks_the_heights_fit() {
+        City::with_dimensions(vec![0; 12], 3, 3);
     }
-}
 
-fn reset_paths_after_city_changes(city: Res<City<25>>, mut people: Query<&mut Person>) {
-    if city.is_changed() {
-        for mut person in &mut people {
-            person.reset_path();
-        }
+    #[test]
+    #[should_panic(expected = "a 4×4 city can't have 24 cells")]
+    fn test_new_checks_the_heights_are_square() {
+        City::new(vec![0; 24]);
     }
-}
 
-fn people_walk(
-    city: Res<City<25>>,
-    mut query: Query<(&mut Person, &Transform, &mut Velocity)>,
-    options: Res<Options>,
-    mut gizmos: Gizmos,
-) {
-    for (mut person, tx, mut velocity) in &mut query {
-        let mut rng = rand::thread_rng();
+    #[test]
+    fn test_screen_step_follows_the_camera() {
+        let arrows = [Vec2::Y, Vec2::NEG_Y, Vec2::NEG_X, Vec2::X];
+        let steps = |camera: Transform| -> Vec<(i8, i8)> {
+            arrows
+                .iter()
+                .map(|&screen| screen_step(camera.forward(), camera.up(), screen))
+                .collect()
+        };
 
-        let coords = GridCoords::from_world(tx.translation);
+        // the starting view: up the screen is away from the camera, towards -z
+        let start = Transform::from_xyz(4.0, 5.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y);
+        assert_eq!(vec![(0, -1), (0, 1), (-1, 0), (1, 0)], steps(start));
 
-        if person.goal.is_none() || person.goal.is_some_and(|goal| goal == coords) {
-            let goal = GridCoords::new(rng.gen_range(-2..=2), rng.gen_range(-2..=2));
-            eprintln!("new goal: {:?}", goal);
-            dbg!(city.height_at_coords(goal));
-            person.goal = Some(goal);
+        // orbited a quarter turn to look along -x, up the screen is -x
+        let orbited = Transform::from_xyz(5.0, 5.0, 0.0).looking_at(Vec3::ZERO, Vec3::Y);
+        assert_eq!(vec![(-1, 0), (1, 0), (0, 1), (0, -1)], steps(orbited));
 
-            person.reset_path();
-        }
+        // and straight down with north at the top, by which way the camera's top points
+        let overhead = Transform::from_xyz(0.0, 10.0, 0.0).looking_at(Vec3::ZERO, Vec3::NEG_Z);
+        assert_eq!(vec![(0, -1), (0, 1), (-1, 0), (1, 0)], steps(overhead));
+    }
 
-        if person.path.steps.is_empty() {
-            eprintln!("empty path, replanning");
-            let goal = person.goal.unwrap(); // previous condition assigned it
-            let path = a_star_search(
-                city.coords_to_index(coords).unwrap(),
-                city.coords_to_index(goal).unwrap(),
-                city.as_ref(),
+    #[test]
+    fn test_dropping_and_removing_people_dont_upset_the_count() {
+        let mut app = App::new();
+        app.add_plugins((TaskPoolPlugin::default(), AssetPlugin::default()))
+            .add_asset::<StandardMaterial>()
+            .init_resource::<Input<MouseButton>>()
+            .init_resource::<Input<KeyCode>>()
+            .insert_resource(Population { target: 1 })
+            .insert_resource(ActiveCity::new(STARTING_CITY))
+            .init_resource::<Reservations>()
+            .add_systems(
+                Update,
+                (
+                    keyboard_population,
+                    drop_person,
+                    remove_selected_person,
+                    (apply_deferred, match_population)
+                        .chain()
+                        .after(keyboard_population)
+                        .after(drop_person)
+                        .after(remove_selected_person),
+                ),
             );
+        let mesh = || Handle::weak(bevy::asset::HandleId::random::<Mesh>());
+        app.insert_resource(PersonMeshes {
+            high: mesh(),
+            medium: mesh(),
+            low: mesh(),
+        });
+        app.world.spawn((Cursor, GridCoords::new(-1, 2)));
+        let count = |app: &mut App| {
+            let mut people = app
+                .world
+                .query_filtered::<(), (With<Person>, Without<Despawning>)>();
+            let mut leaving = app
+                .world
+                .query_filtered::<(), (With<Person>, With<Despawning>)>();
+            (
+                people.iter(&app.world).count(),
+                leaving.iter(&app.world).count(),
+            )
+        };
+        app.update();
+        assert_eq!((1, 0), count(&mut app));
 
-            if path.steps.is_empty() {
-                eprintln!("unreachable goal, try again later");
-                person.goal = None;
-            } else {
-                person.path = path;
-                dbg!(&person.path.steps);
-            }
+        // shift+click drops exactly the one person
+        app.world
+            .resource_mut::<Input<KeyCode>>()
+            .press(KeyCode::ShiftLeft);
+        app.world
+            .resource_mut::<Input<MouseButton>>()
+            .press(MouseButton::Left);
+        app.update();
+        assert_eq!(2, app.world.resource::<Population>().target);
+        assert_eq!((2, 0), count(&mut app));
+        app.world.resource_mut::<Input<KeyCode>>().reset_all();
+        app.world.resource_mut::<Input<MouseButton>>().reset_all();
+        app.update();
+        assert_eq!((2, 0), count(&mut app));
+
+        // deleting the selected person sends away only them
+        let chosen = app
+            .world
+            .query_filtered::<Entity, With<Person>>()
+            .iter(&app.world)
+            .next()
+            .unwrap();
+        app.world.entity_mut(chosen).insert(Selected);
+        app.world
+            .resource_mut::<Input<KeyCode>>()
+            .press(KeyCode::Delete);
+        app.update();
+        assert_eq!(1, app.world.resource::<Population>().target);
+        assert_eq!((1, 1), count(&mut app));
+        assert!(app.world.get::<Despawning>(chosen).is_some());
+    }
+
+    #[test]
+    fn test_unreachable_goal_waits_before_replanning() {
+        // work is walled in by buildings on every side
+        let mut heights = STARTING_CITY;
+        for idx in [7, 11, 13, 17] {
+            heights[idx] = 1;
         }
+        let city = City::new(heights);
+        let options = Options {
+            dwell_time: (Duration::from_secs(1), Duration::from_secs(1)),
+            ..default()
+        };
+        let home = GridCoords::new(-2, -2);
+        let mut person = Person::commuting(home, GridCoords::ORIGIN);
+        let tick = |person: &mut Person, frame: usize| {
+            plan_trip(
+                person,
+                Entity::from_raw(0),
+                home,
+                &city,
+                &options,
+                &mut Reservations::default(),
+                &TimeOfDay::default(),
+                frame as f32 * 0.25,
+                0.25,
+                &mut SimRng::default().0,
+            );
+        };
 
-        if options.draw_paths {
-            let mut path_dbg_from = tx.translation;
-            for &step in &person.path.steps {
-                let path_dbg_to = city.index_to_world(step, PERSON_HEIGHT * 0.5);
-                gizmos.line(path_dbg_from, path_dbg_to, Color::rgba_u8(0, 0, 0, 100));
-                path_dbg_from = path_dbg_to;
-            }
+        tick(&mut person, 0);
+        assert!(person.stuck);
+        assert_eq!(None, person.goal);
+        assert!(person.is_dwelling());
+
+        // the next few ticks don't try again
+        for frame in 1..4 {
+            tick(&mut person, frame);
+            assert_eq!(None, person.goal, "frame {}", frame);
+            assert!(person.is_dwelling(), "frame {}", frame);
         }
 
-        if let Some(&step) = person.path.steps.first() {
-            let goal_coords = city.index_to_coords(step);
+        // once the rest is over it does, fails again, and goes back to waiting
+        tick(&mut person, 4);
+        assert!(person.stuck);
+        assert!(person.is_dwelling());
+        assert_eq!(Duration::ZERO, person.dwell.elapsed());
+    }
 
-            if goal_coords == coords {
-                velocity.0 = Vec3::ZERO;
-                person.path.steps = person.path.steps[1..].to_vec(); // TODO inefficient
-                eprintln!("reached next step, steps now: {:?}", person.path.steps);
-            } else {
-                let goal_center = goal_coords.to_world(PERSON_HEIGHT * 0.5);
-                let direction = goal_center - tx.translation;
-                velocity.0 = direction.normalize_or_zero() * PERSON_SPEED;
+    #[test]
+    fn test_cutting_a_corner_still_follows_the_path() {
+        let city = City::new(STARTING_CITY);
+        // east along y = 0, then turning south down x = 0
+        let cells = [
+            GridCoords::new(-1, 0),
+            GridCoords::ORIGIN,
+            GridCoords::new(0, 1),
+            GridCoords::new(0, 2),
+        ];
+        let steps: Vec<_> = cells
+            .iter()
+            .map(|&coords| city.coords_to_index(coords).unwrap())
+            .collect();
+        let mut person = Person {
+            goal: Some(GridCoords::new(0, 2)),
+            ..default()
+        };
+        person.set_path(NavigationPath {
+            destination: *steps.last().unwrap(),
+            success: true,
+            steps,
+        });
+
+        // steer one step ahead at a steady walk, as people_walk does
+        let lookahead = 1;
+        let mut position = GridCoords::new(-2, 0).to_world(PERSON_HEIGHT * 0.5, city.scale);
+        let mut visited = vec![];
+        for _ in 0..100 {
+            let coords = city.world_to_coords(position);
+            if visited.last() != Some(&coords) {
+                visited.push(coords);
             }
-        } else {
-            eprintln!("nowhere to go for now");
-            velocity.0 = Vec3::ZERO;
+            person.reach_step(&city, coords);
+            if person.at_goal(&city, coords) {
+                break;
+            }
+            let target = lookahead_target(&city, person.steps(), lookahead).unwrap();
+            position += (target - position).normalize_or_zero() * 0.1;
         }
+        // the corner cell itself got skipped on the diagonal...
+        assert!(!visited.contains(&GridCoords::ORIGIN), "{:?}", visited);
+        // ...but they kept on round the corner and got there
+        assert_eq!(
+            Some(&GridCoords::new(0, 2)),
+            visited.last(),
+            "{:?}",
+            visited
+        );
+        assert!(person.at_goal(&city, GridCoords::new(0, 2)));
     }
-}
 
-fn apply_velocities(time: Res<Time>, mut q: Query<(&mut Transform, &Velocity)>) {
-    let secs = time.delta_seconds();
-    for (mut tx, &Velocity(v)) in &mut q {
-        tx.translation += v * secs;
+    #[test]
+    fn test_everything_keeps_to_the_world_scale() {
+        let scale = WorldScale(2.0);
+        let mut city = ActiveCity::new(STARTING_CITY).with_scale(scale);
+        for _ in 0..3 {
+            city.add_storey(GridCoords::ORIGIN, None);
+        }
+        let mut world = World::new();
+        let mut time = Time::default();
+        let start = time.startup();
+        time.update_with_instant(start);
+        world.insert_resource(time);
+        world.insert_resource(city);
+        world.init_resource::<SimSpeed>();
+        world.init_resource::<Paused>();
+        world.init_resource::<CameraFit>();
+        world.init_resource::<CameraFocus>();
+        world.init_resource::<Options>();
+        world.init_resource::<bevy::pbr::DirectionalLightShadowMap>();
+        world.init_resource::<bevy::pbr::PointLightShadowMap>();
+        let mut keys = Input::<KeyCode>::default();
+        keys.press(KeyCode::Home);
+        keys.press(KeyCode::I);
+        world.insert_resource(keys);
+
+        let walker = world.spawn((Transform::default(), Velocity(Vec3::X))).id();
+        let leaver = world
+            .spawn((
+                Person::default(),
+                Transform::from_xyz(0.0, PERSON_HEIGHT * 0.5 * scale.0, 0.0)
+                    .with_scale(Vec3::splat(scale.0)),
+                Despawning(Timer::new(PERSON_DESPAWN_TIME, TimerMode::Once)),
+            ))
+            .id();
+        let ruin = world
+            .spawn((
+                Transform::from_xyz(0.0, 0.5 * scale.0, 0.0).with_scale(Vec3::splat(scale.0)),
+                Demolishing(Timer::new(DEMOLITION_TIME, TimerMode::Once)),
+            ))
+            .id();
+        let camera = world
+            .spawn((
+                Transform::from_xyz(4.0, 5.0, 5.0).looking_at(Vec3::ZERO, Vec3::Y),
+                Projection::Orthographic(OrthographicProjection::default()),
+            ))
+            .id();
+        let sun = world.spawn(bevy::pbr::CascadeShadowConfig::default()).id();
+        // under half a cell from the cursor, but further than half an unscaled one
+        world.spawn((Cursor, GridCoords::new(2, 2)));
+        let bystander = world
+            .spawn((
+                Person::default(),
+                Transform::from_xyz(4.8, PERSON_HEIGHT * 0.5 * scale.0, 4.0),
+            ))
+            .id();
+
+        let mut schedule = Schedule::default();
+        schedule.add_systems((
+            apply_velocities,
+            despawn_people,
+            demolish_buildings,
+            fit_camera_to_city,
+            configure_shadows,
+            inspect_person,
+        ));
+        world
+            .resource_mut::<Time>()
+            .update_with_instant(start + Duration::from_millis(100));
+        schedule.run(&mut world);
+
+        // the speed is in cells a second whatever the scale...
+        assert_eq!(PERSON_SPEED, world.resource::<SimSpeed>().person_speed);
+        // ...and so is the velocity, so they cross twice the world to cover one cell
+        let walked = world.get::<Transform>(walker).unwrap().translation.x;
+        assert!((walked - 0.2).abs() < 1e-5, "walked {}", walked);
+
+        // shrinking and squashing from their full scaled size, with their feet on the ground
+        let tx = world.get::<Transform>(leaver).unwrap();
+        assert!(tx.scale.x > 1.0 && tx.scale.x < scale.0, "{:?}", tx.scale);
+        let feet = tx.translation.y - PERSON_HEIGHT * 0.5 * tx.scale.y;
+        assert!(feet.abs() < 1e-5, "feet at {}", feet);
+        let tx = world.get::<Transform>(ruin).unwrap();
+        assert_eq!(scale.0, tx.scale.x);
+        assert!(tx.scale.y > 1.0 && tx.scale.y < scale.0, "{:?}", tx.scale);
+        let base = tx.translation.y - 0.5 * tx.scale.y;
+        assert!(base.abs() < 1e-5, "base at {}", base);
+
+        // framing a city twice the size takes twice the zoom: up as well as across
+        let (_, fitted) = world.resource::<CameraFit>().target.unwrap();
+        let rotation = world.get::<Transform>(camera).unwrap().rotation;
+        let unscaled = ActiveCity::new(STARTING_CITY);
+        let (min, mut max) = unscaled.world_bounds();
+        max.y = 3.0;
+        let expected = fit_scale(min, max, rotation, 1.0) * scale.0;
+        assert!(
+            (fitted - expected).abs() < 1e-4,
+            "{} vs {}",
+            fitted,
+            expected
+        );
+
+        // the shadows reach the top of the tallest building there could be, with the same
+        // texels a cell as an unscaled city
+        let (min, mut max) = world.resource::<ActiveCity>().world_bounds();
+        max.y = MAX_BUILDING_HEIGHT as f32 * scale.0;
+        let expected = shadow_distance(min, max, 0.0);
+        let reach = *world
+            .get::<bevy::pbr::CascadeShadowConfig>(sun)
+            .unwrap()
+            .bounds
+            .last()
+            .unwrap();
+        assert!((reach - expected).abs() < 1e-3, "{} vs {}", reach, expected);
+        let (min, mut max) = unscaled.world_bounds();
+        max.y = MAX_BUILDING_HEIGHT as f32;
+        assert_eq!(
+            shadow_map_size(min, max, WorldScale::default()),
+            world
+                .resource::<bevy::pbr::DirectionalLightShadowMap>()
+                .size
+        );
+
+        assert!(world.get::<Selected>(bystander).is_some());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_transform_city_keeps_blocks_and_districts() {
+        let mut app = App::new();
+        app.add_plugins((TaskPoolPlugin::default(), AssetPlugin::default()))
+            .add_asset::<Mesh>()
+            .add_asset::<StandardMaterial>()
+            .add_asset::<Image>()
+            .init_resource::<Input<KeyCode>>()
+            .add_systems(Update, transform_city);
+        let mut city = ActiveCity::new(STARTING_CITY);
+        let anchor = GridCoords::new(-2, -2);
+        assert!(city.place_building(anchor, Footprint::TwoByTwo, None));
+        city.add_storey(GridCoords::new(1, 2), None);
+        app.world.spawn((
+            Building {
+                footprint: Footprint::TwoByTwo,
+                ..Building::new(1)
+            },
+            anchor,
+            District(3),
+        ));
+        app.world.spawn((Building::new(1), GridCoords::new(1, 2)));
+        app.insert_resource(city);
+
+        let buildings = |app: &mut App| {
+            let mut buildings: Vec<_> = app
+                .world
+                .query::<(&GridCoords, &Building, Option<&District>)>()
+                .iter(&app.world)
+                .map(|(&coords, building, district)| {
+                    (coords, building.footprint, district.copied())
+                })
+                .collect();
+            buildings.sort_by_key(|&(coords, ..)| (coords.x, coords.y));
+            buildings
+        };
+
+        // a quarter turn takes the block in the far left corner to the far right one, where it
+        // grows from one cell further left
+        app.world
+            .resource_mut::<Input<KeyCode>>()
+            .press(KeyCode::Key6);
+        app.update();
+        let city = app.world.resource::<ActiveCity>();
+        assert_eq!(2, city.building_count());
+        let block = city.building_cells(GridCoords::new(1, -2));
+        let expected: Vec<_> = Footprint::TwoByTwo.cells(GridCoords::new(1, -2)).collect();
+        assert_eq!(expected, block);
+        assert_eq!(
+            vec![
+                (GridCoords::new(-2, 1), Footprint::OneByOne, None),
+                (
+                    GridCoords::new(1, -2),
+                    Footprint::TwoByTwo,
+                    Some(District(3))
+                ),
+            ],
+            buildings(&mut app)
+        );
+
+        // and mirroring it back puts it in the far left corner again
+        let mut keys = app.world.resource_mut::<Input<KeyCode>>();
+        keys.reset_all();
+        keys.press(KeyCode::Key0);
+        app.update();
+        let city = app.world.resource::<ActiveCity>();
+        assert_eq!(2, city.building_count());
+        assert_eq!(4, city.building_cells(GridCoords::new(-2, -1)).len());
+        assert_eq!(
+            vec![
+                (
+                    GridCoords::new(-2, -2),
+                    Footprint::TwoByTwo,
+                    Some(District(3))
+                ),
+                (GridCoords::new(2, 1), Footprint::OneByOne, None),
+            ],
+            buildings(&mut app)
+        );
+    }
 
     #[test]
-    fn test_grid_coords_roundtrip() {
-        for grid in vec![
-            GridCoords::ORIGIN,
-            GridCoords::new(1, 2),
-            GridCoords::new(-1, 0),
-            GridCoords::new(-2, -3),
-        ] {
-            let world = grid.to_world(0.5);
-            assert_eq!(grid, GridCoords::from_world(world));
+    fn test_empty_city_fills_the_active_size() {
+        let city = ActiveCity::empty(ACTIVE_CITY_SIZE);
+        assert_eq!(city.heights.len(), city.x_len * city.y_len);
+        assert_eq!(city.x_len, city.y_len);
+        assert_eq!(0, city.buildings_iter().count());
+    }
+
+    #[test]
+    fn test_ground_fits_the_city() {
+        let city = ActiveCity::empty((5, 5)).with_scale(WorldScale(2.0));
+        // half a cell spare round the 5×5 grid, at two units a cell
+        let (min, max) = city.ground_bounds();
+        assert_eq!(Vec3::new(-6.0, 0.0, -6.0), min);
+        assert_eq!(Vec3::new(6.0, 0.0, 6.0), max);
+        assert_eq!((6, 6), city.ground_cells());
+
+        let obj = city_to_obj(&city);
+        let (_, ground) = obj.split_once("o ground\n").unwrap();
+        let corners: Vec<_> = ground
+            .lines()
+            .take_while(|line| line.starts_with("v "))
+            .collect();
+        assert_eq!(
+            vec!["v -6 0 -6", "v -6 0 6", "v 6 0 6", "v 6 0 -6"],
+            corners
+        );
+    }
+
+    #[test]
+    fn test_zooming_out_lowers_person_detail() {
+        let mut world = World::new();
+        let mesh = || Handle::weak(bevy::asset::HandleId::random::<Mesh>());
+        world.insert_resource(Options::default());
+        world.insert_resource(PersonMeshes {
+            high: mesh(),
+            medium: mesh(),
+            low: mesh(),
+        });
+        let camera = world
+            .spawn((
+                Camera::default(),
+                GlobalTransform::from_xyz(4.0, 5.0, 5.0),
+                Projection::Orthographic(OrthographicProjection {
+                    scale: 3.0,
+                    ..default()
+                }),
+            ))
+            .id();
+        let person = world
+            .spawn((
+                Person::default(),
+                PersonLod::Low,
+                mesh(),
+                Transform::default(),
+            ))
+            .id();
+        let mut schedule = Schedule::default();
+        schedule.add_systems(update_person_lod);
+        let lod_at_zoom = |world: &mut World, schedule: &mut Schedule, zoom: f32| {
+            let mut projection = world.get_mut::<Projection>(camera).unwrap();
+            let Projection::Orthographic(ortho) = projection.as_mut() else {
+                unreachable!();
+            };
+            ortho.scale = zoom;
+            schedule.run(world);
+            *world.get::<PersonLod>(person).unwrap()
+        };
+
+        // the same distance away, but drawn smaller and smaller
+        assert_eq!(PersonLod::High, lod_at_zoom(&mut world, &mut schedule, 2.0));
+        assert_eq!(
+            PersonLod::Medium,
+            lod_at_zoom(&mut world, &mut schedule, 4.0)
+        );
+        assert_eq!(PersonLod::Low, lod_at_zoom(&mut world, &mut schedule, 8.0));
+        assert_eq!(PersonLod::High, lod_at_zoom(&mut world, &mut schedule, 1.0));
+    }
+
+    #[test]
+    fn test_save_keeps_terrain_roads_and_blocks() {
+        let mut city = City::new(STARTING_CITY);
+        city.elevations[2] = 2;
+        city.elevations[12] = -1;
+        city.roads[20] = true;
+        city.roads[21] = true;
+        assert!(city.place_building(GridCoords::new(0, 0), Footprint::TwoByTwo, None));
+
+        let ron = SaveGame {
+            rng: ChaCha8Rng::seed_from_u64(SIM_SEED),
+            districts: vec![],
+            city: Some(save_city(&city)),
         }
+        .to_ron()
+        .unwrap();
+        let saved = SaveGame::from_ron(&ron).unwrap().city.unwrap();
+        let loaded = load_city(&saved, &City::new(STARTING_CITY)).unwrap();
+        assert_eq!(city.elevations, loaded.elevations);
+        assert_eq!(city.roads, loaded.roads);
+        assert_eq!(city.blocks, loaded.blocks);
+        assert_eq!(1, loaded.building_count());
+        assert_eq!(
+            Footprint::TwoByTwo,
+            loaded.footprint_at(GridCoords::new(1, 1))
+        );
 
-        for world in vec![
-            Vec3::ZERO + 0.5 * Vec3::Y,
-            Vec3::X + 0.5 * Vec3::Y,
-            Vec3::Z + 0.5 * Vec3::Y,
-            Vec3::new(-4.0, 0.5, -1.0),
-            Vec3::new(4.0, -1.5, -1.0),
-        ] {
-            let grid = GridCoords::from_world(world);
-            assert_eq!(world, grid.to_world(world.y));
+        // a save from before the rest of the layout was kept still loads, just flat and bare
+        let heights_only = SavedCity {
+            elevations: vec![],
+            roads: vec![],
+            blocks: vec![],
+            ..saved
+        };
+        let loaded = load_city(&heights_only, &City::new(STARTING_CITY)).unwrap();
+        assert_eq!(city.heights, loaded.heights);
+        assert_eq!(vec![0; 25], loaded.elevations);
+        assert_eq!(vec![false; 25], loaded.roads);
+        assert_eq!(4, loaded.building_count());
+    }
+
+    #[test]
+    fn test_generate_rectangular_city() {
+        let city = City::random((10, 6), 3, 0.3);
+        assert_eq!((10, 6), (city.x_len, city.y_len));
+        assert_eq!(60, city.cells());
+        assert_eq!(60, city.elevations.len());
+        for idx in 0..city.cells() {
+            assert_eq!(Some(idx), city.coords_to_index(city.index_to_coords(idx)));
         }
+        let (min, max) = city.world_bounds();
+        assert_eq!(Vec3::new(10.0, 0.0, 6.0), max - min);
     }
 
     #[test]
-    fn test_grid_from_world() {
-        for (world, grid) in vec![
-            (Vec3::ZERO, GridCoords::ORIGIN),
-            (Vec3::X, GridCoords::new(1, 0)),
-            (-Vec3::X, GridCoords::new(-1, 0)),
-            (Vec3::Z, GridCoords::new(0, 1)),
-            (-Vec3::Z, GridCoords::new(0, -1)),
-            // round
-            (Vec3::new(0.2, 0.0, 0.2), GridCoords::ORIGIN),
-            (Vec3::new(0.8, 0.0, 0.8), GridCoords::new(1, 1)),
-            (Vec3::new(-0.2, 0.0, -0.2), GridCoords::ORIGIN),
-            (Vec3::new(-0.8, 0.0, -0.8), GridCoords::new(-1, -1)),
-            // round away from zero
-            (Vec3::new(0.5, 0.0, 0.5), GridCoords::new(1, 1)),
-            (Vec3::new(-0.5, 0.0, -0.5), GridCoords::new(-1, -1)),
-            // all points in Y column map to the same grid coords
-            (Vec3::new(0.8, 1000.0, 0.8), GridCoords::new(1, 1)),
-        ] {
-            assert_eq!(grid, GridCoords::from_world(world), "{}", world);
+    fn test_blocks_level_off_and_export_whole() {
+        let mut city = City::new(STARTING_CITY);
+        assert!(city.place_building(GridCoords::ORIGIN, Footprint::TwoByTwo, None));
+        city.set_height_at_coords(GridCoords::new(-2, 0), Some(5));
+        // selecting just one cell of the block levels all of it
+        assert_eq!(
+            Some(3),
+            city.equalize_heights([GridCoords::new(1, 1), GridCoords::new(-2, 0)], None)
+        );
+        for cell in Footprint::TwoByTwo.cells(GridCoords::ORIGIN) {
+            assert_eq!(Some(3), city.height_at_coords(cell));
+        }
+
+        // and it goes out as one box spanning both cells each way
+        let obj = city_to_obj(&city);
+        assert_eq!(2, obj.matches("o building_").count());
+        let (_, block) = obj.split_once("o building_0_0\n").unwrap();
+        for v in block.lines().filter_map(|line| line.strip_prefix("v ")) {
+            let xyz: Vec<f32> = v.split(' ').map(|n| n.parse().unwrap()).collect();
+            assert!([-0.5, 1.5].contains(&xyz[0]), "{}", v);
+            assert!([-0.5, 1.5].contains(&xyz[2]), "{}", v);
         }
     }
+
+    #[test]
+    fn test_regenerated_city_keeps_connectivity() {
+        let mut app = App::new();
+        app.add_plugins((TaskPoolPlugin::default(), AssetPlugin::default()))
+            .add_asset::<Mesh>()
+            .add_asset::<StandardMaterial>()
+            .add_asset::<Image>()
+            .init_resource::<Options>()
+            .init_resource::<SimRng>()
+            .insert_resource(ActiveCity::new(STARTING_CITY).with_connectivity(Connectivity::Eight))
+            .add_systems(Update, regenerate_city);
+        let mut keys = Input::<KeyCode>::default();
+        keys.press(KeyCode::N);
+        app.insert_resource(keys);
+
+        app.update();
+        // diagonal from the very first frame, with nothing left to sync it later
+        assert_eq!(
+            Connectivity::Eight,
+            app.world.resource::<ActiveCity>().connectivity
+        );
+    }
 }